@@ -0,0 +1,136 @@
+extern crate gameboy;
+
+use gameboy::gameboy::Target;
+use gameboy::testrom::{Outcome, TestRun, Verdict};
+use std::path::{Path, PathBuf};
+
+// Usage: gameboy-test <rom|directory>... [--frames N] [--model dmg|mgb|sgb|cgb|agb]
+//
+// Directories are searched for .gb and .gbc files. A PNG next to a ROM with the
+// same name is used as its reference screenshot.
+fn main() {
+    let mut frames = 60 * 60;
+    let mut model = Target::default();
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                frames = args
+                    .next()
+                    .and_then(|f| f.parse().ok())
+                    .expect("invalid frame count");
+            }
+            "--model" => {
+                model = args
+                    .next()
+                    .and_then(|m| Target::from_name(&m))
+                    .expect("invalid model");
+            }
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        eprintln!(
+            "usage: gameboy-test <rom|directory>... [--frames N] [--model dmg|mgb|sgb|cgb|agb]"
+        );
+        std::process::exit(2);
+    }
+
+    let mut roms = Vec::new();
+    for path in &paths {
+        collect(path, &mut roms);
+    }
+
+    let width = roms
+        .iter()
+        .map(|r| r.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(3);
+    println!(
+        "{:<width$}  {:<7}  {:>6}  {:<10}  DETAIL",
+        "ROM", "RESULT", "FRAMES", "DETECTOR"
+    );
+
+    let (mut passed, mut failed, mut timeouts) = (0, 0, 0);
+    for rom in &roms {
+        let (result, frames, detector, detail) = match run(rom, model, frames) {
+            Ok(outcome) => {
+                let detector = outcome.detector.map_or("-", |d| d.name());
+                match outcome.verdict {
+                    Verdict::Passed => {
+                        passed += 1;
+                        ("pass", outcome.frames, detector, String::new())
+                    }
+                    Verdict::Failed(detail) => {
+                        failed += 1;
+                        ("FAIL", outcome.frames, detector, detail)
+                    }
+                    Verdict::Timeout => {
+                        timeouts += 1;
+                        ("TIMEOUT", outcome.frames, detector, String::new())
+                    }
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                ("ERROR", 0, "-", e)
+            }
+        };
+        let detail: String = detail.chars().take(60).collect();
+        let line = format!(
+            "{:<width$}  {:<7}  {:>6}  {:<10}  {}",
+            rom.display(),
+            result,
+            frames,
+            detector,
+            detail
+        );
+        println!("{}", line.trim_end());
+    }
+
+    println!();
+    println!(
+        "{} passed, {} failed, {} timed out, {} total",
+        passed,
+        failed,
+        timeouts,
+        roms.len()
+    );
+    if passed != roms.len() {
+        std::process::exit(1);
+    }
+}
+
+fn collect(path: &Path, roms: &mut Vec<PathBuf>) {
+    if !path.is_dir() {
+        roms.push(path.to_path_buf());
+        return;
+    }
+    let mut entries: Vec<PathBuf> = match std::fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(e) => panic!("error reading {}: {}", path.display(), e),
+    };
+    entries.sort();
+    for entry in entries {
+        let is_rom = matches!(
+            entry.extension().and_then(|e| e.to_str()),
+            Some("gb") | Some("gbc")
+        );
+        if entry.is_dir() || is_rom {
+            collect(&entry, roms);
+        }
+    }
+}
+
+fn run(rom: &Path, model: Target, frames: u32) -> Result<Outcome, String> {
+    let data = std::fs::read(rom).map_err(|e| e.to_string())?;
+    let mut test = TestRun::new(data).model(model).frames(frames);
+    let reference = rom.with_extension("png");
+    if reference.exists() {
+        let png = std::fs::read(&reference).map_err(|e| e.to_string())?;
+        test = test.reference(&png).map_err(|e| e.to_string())?;
+    }
+    test.run().map_err(|e| e.to_string())
+}
@@ -0,0 +1,42 @@
+extern crate gameboy;
+
+use gameboy::export::{export_avi, export_wav, InputScript};
+use gameboy::gameboy::{load_rom, Gameboy};
+use std::path::Path;
+
+// Usage: wav-export <rom> <output.wav> [frames] [input-script] [--stems]
+//
+// An output ending in .avi records the video along with the sound
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stems = args.iter().any(|a| a == "--stems");
+    let args: Vec<&String> = args.iter().filter(|a| *a != "--stems").collect();
+    if args.len() < 2 {
+        eprintln!("usage: wav-export <rom> <output.wav> [frames] [input-script] [--stems]");
+        std::process::exit(1);
+    }
+
+    let frames = match args.get(2) {
+        Some(frames) => frames.parse().expect("invalid frame count"),
+        None => 60 * 60,
+    };
+    let script = match args.get(3) {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).expect("error reading input script");
+            InputScript::parse(&text).unwrap_or_else(|e| panic!("{}", e))
+        }
+        None => InputScript::default(),
+    };
+
+    if let Ok((data, filepath)) = load_rom(args[0]) {
+        let mut gb = Gameboy::new(data, Some(filepath));
+        let path = Path::new(args[1]);
+        if path.extension().is_some_and(|e| e == "avi") {
+            export_avi(&mut gb, &script, frames, path).expect("error writing avi");
+        } else {
+            export_wav(&mut gb, &script, frames, path, stems).expect("error writing wav");
+        }
+    } else {
+        panic!("error loading rom");
+    }
+}
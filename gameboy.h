@@ -28,7 +28,7 @@ typedef struct ImageBuffer {
   const uint8_t *data;
 } ImageBuffer;
 
-void load_rom(const unsigned char *bytes, uintptr_t bytes_length);
+bool load_rom(const unsigned char *bytes, uintptr_t bytes_length);
 
 void frame(void);
 
@@ -36,6 +36,16 @@ void keydown(KeypadKey key);
 
 void keyup(KeypadKey key);
 
+void set_audio_sample_rate(uint32_t rate);
+
+/**
+ * # Safety
+ *
+ * `samples` must point to a buffer of at least `samples_length` i16 values.
+ * Returns the number of stereo frames written.
+ */
+uintptr_t audio(int16_t *samples, uintptr_t samples_length);
+
 struct ImageBuffer image(void);
 
 extern void log(struct String s);
@@ -1,6 +1,9 @@
 use crate::cpu::registers::Registers;
+use crate::cpu::trace::Tracer;
 use crate::cpu::{data, ld, misc, stack};
+use crate::gameboy::{Error, Target};
 use crate::mmu::MemoryManagementUnit;
+use crate::state::{StateReader, StateWriter, StrResult};
 
 #[allow(dead_code)]
 pub enum Interrupt {
@@ -11,57 +14,139 @@ pub enum Interrupt {
     Joypad = 0x10,
 }
 
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum CpuState {
+    Running,
+    // HALT, until an interrupt is pending
+    Halted,
+    // STOP, until a selected joypad line goes low. Nothing else is clocked either.
+    Stopped,
+    // After an illegal opcode, for good. The rest of the system keeps running.
+    Locked,
+}
+
 pub struct Cpu<'a> {
     pub registers: Registers,
     pub ime: bool,
     pub setdi: u32,
     pub setei: u32,
-    pub halt: u32,
-    pub stop: u32,
+    pub state: CpuState,
+    // HALT with IME off and an interrupt pending fails to increment PC after the
+    // next opcode fetch
+    pub halt_bug: bool,
     pub memory: MemoryManagementUnit<'a>,
-
-    // To debug
-    _executed_operations: Vec<u8>,
+    pub tracer: Option<Tracer>,
+    // Machine cycles spent on the current instruction and the GPU ticks they produced
+    cycles: u32,
+    ticks: u32,
 }
 
 impl Cpu<'_> {
-    pub fn new(data: Vec<u8>, file: Option<std::path::PathBuf>) -> Self {
-        let memory = MemoryManagementUnit::new_cgb(data, file).unwrap();
-        let registers = Registers::new(memory.gbmode);
+    pub fn new(
+        data: Vec<u8>,
+        file: Option<std::path::PathBuf>,
+        model: Target,
+        boot_rom: Option<Vec<u8>>,
+        seed: u32,
+    ) -> Result<Self, Error> {
+        let memory = MemoryManagementUnit::new(data, file, model, boot_rom, seed)?;
+        let registers = match memory.boot_rom_mapped() {
+            true => Registers::default(),
+            false => Registers::new(model, memory.gbmode),
+        };
 
-        Cpu {
+        Ok(Cpu {
             registers,
             memory,
             ime: false,
             setdi: 0,
             setei: 0,
-            halt: 0,
-            stop: 0,
-            _executed_operations: Vec::new(),
+            state: CpuState::Running,
+            halt_bug: false,
+            tracer: None,
+            cycles: 0,
+            ticks: 0,
+        })
+    }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.registers;
+        for v in [r.a, r.b, r.c, r.d, r.e, r.h, r.l, r.f] {
+            w.u8(v);
         }
+        w.u16(r.pc);
+        w.u16(r.sp);
+        w.bool(self.ime);
+        w.u32(self.setdi);
+        w.u32(self.setei);
+        w.u8(self.state as u8);
+        w.bool(self.halt_bug);
+        self.memory.save_state(w);
     }
-    fn mut_find_or_insert<T: PartialEq>(vec: &mut Vec<T>, val: T) -> &mut T {
-        if let Some(i) = vec.iter().position(|each| *each == val) {
-            &mut vec[i]
-        } else {
-            vec.push(val);
-            vec.last_mut().unwrap()
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        let registers = &mut self.registers;
+        for v in [
+            &mut registers.a,
+            &mut registers.b,
+            &mut registers.c,
+            &mut registers.d,
+            &mut registers.e,
+            &mut registers.h,
+            &mut registers.l,
+            &mut registers.f,
+        ] {
+            *v = r.u8()?;
         }
+        registers.pc = r.u16()?;
+        registers.sp = r.u16()?;
+        self.ime = r.bool()?;
+        self.setdi = r.u32()?;
+        self.setei = r.u32()?;
+        self.state = match r.u8()? {
+            0 => CpuState::Running,
+            1 => CpuState::Halted,
+            2 => CpuState::Stopped,
+            3 => CpuState::Locked,
+            _ => return Err("Invalid CPU state"),
+        };
+        self.halt_bug = r.bool()?;
+        self.memory.load_state(r)
+    }
+
+    // Every memory access takes one machine cycle, the rest of the system is clocked
+    // before the access so that it sees the state at that point of the instruction
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+        self.ticks += self.memory.do_cycle(4);
+    }
+    pub fn read(&mut self, a: u16) -> u8 {
+        self.tick();
+        self.memory.rb(a)
     }
-    pub fn debug(&mut self, op: u8) {
-        Cpu::mut_find_or_insert(&mut self._executed_operations, op);
-        // println!("{} {:#01x} {}", op, op, format!("{:?}", self.registers));
-        // println!("{} {}", op, format!("{:?}", self.registers));
+    pub fn write(&mut self, a: u16, v: u8) {
+        self.tick();
+        self.memory.wb(a, v)
     }
+    pub fn read_word(&mut self, a: u16) -> u16 {
+        let lo = self.read(a);
+        let hi = self.read(a.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+    pub fn write_word(&mut self, a: u16, v: u16) {
+        let [lo, hi] = v.to_le_bytes();
+        self.write(a, lo);
+        self.write(a.wrapping_add(1), hi);
+    }
+
     pub fn get_byte(&mut self) -> u8 {
-        let pc = self.memory.rb(self.registers.pc);
+        let pc = self.read(self.registers.pc);
         self.registers.pc = self.registers.pc.wrapping_add(1);
         pc
     }
     pub fn get_word(&mut self) -> u16 {
-        let w = self.memory.rw(self.registers.pc);
-        self.registers.pc += 2;
-        w
+        let lo = self.get_byte();
+        let hi = self.get_byte();
+        u16::from_le_bytes([lo, hi])
     }
     fn updateime(&mut self) {
         self.setdi = match self.setdi {
@@ -83,31 +168,41 @@ impl Cpu<'_> {
     }
 
     fn handleinterrupt(&mut self) -> u32 {
-        if !self.ime && self.halt == 0 {
+        // Only HALT wakes up on an interrupt, a locked up CPU stays locked
+        if matches!(self.state, CpuState::Locked | CpuState::Stopped) {
+            return 0;
+        }
+        if !self.ime && self.state != CpuState::Halted {
             return 0;
         }
 
-        let triggered = self.memory.inte & self.memory.intf;
+        let triggered = self.memory.inte & self.memory.intf & 0x1F;
         if triggered == 0 {
             return 0;
         }
 
-        self.halt = 0;
+        self.state = CpuState::Running;
         if !self.ime {
             return 0;
         }
         self.ime = false;
 
         let n = triggered.trailing_zeros();
-        if n >= 5 {
-            panic!("Invalid interrupt triggered");
-        }
         self.memory.intf &= !(1 << n);
-        let pc = self.registers.pc;
+        // Two wait cycles, the push and the jump
+        self.tick();
+        let mut pc = self.registers.pc;
+        // After EI; HALT the byte following HALT is not read again, the handler
+        // returns to the HALT instead
+        if self.halt_bug {
+            self.halt_bug = false;
+            pc = pc.wrapping_sub(1);
+        }
         stack::pushstack(self, pc);
+        self.tick();
         self.registers.pc = 0x0040 | ((n as u16) << 3);
 
-        4
+        5
     }
 
     pub fn exec(&mut self) -> u32 {
@@ -117,80 +212,66 @@ impl Cpu<'_> {
             n => return n,
         };
 
-        if self.halt == 1 {
+        if self.state != CpuState::Running {
             // Emulate an noop instruction
             1
         } else {
+            if let Some(mut tracer) = self.tracer.take() {
+                tracer.trace(self);
+                self.tracer = Some(tracer);
+            }
+            self.memory.instruction_pc = self.registers.pc;
             self.operation()
         }
     }
 
     pub fn do_cycle(&mut self) -> u32 {
-        let ticks = self.exec() * 4;
-        self.memory.do_cycle(ticks)
-
-        // match self.delay {
-        //     0 => {}
-        //     1 => {
-        //         self.delay = 0;
-        //         self.ime = 1;
-        //     }
-        //     2 => {
-        //         self.delay = 1;
-        //     }
-        //     _ => {}
-        // }
-
-        // let mut ticks = if self.halt == 0 && self.stop == 0 {
-        //     self.operation()
-        // } else {
-        //     self.memory.switch_speed();
-        //     self.stop = 0;
-        //     1
-        // };
+        if self.state == CpuState::Stopped {
+            if self.memory.keypad.rb() & 0x0F == 0x0F {
+                // The whole system is frozen, only time passes
+                return 4;
+            }
+            self.state = CpuState::Running;
+        }
 
-        // // See http://bgb.bircd.org/pandocs.htm#interrupts
-        // if self.ime != 0 || self.halt != 0 {
-        //     let ints = self.memory.intf & self.memory.inte;
+        self.cycles = 0;
+        self.ticks = 0;
+        let cycles = self.exec();
+        // Internal cycles that don't touch the bus
+        while self.cycles < cycles {
+            self.tick();
+        }
+        self.ticks
+    }
 
-        //     if ints != 0 {
-        //         let i = ints.trailing_zeros();
-        //         if self.ime != 0 {
-        //             self.memory.intf &= !(1 << (i as u32));
-        //         }
-        //         self.ime = 0;
-        //         self.halt = 0;
-        //         self.stop = 0;
-        //         match i {
-        //             0 => {
-        //                 stack::rst(self, 0x40);
-        //             }
-        //             1 => {
-        //                 stack::rst(self, 0x48);
-        //             }
-        //             2 => {
-        //                 stack::rst(self, 0x50);
-        //             }
-        //             3 => {
-        //                 stack::rst(self, 0x58);
-        //             }
-        //             4 => {
-        //                 stack::rst(self, 0x60);
-        //             }
-        //             _ => {}
-        //         }
-        //         ticks += 1;
-        //     }
-        // }
+    fn halt(&mut self) {
+        if !self.ime && self.memory.inte & self.memory.intf & 0x1F != 0 {
+            self.halt_bug = true;
+        } else {
+            self.state = CpuState::Halted;
+        }
+    }
 
-        // self.ticks += ticks;
-        // return ticks;
+    fn stop(&mut self) {
+        // The byte after STOP is skipped
+        self.get_byte();
+        self.memory.timer.wb(0xFF04, 0);
+        // Switching speed is the only use of STOP on the CGB, it doesn't stop anything
+        if self.memory.switch_speed() {
+            return;
+        }
+        if self.memory.keypad.rb() & 0x0F == 0x0F {
+            self.state = CpuState::Stopped;
+            self.memory.gpu.blank();
+        }
     }
 
     fn operation(&mut self) -> u32 {
         let op = self.get_byte();
-        // self.debug(op);
-        // println!("{:?}", self.registers);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.pc = self.registers.pc.wrapping_sub(1);
+        }
         match op {
             0x00 => 1,
             0x01 => {
@@ -254,8 +335,7 @@ impl Cpu<'_> {
                 1
             }
             0x10 => {
-                // stack::djnzn(self);
-                self.memory.switch_speed();
+                self.stop();
                 1
             }
             0x11 => {
@@ -639,7 +719,7 @@ impl Cpu<'_> {
                 2
             }
             0x76 => {
-                self.halt = 1;
+                self.halt();
                 1
             }
             0x77 => {
@@ -1111,13 +1191,166 @@ impl Cpu<'_> {
                 4
             }
             _ => {
-                println!(
-                    "Instruction at {:#01x} | {:#06x} | {} not implemented, stopping.",
-                    op, op, op
-                );
-                self.stop = 1;
-                panic!("{:#06x} not implemented", op);
+                self.state = CpuState::Locked;
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.dump();
+                }
+                1
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::CpuState;
+    use crate::gameboy::{GameBoy, GameBoyColor, Gameboy, Target};
+    use crate::header::test_rom;
+    use crate::input::KeypadKey;
+
+    fn gameboy(model: Target, program: &[u8]) -> Gameboy {
+        let mut rom = test_rom(program);
+        rom[0x143] = 0x80;
+        Gameboy::builder(rom).model(model).build().unwrap()
+    }
+
+    #[test]
+    fn reads_see_mid_instruction_state() {
+        let program = [
+            0x3E, 0x05, // LD A,0x05
+            0xE0, 0x07, // LDH (TAC),A ; TIMA counts every 4 M-cycles
+            0xFA, 0x05, 0xFF, // LD A,(TIMA) ; reads in its 4th M-cycle
+        ];
+        let mut gb = gameboy(GameBoy, &program);
+        let cpu = gb.cpu_mut();
+        cpu.do_cycle();
+        cpu.do_cycle();
+        assert_eq!(cpu.do_cycle(), 16);
+        assert_eq!(cpu.registers.a, 1);
+    }
+
+    #[test]
+    fn writes_land_mid_instruction() {
+        let program = [
+            0xE0, 0x04, // LDH (DIV),A ; the system counter starts from 0
+            0x3E, 0x05, // LD A,0x05
+            0xE0, 0x07, // LDH (TAC),A ; TIMA counts every 4 M-cycles
+            0x0E, 0x05, // LD C,0x05
+            0x3E, 0x80, // LD A,0x80
+            0x00, // NOP
+            0xE2, // LD (C),A ; TIMA increments just before the write
+        ];
+        let mut gb = gameboy(GameBoy, &program);
+        let cpu = gb.cpu_mut();
+        for _ in 0..7 {
+            cpu.do_cycle();
+        }
+        assert_eq!(cpu.registers.pc, 0x10C);
+        assert_eq!(gb.peek(0xFF05), 0x80);
+    }
+
+    #[test]
+    fn halt_bug_and_lockup() {
+        let program = [
+            0xF3, // DI
+            0x3E, 0x01, // LD A,0x01
+            0xE0, 0xFF, // LDH (IE),A
+            0xE0, 0x0F, // LDH (IF),A
+            0x76, // HALT ; doesn't halt, INC B runs twice
+            0x04, // INC B
+            0xD3, // illegal
+        ];
+        let mut gb = gameboy(GameBoy, &program);
+        let cpu = gb.cpu_mut();
+        for _ in 0..20 {
+            cpu.do_cycle();
+        }
+        assert_eq!(cpu.registers.b, 2);
+        assert_eq!(cpu.state, CpuState::Locked);
+        assert_eq!(cpu.registers.pc, 0x10A);
+    }
+
+    #[test]
+    fn halt_bug_after_ei() {
+        let program = [
+            0x3E, 0x01, // LD A,0x01
+            0xE0, 0xFF, // LDH (IE),A
+            0xE0, 0x0F, // LDH (IF),A
+            0xFB, // EI
+            0x76, // HALT ; the interrupt returns here
+        ];
+        let mut rom = test_rom(&program);
+        rom[0x40] = 0x0C; // INC C
+        rom[0x41] = 0xD9; // RETI
+        rom[0x143] = 0x80;
+        let mut gb = Gameboy::builder(rom).model(GameBoy).build().unwrap();
+        let cpu = gb.cpu_mut();
+        let c = cpu.registers.c;
+        for _ in 0..7 {
+            cpu.do_cycle();
+        }
+        assert_eq!(cpu.registers.pc, 0x41);
+        assert_eq!(cpu.registers.c, c + 1);
+        let sp = cpu.registers.sp;
+        assert_eq!(cpu.memory.read_byte(sp), 0x07);
+        assert_eq!(cpu.memory.read_byte(sp.wrapping_add(1)), 0x01);
+        assert!(!cpu.halt_bug);
+    }
+
+    #[test]
+    fn lockup_ignores_interrupts() {
+        let program = [
+            0xFB, // EI
+            0x3E, 0x01, // LD A,0x01
+            0xE0, 0x0F, // LDH (IF),A
+            0xDD, // illegal
+        ];
+        let mut gb = gameboy(GameBoy, &program);
+        for _ in 0..5 {
+            gb.cpu_mut().do_cycle();
+        }
+        assert_eq!(gb.cpu().state, CpuState::Locked);
+        gb.poke(0xFFFF, 0x01);
+        for _ in 0..20 {
+            gb.cpu_mut().do_cycle();
+        }
+        assert_eq!(gb.cpu().state, CpuState::Locked);
+        assert_eq!(gb.cpu().registers.pc, 0x106);
+        assert_eq!(gb.peek(0xFF0F) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn stop_waits_for_joypad() {
+        let program = [
+            0x3E, 0x10, // LD A,0x10
+            0xE0, 0x00, // LDH (P1),A ; selects the buttons
+            0x10, 0x00, // STOP
+            0x04, // INC B
+            0x18, 0xFE, // JR -2
+        ];
+        let mut gb = gameboy(GameBoy, &program);
+        gb.frame();
+        gb.frame();
+        assert_eq!(gb.cpu().state, CpuState::Stopped);
+        assert_eq!((gb.cpu().registers.b, gb.peek(0xFF04)), (0, 0));
+
+        gb.keydown(KeypadKey::A);
+        gb.frame();
+        assert_eq!(gb.cpu().state, CpuState::Running);
+        assert_eq!(gb.cpu().registers.b, 1);
+    }
+
+    #[test]
+    fn stop_switches_speed() {
+        let program = [
+            0x3E, 0x01, // LD A,0x01
+            0xE0, 0x4D, // LDH (KEY1),A
+            0x10, 0x00, // STOP
+            0x18, 0xFE, // JR -2
+        ];
+        let mut gb = gameboy(GameBoyColor, &program);
+        gb.frame();
+        assert_eq!(gb.cpu().state, CpuState::Running);
+        assert_eq!(gb.peek(0xFF4D), 0xFE);
+    }
+}
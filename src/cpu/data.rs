@@ -142,7 +142,7 @@ pub fn addr_a(cpu: &mut Cpu) {
 }
 pub fn addhl(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) + cpu.registers.l as u16;
-    let e = cpu.memory.rb(addr);
+    let e = cpu.read(addr);
     let a = cpu.registers.a;
     let r = a.wrapping_add(e);
     cpu.registers.flag(Z, r == 0);
@@ -548,7 +548,7 @@ pub fn orr_a(cpu: &mut Cpu) {
 }
 pub fn orhl(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) + (cpu.registers.l as u16);
-    let v = cpu.memory.rb(addr);
+    let v = cpu.read(addr);
     let r = cpu.registers.a | v;
     cpu.registers.flag(Z, r == 0);
     cpu.registers.flag(C, false);
@@ -624,7 +624,7 @@ pub fn xorr_a(cpu: &mut Cpu) {
 }
 pub fn xorhl(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) + (cpu.registers.l as u16);
-    let v = cpu.memory.rb(addr);
+    let v = cpu.read(addr);
     let r = cpu.registers.a ^ v;
     cpu.registers.flag(Z, r == 0);
     cpu.registers.flag(C, false);
@@ -690,12 +690,12 @@ pub fn incr_b(cpu: &mut Cpu) {
 }
 pub fn inchlm(cpu: &mut Cpu) {
     let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-    let v = cpu.memory.rb(a);
+    let v = cpu.read(a);
     let r = v.wrapping_add(1);
     cpu.registers.flag(Z, r == 0);
     cpu.registers.flag(H, (v & 0x0F) + 1 > 0x0F);
     cpu.registers.flag(N, false);
-    cpu.memory.wb(a, r);
+    cpu.write(a, r);
 }
 pub fn decr_b(cpu: &mut Cpu) {
     let a = cpu.registers.b;
@@ -755,12 +755,12 @@ pub fn decr_a(cpu: &mut Cpu) {
 }
 pub fn dechlm(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-    let v = cpu.memory.rb(addr);
+    let v = cpu.read(addr);
     let r = v.wrapping_sub(1);
     cpu.registers.flag(Z, r == 0);
     cpu.registers.flag(H, (v & 0x0F) == 0);
     cpu.registers.flag(N, true);
-    cpu.memory.wb(addr, r);
+    cpu.write(addr, r);
 }
 pub fn incbc(cpu: &mut Cpu) {
     let mut val = ((cpu.registers.b as u16) << 8) | (cpu.registers.c as u16);
@@ -0,0 +1,250 @@
+// SM83 disassembler. Decodes from raw bytes or from memory without side effects,
+// cycle counts are in machine cycles like the ones `Cpu::operation` returns.
+
+use crate::mmu::MemoryManagementUnit;
+use std::fmt;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Instruction {
+    pub address: u16,
+    // Only the first `length` bytes belong to the instruction
+    pub bytes: [u8; 3],
+    pub length: u8,
+    pub mnemonic: String,
+    pub cycles: u8,
+    // Cycles when a conditional jump, call or return is taken
+    pub cycles_taken: Option<u8>,
+}
+
+impl Instruction {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+
+    pub fn is_illegal(&self) -> bool {
+        ILLEGAL.contains(&self.bytes[0])
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.mnemonic)
+    }
+}
+
+const ILLEGAL: [u8; 11] = [
+    0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+];
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+    1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+    2, 3, 3, 1, 3, 4, 2, 4, 2, 4, 3, 1, 3, 1, 2, 4,
+    3, 3, 2, 1, 1, 4, 2, 4, 4, 1, 4, 1, 1, 1, 2, 4,
+    3, 3, 2, 1, 1, 4, 2, 4, 3, 2, 4, 1, 1, 1, 2, 4,
+];
+
+fn cycles_taken(op: u8) -> Option<u8> {
+    match op {
+        0x20 | 0x28 | 0x30 | 0x38 => Some(3),
+        0xC0 | 0xC8 | 0xD0 | 0xD8 => Some(5),
+        0xC2 | 0xCA | 0xD2 | 0xDA => Some(4),
+        0xC4 | 0xCC | 0xD4 | 0xDC => Some(6),
+        _ => None,
+    }
+}
+
+fn length(op: u8) -> u8 {
+    match op {
+        0x01 | 0x08 | 0x11 | 0x21 | 0x31 | 0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD
+        | 0xD2 | 0xD4 | 0xDA | 0xDC | 0xEA | 0xFA => 3,
+        0x06 | 0x0E | 0x10 | 0x16 | 0x18 | 0x1E | 0x20 | 0x26 | 0x28 | 0x2E | 0x30
+        | 0x36 | 0x38 | 0x3E | 0xC6 | 0xCB | 0xCE | 0xD6 | 0xDE | 0xE0 | 0xE6 | 0xE8
+        | 0xEE | 0xF0 | 0xF6 | 0xF8 | 0xFE => 2,
+        _ => 1,
+    }
+}
+
+fn signed(d: u8) -> String {
+    match d as i8 {
+        d if d < 0 => format!("-{}", -(d as i16)),
+        d => format!("+{}", d),
+    }
+}
+
+// Decodes the instruction at `address` whose bytes start with `bytes`. Missing
+// operand bytes read as zero.
+pub fn decode(address: u16, bytes: &[u8]) -> Instruction {
+    let mut b = [0; 3];
+    let n = bytes.len().min(3);
+    b[..n].copy_from_slice(&bytes[..n]);
+
+    let op = b[0];
+    let length = length(op);
+    let n = format!("${:02X}", b[1]);
+    let nn = format!("${:04X}", u16::from_le_bytes([b[1], b[2]]));
+    let jr = format!(
+        "${:04X}",
+        address.wrapping_add(2).wrapping_add(b[1] as i8 as u16)
+    );
+
+    let (x, y, z) = (op >> 6, ((op >> 3) & 7) as usize, (op & 7) as usize);
+    let (p, q) = (y >> 1, y & 1);
+    let mnemonic = match (x, z) {
+        _ if ILLEGAL.contains(&op) => format!("DB ${:02X}", op),
+        (0, 0) => match y {
+            0 => "NOP".to_string(),
+            1 => format!("LD ({}),SP", nn),
+            2 => "STOP".to_string(),
+            3 => format!("JR {}", jr),
+            _ => format!("JR {},{}", CC[y - 4], jr),
+        },
+        (0, 1) if q == 0 => format!("LD {},{}", RP[p], nn),
+        (0, 1) => format!("ADD HL,{}", RP[p]),
+        (0, 2) => {
+            let m = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            match q {
+                0 => format!("LD {},A", m),
+                _ => format!("LD A,{}", m),
+            }
+        }
+        (0, 3) if q == 0 => format!("INC {}", RP[p]),
+        (0, 3) => format!("DEC {}", RP[p]),
+        (0, 4) => format!("INC {}", R[y]),
+        (0, 5) => format!("DEC {}", R[y]),
+        (0, 6) => format!("LD {},{}", R[y], n),
+        (0, _) => {
+            ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"][y].to_string()
+        }
+        (1, 6) if y == 6 => "HALT".to_string(),
+        (1, _) => format!("LD {},{}", R[y], R[z]),
+        (2, _) => format!("{}{}", ALU[y], R[z]),
+        (_, 0) => match y {
+            0..=3 => format!("RET {}", CC[y]),
+            4 => format!("LDH ({}),A", n),
+            5 => format!("ADD SP,{}", signed(b[1])),
+            6 => format!("LDH A,({})", n),
+            _ => format!("LD HL,SP{}", signed(b[1])),
+        },
+        (_, 1) if q == 0 => format!("POP {}", RP2[p]),
+        (_, 1) => ["RET", "RETI", "JP HL", "LD SP,HL"][p].to_string(),
+        (_, 2) => match y {
+            0..=3 => format!("JP {},{}", CC[y], nn),
+            4 => "LD ($FF00+C),A".to_string(),
+            5 => format!("LD ({}),A", nn),
+            6 => "LD A,($FF00+C)".to_string(),
+            _ => format!("LD A,({})", nn),
+        },
+        (_, 3) => match y {
+            0 => format!("JP {}", nn),
+            1 => return decode_cb(address, b[1]),
+            6 => "DI".to_string(),
+            _ => "EI".to_string(),
+        },
+        (_, 4) => format!("CALL {},{}", CC[y], nn),
+        (_, 5) if q == 0 => format!("PUSH {}", RP2[p]),
+        (_, 5) => format!("CALL {}", nn),
+        (_, 6) => format!("{}{}", ALU[y], n),
+        _ => format!("RST ${:02X}", y * 8),
+    };
+
+    Instruction {
+        address,
+        bytes: b,
+        length,
+        mnemonic,
+        cycles: CYCLES[op as usize],
+        cycles_taken: cycles_taken(op),
+    }
+}
+
+fn decode_cb(address: u16, op: u8) -> Instruction {
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, (op & 7) as usize);
+    let mnemonic = match x {
+        0 => format!("{} {}", ROT[y as usize], R[z]),
+        1 => format!("BIT {},{}", y, R[z]),
+        2 => format!("RES {},{}", y, R[z]),
+        _ => format!("SET {},{}", y, R[z]),
+    };
+    let cycles = match (x, z) {
+        (1, 6) => 3,
+        (_, 6) => 4,
+        _ => 2,
+    };
+
+    Instruction {
+        address,
+        bytes: [0xCB, op, 0],
+        length: 2,
+        mnemonic,
+        cycles,
+        cycles_taken: None,
+    }
+}
+
+pub(crate) fn disassemble(
+    memory: &mut MemoryManagementUnit,
+    address: u16,
+) -> Instruction {
+    let bytes = [0, 1, 2].map(|i| memory.read_byte(address.wrapping_add(i)));
+    decode(address, &bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::decode;
+
+    #[test]
+    fn decodes() {
+        let check = |address: u16, bytes: &[u8], text: &str, length: u8, cycles: u8| {
+            let i = decode(address, bytes);
+            assert_eq!(
+                (i.mnemonic.as_str(), i.length, i.cycles),
+                (text, length, cycles)
+            );
+        };
+        check(0x100, &[0x00], "NOP", 1, 1);
+        check(0x100, &[0xC3, 0x50, 0x01], "JP $0150", 3, 4);
+        check(0x100, &[0x20, 0xFA], "JR NZ,$00FC", 2, 2);
+        check(0x100, &[0x08, 0x00, 0xC0], "LD ($C000),SP", 3, 5);
+        check(0x100, &[0x2A], "LD A,(HL+)", 1, 2);
+        check(0x100, &[0x7E], "LD A,(HL)", 1, 2);
+        check(0x100, &[0x76], "HALT", 1, 1);
+        check(0x100, &[0xAF], "XOR A", 1, 1);
+        check(0x100, &[0xE0, 0x40], "LDH ($40),A", 2, 3);
+        check(0x100, &[0xE2], "LD ($FF00+C),A", 1, 2);
+        check(0x100, &[0xE8, 0xFE], "ADD SP,-2", 2, 4);
+        check(0x100, &[0xF8, 0x05], "LD HL,SP+5", 2, 3);
+        check(0x100, &[0xF5], "PUSH AF", 1, 4);
+        check(0x100, &[0xFF], "RST $38", 1, 4);
+        check(0x100, &[0xD3], "DB $D3", 1, 1);
+        check(0x100, &[0xCB, 0x37], "SWAP A", 2, 2);
+        check(0x100, &[0xCB, 0x7E], "BIT 7,(HL)", 2, 3);
+        check(0x100, &[0xCB, 0xC6], "SET 0,(HL)", 2, 4);
+
+        let call = decode(0x100, &[0xC4, 0x00, 0x02]);
+        assert_eq!((call.cycles, call.cycles_taken), (3, Some(6)));
+        assert!(decode(0, &[0xFD]).is_illegal());
+    }
+}
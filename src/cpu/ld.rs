@@ -3,89 +3,89 @@ use crate::cpu::registers::CpuFlag::{C, H, N, Z};
 
 pub fn r_hlm_b(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.registers.b = cpu.memory.rb(addr);
+    cpu.registers.b = cpu.read(addr);
 }
 pub fn r_hlm_c(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.registers.c = cpu.memory.rb(addr);
+    cpu.registers.c = cpu.read(addr);
 }
 pub fn r_hlm_d(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.registers.d = cpu.memory.rb(addr);
+    cpu.registers.d = cpu.read(addr);
 }
 pub fn r_hlm_e(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.registers.e = cpu.memory.rb(addr);
+    cpu.registers.e = cpu.read(addr);
 }
 pub fn r_hlm_h(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.registers.h = cpu.memory.rb(addr);
+    cpu.registers.h = cpu.read(addr);
 }
 pub fn r_hlm_l(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.registers.l = cpu.memory.rb(addr);
+    cpu.registers.l = cpu.read(addr);
 }
 pub fn r_hlm_a(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.registers.a = cpu.memory.rb(addr);
+    cpu.registers.a = cpu.read(addr);
 }
 pub fn hlmr_b(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, cpu.registers.b);
+    cpu.write(addr, cpu.registers.b);
 }
 pub fn hlmr_c(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, cpu.registers.c);
+    cpu.write(addr, cpu.registers.c);
 }
 pub fn hlmr_d(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, cpu.registers.d);
+    cpu.write(addr, cpu.registers.d);
 }
 pub fn hlmr_e(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, cpu.registers.e);
+    cpu.write(addr, cpu.registers.e);
 }
 pub fn hlmr_h(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, cpu.registers.h);
+    cpu.write(addr, cpu.registers.h);
 }
 pub fn hlmr_l(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, cpu.registers.l);
+    cpu.write(addr, cpu.registers.l);
 }
 pub fn hlmr_a(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, cpu.registers.a);
+    cpu.write(addr, cpu.registers.a);
 }
 pub fn hlmn(cpu: &mut Cpu) {
-    let value = cpu.memory.rb(cpu.registers.pc);
+    let value = cpu.read(cpu.registers.pc);
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    cpu.memory.wb(addr, value);
+    cpu.write(addr, value);
     cpu.registers.pc += 1;
 }
 pub fn bcm_a(cpu: &mut Cpu) {
     let addr = ((cpu.registers.b as u16) << 8) + cpu.registers.c as u16;
-    cpu.memory.wb(addr, cpu.registers.a);
+    cpu.write(addr, cpu.registers.a);
 }
 pub fn dem_a(cpu: &mut Cpu) {
     let addr = ((cpu.registers.d as u16) << 8) + cpu.registers.e as u16;
-    cpu.memory.wb(addr, cpu.registers.a);
+    cpu.write(addr, cpu.registers.a);
 }
 pub fn mm_a(cpu: &mut Cpu) {
     let addr = cpu.get_word();
-    cpu.memory.wb(addr, cpu.registers.a);
+    cpu.write(addr, cpu.registers.a);
 }
 pub fn abcm(cpu: &mut Cpu) {
     let addr = ((cpu.registers.b as u16) << 8) + cpu.registers.c as u16;
-    cpu.registers.a = cpu.memory.rb(addr);
+    cpu.registers.a = cpu.read(addr);
 }
 pub fn adem(cpu: &mut Cpu) {
     let addr = ((cpu.registers.d as u16) << 8) + cpu.registers.e as u16;
-    cpu.registers.a = cpu.memory.rb(addr);
+    cpu.registers.a = cpu.read(addr);
 }
 pub fn amm(cpu: &mut Cpu) {
     let addr = cpu.get_word();
-    cpu.registers.a = cpu.memory.rb(addr);
+    cpu.registers.a = cpu.read(addr);
 }
 pub fn bcnn(cpu: &mut Cpu) {
     let value = cpu.get_word();
@@ -93,8 +93,8 @@ pub fn bcnn(cpu: &mut Cpu) {
     cpu.registers.c = (value & 0x00FF) as u8;
 }
 pub fn denn(cpu: &mut Cpu) {
-    cpu.registers.e = cpu.memory.rb(cpu.registers.pc);
-    cpu.registers.d = cpu.memory.rb(cpu.registers.pc + 1);
+    cpu.registers.e = cpu.read(cpu.registers.pc);
+    cpu.registers.d = cpu.read(cpu.registers.pc + 1);
     cpu.registers.pc += 2;
 }
 pub fn hlnn(cpu: &mut Cpu) {
@@ -107,7 +107,7 @@ pub fn spnn(cpu: &mut Cpu) {
 }
 pub fn hlia(cpu: &mut Cpu) {
     let mut hl = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-    cpu.memory.wb(hl, cpu.registers.a);
+    cpu.write(hl, cpu.registers.a);
     hl += 1;
     cpu.registers.h = (hl >> 8) as u8;
     cpu.registers.l = (hl & 0x00FF) as u8;
@@ -117,43 +117,42 @@ pub fn ahli(cpu: &mut Cpu) {
     let value = hl.wrapping_add(1);
     cpu.registers.h = (value >> 8) as u8;
     cpu.registers.l = (value & 0x00FF) as u8;
-    cpu.registers.a = cpu.memory.rb(hl);
+    cpu.registers.a = cpu.read(hl);
 }
 pub fn hld_a(cpu: &mut Cpu) {
     let addr = ((cpu.registers.h as u16) << 8) + cpu.registers.l as u16;
     let value = addr.wrapping_sub(1);
     cpu.registers.h = (value >> 8) as u8;
     cpu.registers.l = (value & 0x00FF) as u8;
-    cpu.memory.wb(addr, cpu.registers.a);
+    cpu.write(addr, cpu.registers.a);
 }
 pub fn ahld(cpu: &mut Cpu) {
     let res = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
     let value = res - 1;
     cpu.registers.h = (value >> 8) as u8;
     cpu.registers.l = (value & 0x00FF) as u8;
-    cpu.registers.a = cpu.memory.rb(res);
+    cpu.registers.a = cpu.read(res);
 }
 pub fn aion(cpu: &mut Cpu) {
     let addr = 0xFF00 | cpu.get_byte() as u16;
-    cpu.registers.a = cpu.memory.rb(addr);
+    cpu.registers.a = cpu.read(addr);
 }
 pub fn ion_a(cpu: &mut Cpu) {
     let a = 0xFF00 | cpu.get_byte() as u16;
-    cpu.memory.wb(a, cpu.registers.a);
+    cpu.write(a, cpu.registers.a);
 }
 pub fn aioc(cpu: &mut Cpu) {
-    cpu.registers.a = cpu.memory.rb(0xFF00 | cpu.registers.c as u16);
+    cpu.registers.a = cpu.read(0xFF00 | cpu.registers.c as u16);
 }
 pub fn ioca(cpu: &mut Cpu) {
-    cpu.memory
-        .wb(0xFF00 | cpu.registers.c as u16, cpu.registers.a);
+    cpu.write(0xFF00 | cpu.registers.c as u16, cpu.registers.a);
 }
 pub fn hlspn(cpu: &mut Cpu) {
     cpu.registers.sp = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
 }
 pub fn mmsp(cpu: &mut Cpu) {
     let addr = cpu.get_word();
-    cpu.memory.ww(addr, cpu.registers.sp);
+    cpu.write_word(addr, cpu.registers.sp);
 }
 
 pub fn rr_bc(cpu: &mut Cpu) {
@@ -10,7 +10,7 @@ pub fn bit(cpu: &mut Cpu, a: u8, b: u8) -> u32 {
 }
 pub fn bit_m(cpu: &mut Cpu, i: u8) -> u32 {
     let addr = ((cpu.registers.h as u16) << 8) | cpu.registers.l as u16;
-    let value = cpu.memory.rb(addr);
+    let value = cpu.read(addr);
     bit(cpu, value, i);
     3
 }
@@ -132,9 +132,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         6 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_rlc(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         7 => {
@@ -167,9 +167,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         14 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_rrc(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         15 => {
@@ -202,9 +202,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x16 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_rl(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         0x17 => {
@@ -237,9 +237,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x1E => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_rr(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         0x1F => {
@@ -272,9 +272,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x26 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_sla(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         0x27 => {
@@ -307,9 +307,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x2E => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_sra(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         0x2F => {
@@ -342,9 +342,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x36 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_swap(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         0x37 => {
@@ -369,9 +369,9 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x3E => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a);
+            let v = cpu.read(a);
             let v2 = alu_srl(cpu, v);
-            cpu.memory.wb(a, v2);
+            cpu.write(a, v2);
             4
         }
         60 => {
@@ -500,8 +500,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x86 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 0);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 0);
+            cpu.write(a, v);
             4
         }
         0x87 => {
@@ -534,8 +534,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x8E => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 1);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 1);
+            cpu.write(a, v);
             4
         }
         0x8F => {
@@ -568,8 +568,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x96 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 2);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 2);
+            cpu.write(a, v);
             4
         }
         0x97 => {
@@ -602,8 +602,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0x9E => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 3);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 3);
+            cpu.write(a, v);
             4
         }
         0x9F => {
@@ -636,8 +636,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xA6 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 4);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 4);
+            cpu.write(a, v);
             4
         }
         0xA7 => {
@@ -670,8 +670,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xAE => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 5);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 5);
+            cpu.write(a, v);
             4
         }
         0xAF => {
@@ -704,8 +704,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xB6 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 6);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 6);
+            cpu.write(a, v);
             4
         }
         0xB7 => {
@@ -738,8 +738,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xBE => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) & !(1 << 7);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) & !(1 << 7);
+            cpu.write(a, v);
             4
         }
         0xBF => {
@@ -772,8 +772,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xC6 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 0);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 0);
+            cpu.write(a, v);
             4
         }
         0xC7 => {
@@ -806,8 +806,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xCE => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 1);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 1);
+            cpu.write(a, v);
             4
         }
         0xCF => {
@@ -840,8 +840,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xD6 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 2);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 2);
+            cpu.write(a, v);
             4
         }
         0xD7 => {
@@ -874,8 +874,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xDE => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 3);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 3);
+            cpu.write(a, v);
             4
         }
         0xDF => {
@@ -908,8 +908,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xE6 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 4);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 4);
+            cpu.write(a, v);
             4
         }
         0xE7 => {
@@ -942,8 +942,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xEE => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 5);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 5);
+            cpu.write(a, v);
             4
         }
         0xEF => {
@@ -976,8 +976,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xF6 => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 6);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 6);
+            cpu.write(a, v);
             4
         }
         0xF7 => {
@@ -1010,8 +1010,8 @@ pub fn cbmap(cpu: &mut Cpu) -> u32 {
         }
         0xFE => {
             let a = ((cpu.registers.h as u16) << 8) | (cpu.registers.l as u16);
-            let v = cpu.memory.rb(a) | (1 << 7);
-            cpu.memory.wb(a, v);
+            let v = cpu.read(a) | (1 << 7);
+            cpu.write(a, v);
             4
         }
         0xFF => {
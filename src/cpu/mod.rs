@@ -1,5 +1,7 @@
 pub mod core;
-mod registers;
+pub mod disasm;
+pub(crate) mod registers;
+pub mod trace;
 
 mod data;
 mod ld;
@@ -1,7 +1,8 @@
+use crate::gameboy::Target::{self, *};
 use crate::mode::GbMode;
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Registers {
     // 8-bit registers
     pub a: u8,
@@ -50,45 +51,32 @@ impl fmt::Display for Registers {
 }
 
 impl Registers {
-    pub fn new(mode: GbMode) -> Registers {
-        use CpuFlag::*;
-        match mode {
-            GbMode::Classic => Registers {
-                a: 0x01,
-                f: C as u8 | H as u8 | Z as u8,
-                b: 0x00,
-                c: 0x13,
-                d: 0x00,
-                e: 0xD8,
-                h: 0x01,
-                l: 0x4D,
-                pc: 0x0100,
-                sp: 0xFFFE,
-            },
-            GbMode::ColorAsClassic => Registers {
-                a: 0x11,
-                f: Z as u8,
-                b: 0x00,
-                c: 0x00,
-                d: 0x00,
-                e: 0x08,
-                h: 0x00,
-                l: 0x7C,
-                pc: 0x0100,
-                sp: 0xFFFE,
-            },
-            GbMode::Color => Registers {
-                a: 0x11,
-                f: Z as u8,
-                b: 0x00,
-                c: 0x00,
-                d: 0xFF,
-                e: 0x56,
-                h: 0x00,
-                l: 0x0D,
-                pc: 0x0100,
-                sp: 0xFFFE,
-            },
+    // Register values left behind by the boot ROM of each model
+    pub fn new(model: Target, mode: GbMode) -> Registers {
+        let [a, f, b, c, d, e, h, l] = match (model, mode) {
+            (GameBoy, _) => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            (GameBoyPocket, _) => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            (SuperGameBoy, _) => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            (GameBoyColor, GbMode::Color) => {
+                [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D]
+            }
+            (GameBoyColor, _) => [0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C],
+            (GameBoyAdvance, GbMode::Color) => {
+                [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D]
+            }
+            (GameBoyAdvance, _) => [0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7C],
+        };
+        Registers {
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            pc: 0x0100,
+            sp: 0xFFFE,
         }
     }
 
@@ -1,59 +1,55 @@
 use crate::cpu::core::Cpu;
 use crate::cpu::registers::CpuFlag::{C, Z};
 
+// Internal cycle, then the high byte and the low byte
 pub fn pushstack(cpu: &mut Cpu, value: u16) {
-    cpu.registers.sp = cpu.registers.sp.wrapping_sub(2);
-    cpu.memory.ww(cpu.registers.sp, value);
+    cpu.tick();
+    let [lo, hi] = value.to_le_bytes();
+    cpu.registers.sp = cpu.registers.sp.wrapping_sub(1);
+    cpu.write(cpu.registers.sp, hi);
+    cpu.registers.sp = cpu.registers.sp.wrapping_sub(1);
+    cpu.write(cpu.registers.sp, lo);
 }
 
 fn popstack(cpu: &mut Cpu) -> u16 {
-    let res = cpu.memory.rw(cpu.registers.sp);
-    cpu.registers.sp += 2;
+    let res = cpu.read_word(cpu.registers.sp);
+    cpu.registers.sp = cpu.registers.sp.wrapping_add(2);
     res
 }
 
 pub fn pushbc(c: &mut Cpu) {
     let value = ((c.registers.b as u16) << 8) | (c.registers.c as u16);
-    c.registers.sp -= 2;
-    c.memory.ww(c.registers.sp, value);
+    pushstack(c, value);
 }
 pub fn pushde(c: &mut Cpu) {
     let value = ((c.registers.d as u16) << 8) | (c.registers.e as u16);
-    c.registers.sp -= 2;
-    c.memory.ww(c.registers.sp, value);
+    pushstack(c, value);
 }
 pub fn pushhl(c: &mut Cpu) {
     let value = ((c.registers.h as u16) << 8) | (c.registers.l as u16);
-    c.registers.sp -= 2;
-    c.memory.ww(c.registers.sp, value);
+    pushstack(c, value);
 }
 pub fn pushaf(c: &mut Cpu) {
     let value = ((c.registers.a as u16) << 8) | ((c.registers.f & 0xF0) as u16);
-    c.registers.sp -= 2;
-    c.memory.ww(c.registers.sp, value);
+    pushstack(c, value);
 }
 pub fn popbc(c: &mut Cpu) {
-    let val = c.memory.rw(c.registers.sp);
-    c.registers.sp += 2;
+    let val = popstack(c);
     c.registers.b = (val >> 8) as u8;
     c.registers.c = (val & 0x00FF) as u8
 }
 pub fn popde(c: &mut Cpu) {
-    let val = c.memory.rw(c.registers.sp);
-    c.registers.sp += 2;
+    let val = popstack(c);
     c.registers.d = (val >> 8) as u8;
     c.registers.e = (val & 0x00FF) as u8;
 }
 pub fn pophl(c: &mut Cpu) {
-    let value = c.memory.rw(c.registers.sp);
-    c.registers.sp += 2;
+    let value = popstack(c);
     c.registers.h = (value >> 8) as u8;
     c.registers.l = (value & 0x00FF) as u8;
 }
 pub fn popaf(c: &mut Cpu) {
-    let res = c.memory.rw(c.registers.sp);
-    c.registers.sp += 2;
-    let v = res & 0xFFF0;
+    let v = popstack(c) & 0xFFF0;
     c.registers.a = (v >> 8) as u8;
     c.registers.f = (v & 0x00F0) as u8;
 }
@@ -64,183 +60,103 @@ pub fn jphl(c: &mut Cpu) {
     let value = ((c.registers.h as u16) << 8) | (c.registers.l as u16);
     c.registers.pc = value;
 }
-pub fn jpnznn(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(Z) {
-        c.registers.pc = c.get_word();
-        4
-    } else {
-        c.registers.pc += 2;
-        3
+// The operand is always fetched, taking the jump costs one more cycle
+fn jp(c: &mut Cpu, taken: bool) -> u32 {
+    let addr = c.get_word();
+    if !taken {
+        return 3;
     }
+    c.registers.pc = addr;
+    4
+}
+pub fn jpnznn(c: &mut Cpu) -> u32 {
+    jp(c, !c.registers.getflag(Z))
 }
 pub fn jpznn(c: &mut Cpu) -> u32 {
-    if c.registers.getflag(Z) {
-        c.registers.pc = c.memory.rw(c.registers.pc);
-        4
-    } else {
-        c.registers.pc += 2;
-        3
-    }
+    jp(c, c.registers.getflag(Z))
 }
 pub fn jpncnn(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(C) {
-        c.registers.pc = c.memory.rw(c.registers.pc);
-        4
-    } else {
-        c.registers.pc += 2;
-        3
-    }
+    jp(c, !c.registers.getflag(C))
 }
-pub fn jpcnn(cpu: &mut Cpu) -> u32 {
-    if cpu.registers.getflag(C) {
-        cpu.registers.pc = cpu.get_word();
-        4
-    } else {
-        cpu.registers.pc += 2;
-        3
-    }
+pub fn jpcnn(c: &mut Cpu) -> u32 {
+    jp(c, c.registers.getflag(C))
 }
 pub fn jrn(c: &mut Cpu) {
+    jr(c, true);
+}
+fn jr(c: &mut Cpu, taken: bool) -> u32 {
     let n = c.get_byte() as i8;
-    c.registers.pc = ((c.registers.pc as u32 as i32) + (n as i32)) as u16;
+    if !taken {
+        return 2;
+    }
+    c.registers.pc = c.registers.pc.wrapping_add(n as u16);
+    3
 }
 pub fn jrnzn(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(Z) {
-        let n = c.get_byte() as i8;
-        c.registers.pc = ((c.registers.pc as u32 as i32) + (n as i32)) as u16;
-        3
-    } else {
-        c.registers.pc += 1;
-        2
-    }
+    jr(c, !c.registers.getflag(Z))
 }
 pub fn jrzn(c: &mut Cpu) -> u32 {
-    if c.registers.getflag(Z) {
-        let n = c.get_byte() as i8;
-        c.registers.pc = ((c.registers.pc as u32 as i32) + (n as i32)) as u16;
-        3
-    } else {
-        c.registers.pc += 1;
-        2
-    }
+    jr(c, c.registers.getflag(Z))
 }
-
 pub fn jrncn(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(C) {
-        let n = c.get_byte() as i8;
-        c.registers.pc = ((c.registers.pc as u32 as i32) + (n as i32)) as u16;
-        3
-    } else {
-        c.registers.pc += 1;
-        2
-    }
+    jr(c, !c.registers.getflag(C))
 }
 pub fn jrcn(c: &mut Cpu) -> u32 {
-    if c.registers.getflag(C) {
-        let n = c.get_byte() as i8;
-        c.registers.pc = ((c.registers.pc as u32 as i32) + (n as i32)) as u16;
-        3
-    } else {
-        c.registers.pc += 1;
-        2
-    }
+    jr(c, c.registers.getflag(C))
 }
 
+fn call(c: &mut Cpu, taken: bool) -> u32 {
+    let addr = c.get_word();
+    if !taken {
+        return 3;
+    }
+    pushstack(c, c.registers.pc);
+    c.registers.pc = addr;
+    6
+}
 pub fn callnn(c: &mut Cpu) {
-    c.registers.sp -= 2;
-    c.memory.ww(c.registers.sp, c.registers.pc + 2);
-    c.registers.pc = c.get_word();
+    call(c, true);
 }
 pub fn callnznn(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(Z) {
-        c.registers.sp -= 2;
-        c.memory.ww(c.registers.sp, c.registers.pc + 2);
-        c.registers.pc = c.get_word();
-        6
-    } else {
-        c.registers.pc += 2;
-        3
-    }
+    call(c, !c.registers.getflag(Z))
 }
 pub fn callznn(c: &mut Cpu) -> u32 {
-    if c.registers.getflag(Z) {
-        c.registers.sp -= 2;
-        c.memory.ww(c.registers.sp, c.registers.pc + 2);
-        c.registers.pc = c.memory.rw(c.registers.pc);
-        6
-    } else {
-        c.registers.pc += 2;
-        3
-    }
+    call(c, c.registers.getflag(Z))
 }
 pub fn callncnn(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(C) {
-        pushstack(c, c.registers.pc + 2);
-        c.registers.pc = c.get_word();
-        6
-    } else {
-        c.registers.pc += 2;
-        3
-    }
+    call(c, !c.registers.getflag(C))
 }
 pub fn callcnn(c: &mut Cpu) -> u32 {
-    if c.registers.getflag(C) {
-        c.registers.sp -= 2;
-        c.memory.ww(c.registers.sp, c.registers.pc + 2);
-        c.registers.pc = c.get_word();
-        6
-    } else {
-        c.registers.pc += 2;
-        3
-    }
+    call(c, c.registers.getflag(C))
 }
 pub fn ret(c: &mut Cpu) {
-    let val = c.memory.rw(c.registers.sp);
-    c.registers.sp += 2;
-    c.registers.pc = val;
+    c.registers.pc = popstack(c);
 }
 pub fn reti(c: &mut Cpu) {
-    let val = c.memory.rw(c.registers.sp);
-    c.registers.sp += 2;
-    c.registers.pc = val;
+    c.registers.pc = popstack(c);
 }
-pub fn retnz(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(Z) {
-        c.registers.pc = popstack(c);
-        5
-    } else {
-        2
+// Checking the condition takes a cycle of its own
+fn retcc(c: &mut Cpu, taken: bool) -> u32 {
+    c.tick();
+    if !taken {
+        return 2;
     }
+    c.registers.pc = popstack(c);
+    5
+}
+pub fn retnz(c: &mut Cpu) -> u32 {
+    retcc(c, !c.registers.getflag(Z))
 }
 pub fn retz(c: &mut Cpu) -> u32 {
-    if c.registers.getflag(Z) {
-        c.registers.pc = c.memory.rw(c.registers.sp);
-        c.registers.sp += 2;
-        5
-    } else {
-        2
-    }
+    retcc(c, c.registers.getflag(Z))
 }
 pub fn retnc(c: &mut Cpu) -> u32 {
-    if !c.registers.getflag(C) {
-        let res = c.memory.rw(c.registers.sp);
-        c.registers.sp += 2;
-        c.registers.pc = res;
-        5
-    } else {
-        2
-    }
+    retcc(c, !c.registers.getflag(C))
 }
-pub fn retc(cpu: &mut Cpu) -> u32 {
-    if cpu.registers.getflag(C) {
-        cpu.registers.pc = popstack(cpu);
-        5
-    } else {
-        2
-    }
+pub fn retc(c: &mut Cpu) -> u32 {
+    retcc(c, c.registers.getflag(C))
 }
 pub fn rst(c: &mut Cpu, val: u16) {
-    c.registers.sp -= 2;
-    c.memory.ww(c.registers.sp, c.registers.pc);
+    pushstack(c, c.registers.pc);
     c.registers.pc = val;
 }
@@ -0,0 +1,273 @@
+// Execution trace in the format of gameboy-doctor
+// (https://github.com/robert/gameboy-doctor), one line per instruction:
+//
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+
+use crate::cpu::core::Cpu;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::panic;
+use std::sync::{Arc, Mutex, MutexGuard, Once, Weak};
+
+#[derive(Copy, Clone)]
+struct Entry {
+    // A, F, B, C, D, E, H, L
+    registers: [u8; 8],
+    sp: u16,
+    pc: u16,
+    pcmem: [u8; 4],
+}
+
+impl Entry {
+    fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let [a, f, b, c, d, e, h, l] = self.registers;
+        let [m0, m1, m2, m3] = self.pcmem;
+        writeln!(
+            out,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+             SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            a, f, b, c, d, e, h, l, self.sp, self.pc, m0, m1, m2, m3
+        )
+    }
+}
+
+struct Output {
+    out: Box<dyn Write + Send>,
+    ring: Option<(usize, VecDeque<Entry>)>,
+    error: Option<io::Error>,
+}
+
+impl Output {
+    fn write(&mut self, entry: &Entry) {
+        if let Err(e) = entry.write(&mut self.out) {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn dump(&mut self) {
+        if let Some((len, mut entries)) = self.ring.take() {
+            for entry in entries.drain(..) {
+                self.write(&entry);
+            }
+            self.ring = Some((len, entries));
+        }
+        if let Err(e) = self.out.flush() {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+// Outputs of the tracers handed to a `Gameboy`, for the panic hook
+static LIVE_OUTPUTS: Mutex<Vec<Weak<Mutex<Output>>>> = Mutex::new(Vec::new());
+static PANIC_HOOK: Once = Once::new();
+
+pub struct Tracer {
+    ranges: Vec<RangeInclusive<u16>>,
+    banks: Vec<usize>,
+    // Shared with the panic hook, which dumps even when panics abort
+    output: Arc<Mutex<Output>>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Tracer {
+        Tracer {
+            ranges: Vec::new(),
+            banks: Vec::new(),
+            output: Arc::new(Mutex::new(Output {
+                out: Box::new(out),
+                ring: None,
+                error: None,
+            })),
+        }
+    }
+
+    // Only traces instructions in one of the given ranges, can be repeated
+    pub fn pc_range(mut self, range: RangeInclusive<u16>) -> Tracer {
+        self.ranges.push(range);
+        self
+    }
+
+    // Only traces ROM code while one of the given banks is mapped, can be repeated.
+    // Code outside of ROM is not affected.
+    pub fn rom_bank(mut self, bank: usize) -> Tracer {
+        self.banks.push(bank);
+        self
+    }
+
+    // Keeps the last `len` instructions instead of writing them, see `dump`
+    pub fn ring_buffer(self, len: usize) -> Tracer {
+        self.output().ring = Some((len, VecDeque::with_capacity(len)));
+        self
+    }
+
+    // Writes out and empties the ring buffer. The debugger calls this when it stops
+    // on a breakpoint, and it happens on its own when the CPU locks up or the
+    // emulator panics.
+    pub fn dump(&mut self) {
+        self.output().dump();
+    }
+
+    // First write error, tracing carries on regardless
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.output().error.take()
+    }
+
+    fn output(&self) -> MutexGuard<'_, Output> {
+        self.output.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Dumps the ring buffer from the panic hook for as long as the tracer lives. The
+    // hook is installed once per process and chains to the one before it.
+    pub(crate) fn hook_panics(&self) {
+        let mut live = LIVE_OUTPUTS.lock().unwrap_or_else(|e| e.into_inner());
+        live.retain(|output| output.strong_count() > 0);
+        live.push(Arc::downgrade(&self.output));
+        drop(live);
+
+        PANIC_HOOK.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                // Skips whatever is locked, the panic may have come from inside
+                if let Ok(live) = LIVE_OUTPUTS.try_lock() {
+                    for output in live.iter().filter_map(Weak::upgrade) {
+                        if let Ok(mut output) = output.try_lock() {
+                            output.dump();
+                        }
+                    }
+                }
+                previous(info);
+            }));
+        });
+    }
+
+    pub(crate) fn trace(&mut self, cpu: &mut Cpu) {
+        let pc = cpu.registers.pc;
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|r| r.contains(&pc)) {
+            return;
+        }
+        if let Some(bank) = cpu.memory.rombank(pc) {
+            if !self.banks.is_empty() && !self.banks.contains(&bank) {
+                return;
+            }
+        }
+
+        let r = &cpu.registers;
+        let entry = Entry {
+            registers: [r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l],
+            sp: r.sp,
+            pc,
+            pcmem: [0, 1, 2, 3].map(|i| cpu.memory.read_byte(pc.wrapping_add(i))),
+        };
+        let mut output = self.output();
+        match &mut output.ring {
+            Some((len, entries)) => {
+                if entries.len() >= *len {
+                    entries.pop_front();
+                }
+                if *len > 0 {
+                    entries.push_back(entry);
+                }
+            }
+            None => output.write(&entry),
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.dump();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Tracer;
+    use crate::gameboy::{GameBoy, Gameboy};
+    use crate::header::test_rom;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn lines(&self) -> Vec<String> {
+            let data = self.0.lock().unwrap();
+            String::from_utf8_lossy(&data)
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    fn gameboy() -> Gameboy {
+        let program = [
+            0x21, 0x00, 0xC0, // LD HL,0xC000
+            0x34, // INC (HL)
+            0x18, 0xFD, // JR -3
+        ];
+        Gameboy::builder(test_rom(&program))
+            .model(GameBoy)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn doctor_format() {
+        let out = Shared::default();
+        let mut gb = gameboy();
+        gb.set_tracer(Tracer::new(out.clone()));
+        gb.frame();
+        let lines = out.lines();
+        assert_eq!(
+            lines[0],
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:21,00,C0,34"
+        );
+        assert!(lines[1].ends_with("H:C0 L:00 SP:FFFE PC:0103 PCMEM:34,18,FD,00"));
+
+        let out = Shared::default();
+        let mut gb = gameboy();
+        gb.set_tracer(Tracer::new(out.clone()).pc_range(0x103..=0x103).rom_bank(0));
+        gb.frame();
+        let lines = out.lines();
+        assert!(!lines.is_empty() && lines.iter().all(|l| l.contains("PC:0103")));
+
+        let out = Shared::default();
+        let mut gb = gameboy();
+        gb.set_tracer(Tracer::new(out.clone()).ring_buffer(3));
+        gb.frame();
+        assert!(out.lines().is_empty());
+        gb.take_tracer().unwrap().dump();
+        assert_eq!(out.lines().len(), 3);
+    }
+
+    #[test]
+    fn dumps_on_lockup_and_panic() {
+        let out = Shared::default();
+        let rom = test_rom(&[0xD3]); // illegal
+        let mut gb = Gameboy::builder(rom).model(GameBoy).build().unwrap();
+        gb.set_tracer(Tracer::new(out.clone()).ring_buffer(3));
+        gb.frame();
+        assert_eq!(out.lines().len(), 1);
+
+        let out = Shared::default();
+        let mut gb = gameboy();
+        gb.set_tracer(Tracer::new(out.clone()).ring_buffer(3));
+        gb.frame();
+        // The tracer is still alive, only the hook can dump it
+        let _ = std::panic::catch_unwind(|| panic!("crash"));
+        assert_eq!(out.lines().len(), 3);
+    }
+}
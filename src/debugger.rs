@@ -0,0 +1,525 @@
+// Breakpoints, watchpoints and stepping on top of `Cpu::do_cycle`.
+//
+// Breakpoints and conditions are checked before every instruction, except the one
+// a run stopped in front of so that resuming makes progress. Read and write
+// watchpoints are handed to the MMU for the duration of a run and trip on CPU
+// accesses only, DMA transfers do not count.
+
+use crate::cpu::core::{Cpu, CpuState};
+use crate::cpu::disasm;
+use crate::cpu::registers::Registers;
+use crate::gameboy::Gameboy;
+use std::ops::RangeInclusive;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+// A CPU access to VRAM or OAM while the PPU owns it, reported to the callback set
+// with `Gameboy::set_access_callback`
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct IllegalAccess {
+    // Address of the instruction making the access
+    pub pc: u16,
+    pub address: u16,
+    pub access: Access,
+    // The byte read or written
+    pub value: u8,
+    pub mode: u8,
+    pub ly: u8,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub fn read(self, gb: &Gameboy) -> u16 {
+        self.get(&gb.cpu().registers)
+    }
+
+    // 8-bit registers take the low byte, the low nibble of F always reads as zero
+    pub fn write(self, gb: &mut Gameboy, value: u16) {
+        let r = &mut gb.cpu_mut().registers;
+        let [lo, hi] = value.to_le_bytes();
+        match self {
+            Register::A => r.a = lo,
+            Register::F => r.f = lo & 0xF0,
+            Register::B => r.b = lo,
+            Register::C => r.c = lo,
+            Register::D => r.d = lo,
+            Register::E => r.e = lo,
+            Register::H => r.h = lo,
+            Register::L => r.l = lo,
+            Register::AF => (r.a, r.f) = (hi, lo & 0xF0),
+            Register::BC => (r.b, r.c) = (hi, lo),
+            Register::DE => (r.d, r.e) = (hi, lo),
+            Register::HL => (r.h, r.l) = (hi, lo),
+            Register::SP => r.sp = value,
+            Register::PC => r.pc = value,
+        }
+    }
+
+    fn get(self, r: &Registers) -> u16 {
+        let pair = |hi: u8, lo: u8| ((hi as u16) << 8) | lo as u16;
+        match self {
+            Register::A => r.a as u16,
+            Register::F => r.f as u16,
+            Register::B => r.b as u16,
+            Register::C => r.c as u16,
+            Register::D => r.d as u16,
+            Register::E => r.e as u16,
+            Register::H => r.h as u16,
+            Register::L => r.l as u16,
+            Register::AF => pair(r.a, r.f),
+            Register::BC => pair(r.b, r.c),
+            Register::DE => pair(r.d, r.e),
+            Register::HL => pair(r.h, r.l),
+            Register::SP => r.sp,
+            Register::PC => r.pc,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct Condition {
+    pub register: Register,
+    pub compare: Compare,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn new(register: Register, compare: Compare, value: u16) -> Condition {
+        Condition {
+            register,
+            compare,
+            value,
+        }
+    }
+
+    fn holds(&self, r: &Registers) -> bool {
+        let v = self.register.get(r);
+        match self.compare {
+            Compare::Equal => v == self.value,
+            Compare::NotEqual => v != self.value,
+            Compare::Less => v < self.value,
+            Compare::Greater => v > self.value,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct Breakpoint {
+    pub address: u16,
+    // ROM bank that has to be mapped at `address`, only used below 0x8000
+    pub bank: Option<usize>,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn at(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            bank: None,
+            condition: None,
+        }
+    }
+
+    pub fn in_bank(mut self, bank: usize) -> Breakpoint {
+        self.bank = Some(bank);
+        self
+    }
+
+    pub fn when(mut self, condition: Condition) -> Breakpoint {
+        self.condition = Some(condition);
+        self
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, access: Access) -> bool {
+        self.access == access && self.range.contains(&address)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum StopReason {
+    // A step finished without anything else stopping it
+    Step,
+    Breakpoint {
+        address: u16,
+        bank: usize,
+    },
+    // `value` is the byte read or written, or the opcode for execute watchpoints
+    Watchpoint {
+        address: u16,
+        access: Access,
+        value: u8,
+    },
+    // A standalone condition went from false to true
+    Condition(Condition),
+    FrameDone,
+    CycleLimit,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // With whether they held before the last instruction
+    conditions: Vec<(Condition, bool)>,
+    // Where the last run stopped before an instruction. It runs without checks when
+    // resuming, unless the PC has moved since.
+    stopped_at: Option<u16>,
+}
+
+fn is_call(op: u8) -> bool {
+    matches!(op, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || op & 0xC7 == 0xC7
+}
+
+fn is_return(op: u8) -> bool {
+    matches!(op, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9)
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    // Removes every breakpoint at `address` in `bank`
+    pub fn remove_breakpoint(&mut self, address: u16, bank: Option<usize>) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints
+            .retain(|b| b.address != address || b.bank != bank);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, access: Access) {
+        let watchpoint = Watchpoint { range, access };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(
+        &mut self,
+        range: RangeInclusive<u16>,
+        access: Access,
+    ) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|w| w.range != range || w.access != access);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_condition(&mut self, condition: Condition) {
+        self.conditions.push((condition, false));
+    }
+
+    pub fn remove_condition(&mut self, condition: Condition) -> bool {
+        let len = self.conditions.len();
+        self.conditions.retain(|(c, _)| *c != condition);
+        self.conditions.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.conditions.clear();
+    }
+
+    // Executes a single instruction, entering calls and interrupt handlers
+    pub fn step_into(&mut self, gb: &mut Gameboy) -> StopReason {
+        self.run(gb, None, |_, _, _| true)
+    }
+
+    // Like `step_into`, but runs a call or RST until it returns
+    pub fn step_over(&mut self, gb: &mut Gameboy) -> StopReason {
+        let cpu = gb.cpu_mut();
+        let (pc, sp) = (cpu.registers.pc, cpu.registers.sp);
+        let instruction = disasm::disassemble(&mut cpu.memory, pc);
+        if !is_call(instruction.bytes[0]) {
+            return self.step_into(gb);
+        }
+        let next = pc.wrapping_add(instruction.length as u16);
+        self.run(gb, None, |cpu, _, _| {
+            cpu.registers.pc == next && cpu.registers.sp >= sp
+        })
+    }
+
+    // Runs until the current function returns to its caller
+    pub fn step_out(&mut self, gb: &mut Gameboy) -> StopReason {
+        let sp = gb.cpu().registers.sp;
+        self.run(gb, None, |cpu, op, old_sp| {
+            is_return(op)
+                && cpu.registers.sp == old_sp.wrapping_add(2)
+                && cpu.registers.sp > sp
+        })
+    }
+
+    // Runs for at most `cycles` clock cycles, or until something stops it
+    pub fn run_until(&mut self, gb: &mut Gameboy, cycles: u32) -> StopReason {
+        match self.run(gb, Some(cycles), |_, _, _| false) {
+            StopReason::Step => StopReason::CycleLimit,
+            reason => reason,
+        }
+    }
+
+    // Runs to the end of the current frame, or until something stops it
+    pub fn run_frame(&mut self, gb: &mut Gameboy) -> StopReason {
+        match self.run(gb, None, |cpu, _, _| cpu.memory.gpu.updated) {
+            StopReason::Step => {
+                gb.check_and_reset_gpu_updated();
+                StopReason::FrameDone
+            }
+            reason => reason,
+        }
+    }
+
+    // Executes instructions until `done` returns true after one of them, which then
+    // stops with `StopReason::Step`. `done` gets the opcode and stack pointer from
+    // before the instruction.
+    fn run<F>(&mut self, gb: &mut Gameboy, cycles: Option<u32>, mut done: F) -> StopReason
+    where
+        F: FnMut(&Cpu, u8, u16) -> bool,
+    {
+        let cpu = gb.cpu_mut();
+        cpu.memory.watchpoints = std::mem::take(&mut self.watchpoints);
+        // Registers may have been written since the last run
+        for (condition, held) in &mut self.conditions {
+            *held = condition.holds(&cpu.registers);
+        }
+
+        let mut ticks = 0;
+        let reason = loop {
+            let resuming = self.stopped_at.take() == Some(cpu.registers.pc);
+            if !resuming && cpu.state == CpuState::Running {
+                if let Some(reason) = self.check(cpu) {
+                    self.stopped_at = Some(cpu.registers.pc);
+                    break reason;
+                }
+            }
+            if cycles.is_some_and(|c| ticks >= c) {
+                break StopReason::Step;
+            }
+
+            let op = cpu.memory.read_byte(cpu.registers.pc);
+            let sp = cpu.registers.sp;
+            ticks += cpu.do_cycle();
+
+            if let Some(reason) = cpu.memory.watch_hit.take() {
+                break reason;
+            }
+            if done(cpu, op, sp) {
+                break StopReason::Step;
+            }
+        };
+
+        self.watchpoints = std::mem::take(&mut cpu.memory.watchpoints);
+        if !matches!(reason, StopReason::Step) {
+            if let Some(tracer) = &mut cpu.tracer {
+                tracer.dump();
+            }
+        }
+        reason
+    }
+
+    fn check(&mut self, cpu: &mut Cpu) -> Option<StopReason> {
+        let pc = cpu.registers.pc;
+        let bank = cpu.memory.rombank(pc).unwrap_or(0);
+        for b in &self.breakpoints {
+            if b.address == pc
+                && b.bank.is_none_or(|n| n == bank)
+                && b.condition.is_none_or(|c| c.holds(&cpu.registers))
+            {
+                return Some(StopReason::Breakpoint { address: pc, bank });
+            }
+        }
+
+        if cpu
+            .memory
+            .watchpoints
+            .iter()
+            .any(|w| w.matches(pc, Access::Execute))
+        {
+            return Some(StopReason::Watchpoint {
+                address: pc,
+                access: Access::Execute,
+                value: cpu.memory.read_byte(pc),
+            });
+        }
+
+        let mut reason = None;
+        for (condition, held) in &mut self.conditions {
+            let holds = condition.holds(&cpu.registers);
+            if holds && !*held && reason.is_none() {
+                reason = Some(StopReason::Condition(*condition));
+            }
+            *held = holds;
+        }
+        reason
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Access, Breakpoint, Compare, Condition, Debugger, Register, StopReason};
+    use crate::gameboy::Gameboy;
+    use crate::header::test_rom;
+
+    fn call_rom() -> Gameboy {
+        let main = [
+            0xCD, 0x00, 0x02, // CALL 0x0200
+            0xEA, 0x00, 0xC0, // LD (0xC000),A
+            0x18, 0xFE, // JR -2
+        ];
+        let function = [
+            0x3E, 0x42, // LD A,0x42
+            0x04, // INC B
+            0xC9, // RET
+        ];
+        let mut rom = test_rom(&main);
+        rom[0x200..0x200 + function.len()].copy_from_slice(&function);
+        Gameboy::new(rom, None)
+    }
+
+    #[test]
+    fn steps() {
+        let mut dbg = Debugger::new();
+        let mut gb = call_rom();
+        assert_eq!(dbg.step_into(&mut gb), StopReason::Step);
+        assert_eq!(Register::PC.read(&gb), 0x200);
+        assert_eq!(dbg.step_out(&mut gb), StopReason::Step);
+        assert_eq!(Register::PC.read(&gb), 0x103);
+
+        let mut gb = call_rom();
+        assert_eq!(dbg.step_over(&mut gb), StopReason::Step);
+        assert_eq!(Register::PC.read(&gb), 0x103);
+        assert_eq!(Register::A.read(&gb), 0x42);
+        assert_eq!(dbg.step_over(&mut gb), StopReason::Step);
+        assert_eq!(Register::PC.read(&gb), 0x106);
+    }
+
+    #[test]
+    fn stops() {
+        let mut dbg = Debugger::new();
+        let mut gb = call_rom();
+        dbg.add_breakpoint(Breakpoint::at(0x202).in_bank(1));
+        assert_eq!(dbg.run_until(&mut gb, 10_000), StopReason::CycleLimit);
+
+        let mut gb = call_rom();
+        dbg.add_breakpoint(Breakpoint::at(0x202));
+        let hit = StopReason::Breakpoint {
+            address: 0x202,
+            bank: 0,
+        };
+        assert_eq!(dbg.run_until(&mut gb, 10_000), hit);
+        assert!(dbg.remove_breakpoint(0x202, None));
+
+        dbg.add_condition(Condition::new(Register::B, Compare::Equal, 1));
+        assert_eq!(
+            dbg.run_until(&mut gb, 10_000),
+            StopReason::Condition(Condition::new(Register::B, Compare::Equal, 1))
+        );
+        assert_eq!(Register::PC.read(&gb), 0x203);
+
+        dbg.add_watchpoint(0xC000..=0xC0FF, Access::Write);
+        let hit = StopReason::Watchpoint {
+            address: 0xC000,
+            access: Access::Write,
+            value: 0x42,
+        };
+        assert_eq!(dbg.run_until(&mut gb, 10_000), hit);
+        assert_eq!(Register::PC.read(&gb), 0x106);
+
+        dbg.add_watchpoint(0x106..=0x106, Access::Execute);
+        assert!(matches!(
+            dbg.run_frame(&mut gb),
+            StopReason::Watchpoint {
+                access: Access::Execute,
+                ..
+            }
+        ));
+        dbg.clear();
+        assert_eq!(dbg.run_frame(&mut gb), StopReason::FrameDone);
+    }
+
+    #[test]
+    fn checks_a_moved_pc() {
+        let mut dbg = Debugger::new();
+        let mut gb = call_rom();
+        dbg.add_breakpoint(Breakpoint::at(0x202));
+        let hit = |address| StopReason::Breakpoint { address, bank: 0 };
+        assert_eq!(dbg.run_until(&mut gb, 10_000), hit(0x202));
+
+        // Resuming somewhere else does not skip the breakpoint there
+        dbg.add_breakpoint(Breakpoint::at(0x100));
+        Register::PC.write(&mut gb, 0x100);
+        assert_eq!(dbg.run_until(&mut gb, 10_000), hit(0x100));
+        assert_eq!(dbg.run_until(&mut gb, 10_000), hit(0x202));
+    }
+
+    #[test]
+    fn conditions_fire_on_change() {
+        let mut dbg = Debugger::new();
+        let mut gb = call_rom();
+        assert_eq!(dbg.step_into(&mut gb), StopReason::Step);
+        // Already true when the run starts, INC B then makes it false
+        dbg.add_condition(Condition::new(Register::B, Compare::Equal, 1));
+        Register::B.write(&mut gb, 1);
+        assert_eq!(dbg.run_until(&mut gb, 10_000), StopReason::CycleLimit);
+
+        let mut gb = call_rom();
+        assert_eq!(dbg.step_into(&mut gb), StopReason::Step);
+        Register::B.write(&mut gb, 0);
+        assert_eq!(
+            dbg.run_until(&mut gb, 10_000),
+            StopReason::Condition(Condition::new(Register::B, Compare::Equal, 1))
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use crate::gameboy::CYCLES;
+use crate::gpu::{HEIGHT, WIDTH};
+use std::io::{self, Seek, SeekFrom, Write};
+
+const CLOCK_RATE: u32 = 4194304;
+const FRAME_SIZE: u32 = (WIDTH * HEIGHT * 3) as u32;
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+// Writes uncompressed 24-bit video at the hardware refresh rate and 16-bit stereo
+// PCM sound. The RIFF sizes and lengths are patched in `finish`, so the writer has
+// to be seekable.
+pub struct AviWriter<W: Write + Seek> {
+    writer: W,
+    // Offsets of the fields patched in `finish`
+    total_frames_at: u64,
+    video_length_at: u64,
+    audio_length_at: u64,
+    movi_at: u64,
+    index: Vec<([u8; 4], u32, u32)>,
+    movi_len: u32,
+    frames: u32,
+    audio_frames: u32,
+}
+
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+fn list(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(b"LIST");
+    out.extend_from_slice(&(data.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<AviWriter<W>> {
+        let (width, height) = (WIDTH as u32, HEIGHT as u32);
+        let block_align = 4;
+
+        let avih = u32s(&[
+            (CYCLES as u64 * 1_000_000 / CLOCK_RATE as u64) as u32,
+            FRAME_SIZE * 60 + sample_rate * block_align,
+            0,
+            AVIF_HASINDEX,
+            0, // total frames
+            0,
+            2,
+            FRAME_SIZE,
+            width,
+            height,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        let mut video = Vec::new();
+        let mut strh = b"vidsDIB ".to_vec();
+        strh.extend(u32s(&[0, 0, 0, CYCLES, CLOCK_RATE, 0, 0, FRAME_SIZE]));
+        strh.extend(u32s(&[u32::MAX, 0, 0, width | height << 16]));
+        chunk(&mut video, b"strh", &strh);
+        // Bottom-up BGR rows
+        let mut strf = u32s(&[40, width, height, 1 | 24 << 16, 0, FRAME_SIZE]);
+        strf.extend(u32s(&[0, 0, 0, 0]));
+        chunk(&mut video, b"strf", &strf);
+
+        let mut audio = Vec::new();
+        let mut strh = b"auds".to_vec();
+        strh.extend(u32s(&[0, 0, 0, 0, 1, sample_rate, 0, 0, sample_rate]));
+        strh.extend(u32s(&[u32::MAX, block_align, 0, 0]));
+        chunk(&mut audio, b"strh", &strh);
+        let mut strf = u32s(&[1 | 2 << 16, sample_rate, sample_rate * block_align]);
+        strf.extend(u32s(&[block_align | 16 << 16]));
+        chunk(&mut audio, b"strf", &strf);
+
+        let mut streams = Vec::new();
+        chunk(&mut streams, b"avih", &avih);
+        list(&mut streams, b"strl", &video);
+        list(&mut streams, b"strl", &audio);
+
+        let mut header = b"RIFF\0\0\0\0AVI ".to_vec();
+        list(&mut header, b"hdrl", &streams);
+        // hdrl list header, avih chunk header, then the fields before the total
+        let total_frames_at = 12 + 12 + 8 + 16;
+        // Then the avih fields, the strl list header and the strh fields before the
+        // length
+        let video_length_at = total_frames_at - 16 + 56 + 12 + 8 + 32;
+        let audio_length_at = video_length_at - 32 + 56 + 8 + 40 + 12 + 8 + 32;
+        let movi_at = header.len() as u64;
+        header.extend_from_slice(b"LIST\0\0\0\0movi");
+        writer.write_all(&header)?;
+
+        Ok(AviWriter {
+            writer,
+            total_frames_at,
+            video_length_at,
+            audio_length_at,
+            movi_at,
+            index: Vec::new(),
+            movi_len: 4,
+            frames: 0,
+            audio_frames: 0,
+        })
+    }
+
+    fn write_chunk(&mut self, id: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        self.index.push((*id, self.movi_len, data.len() as u32));
+        self.writer.write_all(id)?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)?;
+        self.movi_len += 8 + data.len() as u32;
+        if data.len() % 2 == 1 {
+            self.writer.write_all(&[0])?;
+            self.movi_len += 1;
+        }
+        Ok(())
+    }
+
+    // Takes a screen of RGBA pixels, like `Gameboy::image`
+    pub fn write_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        let mut bgr = Vec::with_capacity(FRAME_SIZE as usize);
+        for row in rgba.chunks_exact(WIDTH * 4).rev() {
+            for pixel in row.chunks_exact(4) {
+                bgr.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+        }
+        self.write_chunk(b"00db", &bgr)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    // Takes interleaved stereo samples
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            let v = (sample.clamp(-1.0, 1.0) * 32767.0) as i16;
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        self.write_chunk(b"01wb", &bytes)?;
+        self.audio_frames += samples.len() as u32 / 2;
+        Ok(())
+    }
+
+    // Size of the file once finished
+    pub fn len(&self) -> u64 {
+        self.movi_at + 8 + self.movi_len as u64 + 8 + self.index.len() as u64 * 16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0 && self.audio_frames == 0
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut idx1 = Vec::with_capacity(self.index.len() * 16);
+        for (id, offset, size) in &self.index {
+            idx1.extend_from_slice(id);
+            idx1.extend(u32s(&[AVIIF_KEYFRAME, *offset, *size]));
+        }
+        let mut trailer = Vec::new();
+        chunk(&mut trailer, b"idx1", &idx1);
+        self.writer.write_all(&trailer)?;
+
+        let len = self.len() as u32;
+        let patches = [
+            (4, len - 8),
+            (self.total_frames_at, self.frames),
+            (self.video_length_at, self.frames),
+            (self.audio_length_at, self.audio_frames),
+            (self.movi_at + 4, self.movi_len),
+        ];
+        for (at, value) in patches {
+            self.writer.seek(SeekFrom::Start(at))?;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AviWriter;
+    use crate::gpu::{HEIGHT, WIDTH};
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn layout() {
+        let mut avi = AviWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        let mut image = vec![0; WIDTH * HEIGHT * 4];
+        image[..4].copy_from_slice(&[1, 2, 3, 255]);
+        for _ in 0..2 {
+            avi.write_frame(&image).unwrap();
+            avi.write_samples(&[0.0, 1.0, -1.0, 0.5]).unwrap();
+        }
+        let len = avi.len();
+        let bytes = avi.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len() as u64, len);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..16], b"AVI LIST");
+        assert_eq!(&bytes[24..28], b"avih");
+        // Total frames, then the video and audio stream lengths
+        assert_eq!(u32_at(&bytes, 48), 2);
+        assert_eq!(&bytes[88..92], b"LIST");
+        assert_eq!(&bytes[100..104], b"strh");
+        assert_eq!(&bytes[108..112], b"vids");
+        assert_eq!(u32_at(&bytes, 140), 2);
+        assert_eq!(&bytes[212..216], b"LIST");
+        assert_eq!(&bytes[224..228], b"strh");
+        assert_eq!(&bytes[232..236], b"auds");
+        assert_eq!(u32_at(&bytes, 264), 4);
+        assert_eq!(&bytes[320..324], b"movi");
+
+        let movi = 320;
+        assert_eq!(&bytes[movi + 4..movi + 8], b"00db");
+        // The bottom row comes first, the first pixel ends up in the last row
+        let first = movi + 12 + (HEIGHT - 1) * WIDTH * 3;
+        assert_eq!(&bytes[first..first + 3], &[3, 2, 1]);
+
+        let idx1 = bytes.len() - 8 - 4 * 16;
+        assert_eq!(&bytes[idx1..idx1 + 4], b"idx1");
+        assert_eq!(&bytes[idx1 + 8 + 16..idx1 + 8 + 20], b"01wb");
+        assert_eq!(u32_at(&bytes, idx1 + 8 + 16 + 12), 8);
+    }
+}
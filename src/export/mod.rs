@@ -0,0 +1,382 @@
+pub mod avi;
+pub mod wav;
+
+use crate::export::avi::AviWriter;
+use crate::export::wav::WavWriter;
+use crate::gameboy::{AudioChannel, Gameboy};
+use crate::input::KeypadKey;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InputEvent {
+    pub frame: u32,
+    pub key: KeypadKey,
+    pub pressed: bool,
+}
+
+// Key presses and releases scheduled on frame numbers, for headless runs.
+//
+// The text format has one event per line, `<frame> <key> <down|up>`, for example
+// `120 start down`. Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct InputScript {
+    events: Vec<InputEvent>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut script = InputScript::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let error =
+                || format!("Invalid input event at line {}: {}", number + 1, line);
+            if fields.len() != 3 {
+                return Err(error());
+            }
+            let frame = fields[0].parse().map_err(|_| error())?;
+            let key = KeypadKey::from_name(fields[1]).ok_or_else(error)?;
+            let pressed = match fields[2] {
+                "down" => true,
+                "up" => false,
+                _ => return Err(error()),
+            };
+            script.push(frame, key, pressed);
+        }
+        Ok(script)
+    }
+
+    pub fn push(&mut self, frame: u32, key: KeypadKey, pressed: bool) {
+        self.events.push(InputEvent {
+            frame,
+            key,
+            pressed,
+        });
+        self.events.sort_by_key(|e| e.frame);
+    }
+
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    // Applies the events scheduled for `frame`, before it runs
+    pub fn apply(&self, frame: u32, gameboy: &mut Gameboy) {
+        for event in self.events.iter().filter(|e| e.frame == frame) {
+            if event.pressed {
+                gameboy.keydown(event.key);
+            } else {
+                gameboy.keyup(event.key);
+            }
+        }
+    }
+}
+
+// `song.wav` gets its stems written as `song.square1.wav`, `song.wave.wav` and so on
+pub fn stem_path(path: &Path, channel: AudioChannel) -> PathBuf {
+    path.with_extension(format!("{}.wav", channel.name()))
+}
+
+// Runs `frames` frames with scripted input and writes the sound output to `path`.
+// With `stems`, every channel is also written to its own file next to it.
+pub fn export_wav(
+    gameboy: &mut Gameboy,
+    script: &InputScript,
+    frames: u32,
+    path: &Path,
+    stems: bool,
+) -> io::Result<()> {
+    if !stems {
+        return write_wav(gameboy, script, frames, path, false);
+    }
+    // Stems are turned off again whether or not the export worked
+    gameboy.set_audio_stems(true);
+    let result = write_wav(gameboy, script, frames, path, true);
+    gameboy.set_audio_stems(false);
+    result
+}
+
+fn write_wav(
+    gameboy: &mut Gameboy,
+    script: &InputScript,
+    frames: u32,
+    path: &Path,
+    stems: bool,
+) -> io::Result<()> {
+    let rate = gameboy.audio_sample_rate();
+    let create = |path: &Path| WavWriter::new(BufWriter::new(File::create(path)?), rate);
+
+    let mut mix = create(path)?;
+    let mut stem_writers = Vec::new();
+    if stems {
+        for channel in AudioChannel::ALL {
+            stem_writers.push((channel, create(&stem_path(path, channel))?));
+        }
+    }
+
+    // Whatever was generated before the export started is not part of it
+    let mut samples = Vec::new();
+    gameboy.drain_audio_f32(&mut samples);
+
+    for frame in 0..frames {
+        script.apply(frame, gameboy);
+        gameboy.frame();
+
+        samples.clear();
+        gameboy.drain_audio_f32(&mut samples);
+        mix.write_samples(&samples)?;
+        for (channel, writer) in stem_writers.iter_mut() {
+            samples.clear();
+            gameboy.drain_audio_stem_f32(*channel, &mut samples);
+            writer.write_samples(&samples)?;
+        }
+    }
+
+    mix.finish()?;
+    for (_, writer) in stem_writers {
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+// AVI 1.0 files have 32-bit sizes and some readers choke past 2 GB, longer
+// recordings go on in `name.1.avi`, `name.2.avi` and so on
+const MAX_AVI_SIZE: u64 = 1 << 31;
+
+// Writes the frames and sound of a `Gameboy` to AVI files, see
+// `Gameboy::start_recording`
+pub struct Recorder {
+    path: PathBuf,
+    part: u32,
+    sample_rate: u32,
+    avi: Option<AviWriter<BufWriter<File>>>,
+    error: Option<io::Error>,
+}
+
+impl Recorder {
+    pub fn new(path: &Path, sample_rate: u32) -> io::Result<Recorder> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            part: 0,
+            sample_rate,
+            avi: Some(AviWriter::new(file, sample_rate)?),
+            error: None,
+        })
+    }
+
+    fn part_path(&self) -> PathBuf {
+        match self.part {
+            0 => self.path.clone(),
+            n => self.path.with_extension(format!("{}.avi", n)),
+        }
+    }
+
+    fn write(&mut self, image: &[u8], samples: &[f32]) -> io::Result<()> {
+        let next = image.len() as u64 + samples.len() as u64 * 2 + 64;
+        if self
+            .avi
+            .as_ref()
+            .is_some_and(|a| a.len() + next > MAX_AVI_SIZE)
+        {
+            if let Some(avi) = self.avi.take() {
+                avi.finish()?;
+            }
+            self.part += 1;
+            let file = BufWriter::new(File::create(self.part_path())?);
+            self.avi = Some(AviWriter::new(file, self.sample_rate)?);
+        }
+        if let Some(avi) = &mut self.avi {
+            avi.write_frame(image)?;
+            avi.write_samples(samples)?;
+        }
+        Ok(())
+    }
+
+    // Stops writing on the first error, `finish` returns it
+    pub fn record(&mut self, image: &[u8], samples: &[f32]) {
+        if self.error.is_none() {
+            if let Err(e) = self.write(image, samples) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        match self.avi.take() {
+            Some(avi) => avi.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(avi) = self.avi.take() {
+            let _ = avi.finish();
+        }
+    }
+}
+
+// Runs `frames` frames with scripted input and records them with their sound to
+// `path`
+pub fn export_avi(
+    gameboy: &mut Gameboy,
+    script: &InputScript,
+    frames: u32,
+    path: &Path,
+) -> io::Result<()> {
+    gameboy.start_recording(path)?;
+    for frame in 0..frames {
+        script.apply(frame, gameboy);
+        gameboy.frame();
+    }
+    gameboy.stop_recording()
+}
+
+// Starts recording to a new file named after the current time in the working
+// directory, or stops the recording in progress. Returns the file started.
+pub fn toggle_recording(gameboy: &mut Gameboy) -> io::Result<Option<PathBuf>> {
+    if gameboy.is_recording() {
+        gameboy.stop_recording()?;
+        return Ok(None);
+    }
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = PathBuf::from(format!("gameboy-{}.avi", secs));
+    gameboy.start_recording(&path)?;
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export_avi, export_wav, stem_path, InputScript};
+    use crate::gameboy::{AudioChannel, Gameboy};
+    use crate::header::test_rom;
+    use crate::input::KeypadKey;
+    use std::path::Path;
+
+    // Turns the APU on and plays a note on the second square channel forever
+    fn tone_rom() -> Vec<u8> {
+        let program = [
+            0x3E, 0x80, 0xE0, 0x26, // NR52: power on
+            0x3E, 0x77, 0xE0, 0x24, // NR50: full volume
+            0x3E, 0xFF, 0xE0, 0x25, // NR51: every channel on both sides
+            0x3E, 0xF0, 0xE0, 0x17, // NR22: volume 15, no envelope
+            0x3E, 0x80, 0xE0, 0x18, // NR23
+            0x3E, 0x87, 0xE0, 0x19, // NR24: trigger
+            0x18, 0xFE, // JR -2
+        ];
+        test_rom(&program)
+    }
+
+    #[test]
+    fn parse_script() {
+        let script =
+            InputScript::parse("# intro\n\n120 start down\n 121 Start up\n10 a down")
+                .unwrap();
+        let events = script.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].frame, 10);
+        assert_eq!(events[0].key, KeypadKey::A);
+        assert_eq!(events[1].key, KeypadKey::Start);
+        assert!(events[1].pressed);
+        assert!(!events[2].pressed);
+
+        assert!(InputScript::parse("12 start").is_err());
+        assert!(InputScript::parse("12 turbo down").is_err());
+        assert!(InputScript::parse("x start down").is_err());
+    }
+
+    #[test]
+    fn stem_paths() {
+        assert_eq!(
+            stem_path(Path::new("out/song.wav"), AudioChannel::Square2),
+            Path::new("out/song.square2.wav")
+        );
+    }
+
+    #[test]
+    fn export_with_stems() {
+        let dir =
+            std::env::temp_dir().join(format!("gameboy-wav-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tone.wav");
+
+        let mut gameboy = Gameboy::new(tone_rom(), None);
+        gameboy.set_audio_sample_rate(22050);
+        export_wav(&mut gameboy, &InputScript::default(), 30, &path, true).unwrap();
+
+        let peak = |path: &Path| {
+            let bytes = std::fs::read(path).unwrap();
+            assert!(bytes.len() > 44 + 30 * 300 * 4);
+            bytes[44..]
+                .chunks(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]).unsigned_abs())
+                .max()
+                .unwrap()
+        };
+        assert!(peak(&path) > 1000);
+        assert!(peak(&stem_path(&path, AudioChannel::Square2)) > 1000);
+        assert!(peak(&stem_path(&path, AudioChannel::Noise)) < 100);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_export_turns_stems_off() {
+        let dir =
+            std::env::temp_dir().join(format!("gameboy-stems-{}", std::process::id()));
+        let path = dir.join("tone.wav");
+        // A directory where a stem should go makes creating it fail
+        std::fs::create_dir_all(stem_path(&path, AudioChannel::Wave)).unwrap();
+
+        let mut gameboy = Gameboy::new(tone_rom(), None);
+        assert!(
+            export_wav(&mut gameboy, &InputScript::default(), 1, &path, true).is_err()
+        );
+        gameboy.frame();
+        let mut samples = Vec::new();
+        assert_eq!(
+            gameboy.drain_audio_stem_f32(AudioChannel::Square2, &mut samples),
+            0
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_avi() {
+        let dir =
+            std::env::temp_dir().join(format!("gameboy-avi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tone.avi");
+
+        let mut gameboy = Gameboy::new(tone_rom(), None);
+        gameboy.set_audio_sample_rate(22050);
+        export_avi(&mut gameboy, &InputScript::default(), 10, &path).unwrap();
+        assert!(!gameboy.is_recording());
+
+        let bytes = std::fs::read(&path).unwrap();
+        let u32_at =
+            |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        // Total frames in the main header, then about 1/60 s of sound per frame
+        assert_eq!(u32_at(48), 10);
+        let audio_frames = u32_at(264);
+        assert!((3500..3800).contains(&audio_frames), "{}", audio_frames);
+        assert!(bytes.len() > 10 * 160 * 144 * 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,39 @@
-use crate::cpu::core::Cpu;
+use crate::cpu::core::{Cpu, CpuState};
+use crate::cpu::disasm::{self, Instruction};
+use crate::cpu::trace::Tracer;
+use crate::debugger::IllegalAccess;
+use crate::export::Recorder;
+use crate::header::CartridgeHeader;
 use crate::input::KeypadKey;
+use crate::movie::{Movie, MovieStart, Session};
+use crate::rewind::{self, Rewind};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::screenshot::{self, Comparison};
+use crate::state::{self, StateReader, StateWriter};
+use std::fmt;
+use std::io;
+use std::path::Path;
 
 pub struct Gameboy {
     cpu: Cpu<'static>,
+    rom_hash: u64,
+    model: Target,
+    rewind: Option<Rewind>,
+    rewinding: bool,
+    recorder: Option<Recorder>,
+    seed: u32,
+    movie: Option<Session>,
+    // Nothing has run or changed since power on, a movie can start from there
+    fresh: bool,
+    // Clock cycles into the frame being run
+    frame_ticks: u32,
     pub width: u32,
     pub height: u32,
 }
 
-pub use self::Target::{GameBoy, GameBoyColor, SuperGameBoy};
+pub use self::Target::{
+    GameBoy, GameBoyAdvance, GameBoyColor, GameBoyPocket, SuperGameBoy,
+};
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
 pub enum RenderMode {
@@ -21,11 +47,192 @@ pub enum RenderMode {
     WebAssembly,
 }
 
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Error {
+    // Cartridge type (0x147) of a memory bank controller that is not emulated
+    UnsupportedMbc(u8),
+    TruncatedRom { expected: usize, actual: usize },
+    BadBootRom { expected: usize, actual: usize },
+    BadHeader(&'static str),
+    SaveIo(String),
+    // HDMA/GDMA started from a source outside of ROM and RAM
+    IllegalDma(u16),
+    InvalidState(&'static str),
+    BadImage(String),
+    BadMovie(&'static str),
+    // Refused while a movie is recording or playing, it would not replay
+    MovieActive,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnsupportedMbc(kind) => write!(f, "Unsupported MBC type {:02X}", kind),
+            Error::TruncatedRom { expected, actual } => write!(
+                f,
+                "ROM is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            Error::BadBootRom { expected, actual } => write!(
+                f,
+                "Boot ROM has the wrong size: expected {} bytes, got {}",
+                expected, actual
+            ),
+            Error::BadHeader(reason) => write!(f, "Bad cartridge header: {}", reason),
+            Error::SaveIo(reason) => write!(f, "Save file error: {}", reason),
+            Error::IllegalDma(src) => {
+                write!(f, "DMA transfer with illegal source {:04X}", src)
+            }
+            Error::InvalidState(reason) => write!(f, "Invalid save state: {}", reason),
+            Error::BadImage(reason) => write!(f, "Bad image: {}", reason),
+            Error::BadMovie(reason) => write!(f, "Bad movie: {}", reason),
+            Error::MovieActive => write!(f, "A movie is being recorded or played back"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum AudioChannel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl AudioChannel {
+    pub const ALL: [AudioChannel; 4] = [
+        AudioChannel::Square1,
+        AudioChannel::Square2,
+        AudioChannel::Wave,
+        AudioChannel::Noise,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioChannel::Square1 => "square1",
+            AudioChannel::Square2 => "square2",
+            AudioChannel::Wave => "wave",
+            AudioChannel::Noise => "noise",
+        }
+    }
+}
+
+// Hardware model to emulate. Models differ in the state the boot ROM leaves behind,
+// and only the Color and Advance run Color games.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
 pub enum Target {
+    // DMG
     GameBoy,
-    GameBoyColor,
+    // MGB
+    GameBoyPocket,
+    // SGB
     SuperGameBoy,
+    // CGB
+    #[default]
+    GameBoyColor,
+    // AGB
+    GameBoyAdvance,
+}
+
+impl Target {
+    pub fn is_color(&self) -> bool {
+        matches!(self, GameBoyColor | GameBoyAdvance)
+    }
+
+    // Takes the short model names: dmg, mgb, sgb, cgb and agb
+    pub fn from_name(name: &str) -> Option<Target> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg" => Some(GameBoy),
+            "mgb" => Some(GameBoyPocket),
+            "sgb" => Some(SuperGameBoy),
+            "cgb" => Some(GameBoyColor),
+            "agb" => Some(GameBoyAdvance),
+            _ => None,
+        }
+    }
+}
+
+pub struct GameboyBuilder {
+    data: Vec<u8>,
+    filepath: Option<std::path::PathBuf>,
+    model: Target,
+    boot_rom: Option<Vec<u8>>,
+    seed: u32,
+    movie: Option<Movie>,
+}
+
+// Seed of the random values WRAM powers on with
+pub const DEFAULT_SEED: u32 = 42;
+
+impl GameboyBuilder {
+    pub fn model(mut self, model: Target) -> GameboyBuilder {
+        self.model = model;
+        self
+    }
+
+    // Where battery backed RAM is saved, nothing is saved without one
+    pub fn save_path(mut self, filepath: std::path::PathBuf) -> GameboyBuilder {
+        self.filepath = Some(filepath);
+        self
+    }
+
+    // Starts from the boot ROM instead of the state it leaves behind. It is 256 bytes
+    // for the monochrome models and 2304 bytes for the Color and Advance.
+    pub fn boot_rom(mut self, boot_rom: Vec<u8>) -> GameboyBuilder {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+
+    pub fn seed(mut self, seed: u32) -> GameboyBuilder {
+        self.seed = seed;
+        self
+    }
+
+    // Plays the movie back from the machine it was recorded on, which takes over the
+    // model and seed. A boot ROM or save file has to be the same as when recording.
+    pub fn movie(mut self, movie: Movie) -> GameboyBuilder {
+        self.model = movie.model;
+        self.seed = movie.seed;
+        self.movie = Some(movie);
+        self
+    }
+
+    pub fn build(self) -> Result<Gameboy, Error> {
+        let rom_hash = state::rom_hash(&self.data);
+        let cpu = Cpu::new(
+            self.data,
+            self.filepath,
+            self.model,
+            self.boot_rom,
+            self.seed,
+        )?;
+        let mut gb = Gameboy {
+            cpu,
+            rom_hash,
+            model: self.model,
+            rewind: None,
+            rewinding: false,
+            recorder: None,
+            seed: self.seed,
+            movie: None,
+            fresh: true,
+            frame_ticks: 0,
+            width: 160,
+            height: 144,
+        };
+        if let Some(movie) = self.movie {
+            if movie.rom_hash != rom_hash {
+                return Err(Error::BadMovie("recorded with a different ROM"));
+            }
+            if let MovieStart::State(ref state) = movie.start {
+                gb.load_state(state)?;
+            }
+            gb.movie = Some(Session::playing(movie));
+        }
+        Ok(gb)
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -51,16 +258,51 @@ pub fn load_rom(filepath: &str) -> Result<(Vec<u8>, std::path::PathBuf), String>
 pub const CYCLES: u32 = 70224;
 
 impl Gameboy {
+    // Panics when the ROM cannot be loaded, see `try_new`
     pub fn new(data: Vec<u8>, filepath: Option<std::path::PathBuf>) -> Gameboy {
-        // let rom = load_rom();
+        match Gameboy::try_new(data, filepath) {
+            Ok(gb) => gb,
+            Err(e) => panic!("{}", e),
+        }
+    }
 
-        let gb = Gameboy {
-            cpu: Cpu::new(data, filepath),
-            width: 160,
-            height: 144,
-        };
+    pub fn try_new(
+        data: Vec<u8>,
+        filepath: Option<std::path::PathBuf>,
+    ) -> Result<Gameboy, Error> {
+        GameboyBuilder {
+            data,
+            filepath,
+            model: Target::default(),
+            boot_rom: None,
+            seed: DEFAULT_SEED,
+            movie: None,
+        }
+        .build()
+    }
+
+    pub fn builder(data: Vec<u8>) -> GameboyBuilder {
+        GameboyBuilder {
+            data,
+            filepath: None,
+            model: Target::default(),
+            boot_rom: None,
+            seed: DEFAULT_SEED,
+            movie: None,
+        }
+    }
+
+    pub fn model(&self) -> Target {
+        self.model
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.cpu.memory.header
+    }
 
-        gb
+    // The last error the running machine ran into, such as an illegal DMA
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.cpu.memory.error.take()
     }
 
     pub fn render(self, render_mode: RenderMode) {
@@ -103,6 +345,12 @@ impl Gameboy {
 
         let cx = Glcx::new();
         let mut focused = true;
+        self.enable_rewind(rewind::DEFAULT_INTERVAL, rewind::DEFAULT_BUDGET);
+        let mut sink = open_audio_sink();
+        if let Some(rate) = sink.sample_rate() {
+            self.set_audio_sample_rate(rate);
+        }
+        let mut pacer = FramePacer::new(std::time::Instant::now());
         event_loop.run(move |event, _, control_flow| {
             let window = gl_window.window();
             match event {
@@ -113,31 +361,95 @@ impl Gameboy {
                     *control_flow =
                         process_window(window, &wevent, &mut self, &mut focused)
                 }
-                glutin::event::Event::MainEventsCleared => window.request_redraw(),
+                glutin::event::Event::MainEventsCleared => {
+                    let now = std::time::Instant::now();
+                    if pacer.run_frames(now, &mut self, sink.as_mut()) > 0 {
+                        window.request_redraw();
+                    }
+                    *control_flow = glutin::event_loop::ControlFlow::WaitUntil(
+                        pacer.next_deadline(now, sink.as_ref()),
+                    );
+                }
                 glutin::event::Event::RedrawRequested(_) => {
-                    self.frame();
                     cx.draw(self.width, self.height, self.image());
                     gl_window.swap_buffers().unwrap();
-
-                    std::thread::sleep(std::time::Duration::from_millis(5));
-                }
-                _ => {
-                    let next_frame_time =
-                        std::time::Instant::now() + std::time::Duration::from_millis(5);
-                    *control_flow =
-                        glutin::event_loop::ControlFlow::WaitUntil(next_frame_time);
                 }
+                _ => {}
             }
         });
     }
 
     #[cfg(not(target_arch = "wasm32"))]
-    pub fn render_terminal(self) {
+    pub fn render_terminal(mut self) {
         use crate::screen::tui;
 
+        self.enable_rewind(rewind::DEFAULT_INTERVAL, rewind::DEFAULT_BUDGET);
         let _ = tui::run(self);
     }
 
+    // Traces every instruction from now on, replacing the previous tracer
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        tracer.hook_panics();
+        self.cpu.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.cpu.tracer.take()
+    }
+
+    // Called with every byte sent over the link cable, returns the byte received
+    // in exchange if there is another end
+    pub fn set_serial_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(u8) -> Option<u8> + Send + 'static,
+    {
+        self.cpu.memory.serial.set_callback(Box::new(callback));
+    }
+
+    // Whether CPU accesses to VRAM and OAM are dropped while the PPU uses them,
+    // reads then return 0xFF. On by default, as on hardware
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.cpu.memory.access_restrictions = enabled;
+    }
+
+    // Called with every CPU access to VRAM or OAM while the PPU uses them, whether
+    // or not restrictions are enabled
+    pub fn set_access_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(IllegalAccess) + Send + 'static,
+    {
+        self.cpu.memory.access_callback = Some(Box::new(callback));
+    }
+
+    // Reads memory as the CPU sees it, without tripping watchpoints
+    pub fn peek(&mut self, address: u16) -> u8 {
+        self.cpu.memory.read_byte(address)
+    }
+
+    // Writes memory as the CPU would, ROM writes reach the MBC. Ignored during a
+    // movie.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        if self.movie.is_some() {
+            return;
+        }
+        self.fresh = false;
+        self.cpu.memory.wb(address, value)
+    }
+
+    // Decodes the instruction at `address` as currently mapped
+    pub fn disassemble(&mut self, address: u16) -> Instruction {
+        disasm::disassemble(&mut self.cpu.memory, address)
+    }
+
+    pub(crate) fn cpu(&self) -> &Cpu<'static> {
+        &self.cpu
+    }
+
+    pub(crate) fn cpu_mut(&mut self) -> &mut Cpu<'static> {
+        self.fresh = false;
+        &mut self.cpu
+    }
+
     #[inline]
     pub fn check_and_reset_gpu_updated(&mut self) -> bool {
         let result = self.cpu.memory.gpu.updated;
@@ -146,35 +458,640 @@ impl Gameboy {
     }
 
     pub fn frame(&mut self) {
+        if self.rewinding {
+            self.rewind_step();
+            self.record_frame();
+            return;
+        }
+        self.fresh = false;
+
         // let waitticks = (4194304f64 / 1000.0 * 16.0).round() as u32;
         let waitticks = CYCLES;
         let mut ticks = 0;
 
         'outer: loop {
             while ticks < waitticks {
-                ticks += self.cpu.do_cycle();
+                self.play_movie_input();
+                let cycle_ticks = self.cpu.do_cycle();
+                ticks += cycle_ticks;
+                self.frame_ticks += cycle_ticks;
                 if self.check_and_reset_gpu_updated() {
                     break 'outer;
                 }
             }
 
+            // The LCD isn't clocked in STOP mode, a frame's worth of time will do
+            if self.cpu.state == CpuState::Stopped {
+                break;
+            }
             ticks -= waitticks;
         }
+
+        self.frame_ticks = 0;
+        if self.movie.as_mut().is_some_and(|m| m.frame_done()) {
+            self.movie = None;
+        }
+        if self.rewind.as_mut().is_some_and(|r| r.frame_done()) {
+            let state = self.save_state();
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.push(state);
+            }
+        }
+        self.record_frame();
+    }
+
+    fn play_movie_input(&mut self) {
+        if let Some(ref mut movie) = self.movie {
+            for event in movie.due(self.frame_ticks) {
+                if event.pressed {
+                    self.cpu.memory.keypad.keydown(event.key);
+                } else {
+                    self.cpu.memory.keypad.keyup(event.key);
+                }
+            }
+        }
+    }
+
+    // Records every key press and release from now on. The movie starts at power on
+    // if nothing has run since, otherwise from a save state of the machine as it is.
+    pub fn start_movie(&mut self) {
+        let start = match self.fresh {
+            true => MovieStart::PowerOn,
+            false => MovieStart::State(self.save_state()),
+        };
+        self.rewinding = false;
+        self.movie = Some(Session::recording(Movie {
+            rom_hash: self.rom_hash,
+            model: self.model,
+            seed: self.seed,
+            start,
+            events: Vec::new(),
+            frames: 0,
+        }));
+    }
+
+    // Ends recording or playback, returns the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|m| m.finish())
+    }
+
+    pub fn is_movie_recording(&self) -> bool {
+        self.movie.as_ref().is_some_and(|m| !m.is_playing())
+    }
+
+    // Playback stops by itself at the end of the movie
+    pub fn is_movie_playing(&self) -> bool {
+        self.movie.as_ref().is_some_and(|m| m.is_playing())
+    }
+
+    fn record_frame(&mut self) {
+        if let Some(ref mut recorder) = self.recorder {
+            let mut samples = Vec::new();
+            self.cpu.memory.sound.read_capture_samples(&mut samples);
+            recorder.record(&*self.cpu.memory.gpu.data, &samples);
+        }
+    }
+
+    // Records every frame from now on, with its sound, to an uncompressed AVI file
+    pub fn start_recording(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_recording()?;
+        let rate = self.audio_sample_rate();
+        self.recorder = Some(Recorder::new(path, rate)?);
+        self.cpu.memory.sound.set_capture(Some(rate));
+        Ok(())
+    }
+
+    // Finishes the file, or returns the first error that stopped the recording
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => {
+                self.cpu.memory.sound.set_capture(None);
+                recorder.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Keeps a snapshot every `interval` frames, dropping the oldest ones once they
+    // take more than `budget` bytes
+    pub fn enable_rewind(&mut self, interval: u32, budget: usize) {
+        self.rewind = Some(Rewind::new(interval, budget));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+        self.rewinding = false;
+    }
+
+    // While set, `frame` steps back one snapshot instead of running. Not available
+    // during a movie.
+    pub fn set_rewinding(&mut self, rewinding: bool) {
+        self.rewinding = rewinding && self.rewind.is_some() && self.movie.is_none();
+    }
+
+    pub fn is_rewinding(&self) -> bool {
+        self.rewinding
+    }
+
+    // Number of snapshots held and the memory they take
+    pub fn rewind_usage(&self) -> (usize, usize) {
+        match self.rewind {
+            Some(ref rewind) => (rewind.snapshots(), rewind.memory_used()),
+            None => (0, 0),
+        }
+    }
+
+    // Goes back to the previous snapshot. Returns false when there is none left.
+    pub fn rewind_step(&mut self) -> bool {
+        if self.movie.is_some() {
+            return false;
+        }
+        let state = match self.rewind.as_mut().and_then(|r| r.step_back()) {
+            Some(state) => state,
+            None => return false,
+        };
+        self.load_state(&state).is_ok()
     }
 
     pub fn image(&self) -> &[u8] {
         &*self.cpu.memory.gpu.data
     }
 
+    // The screen as a PNG
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn screenshot_png(&self) -> Vec<u8> {
+        screenshot::encode_png(self.image())
+    }
+
+    // Compares the screen with a PNG of the same size
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn compare_screenshot(&self, reference_png: &[u8]) -> Result<Comparison, Error> {
+        let reference = screenshot::decode_png(reference_png)?;
+        Ok(screenshot::compare(self.image(), &reference))
+    }
+
     pub fn image_mut(&mut self) -> &mut [u8] {
         &mut *self.cpu.memory.gpu.data
     }
 
+    pub fn set_audio_sample_rate(&mut self, rate: u32) {
+        self.cpu.memory.sound.set_sample_rate(rate);
+    }
+
+    pub fn audio_sample_rate(&self) -> u32 {
+        self.cpu.memory.sound.sample_rate()
+    }
+
+    // Appends every generated stereo frame as interleaved f32 samples and returns
+    // the number of frames appended
+    pub fn drain_audio_f32(&mut self, out: &mut Vec<f32>) -> usize {
+        let sound = &mut self.cpu.memory.sound;
+        let start = out.len();
+        out.resize(start + sound.samples_available() * 2, 0.0);
+        sound.read_samples(&mut out[start..])
+    }
+
+    pub fn drain_audio_i16(&mut self, out: &mut Vec<i16>) -> usize {
+        let mut samples = Vec::new();
+        let count = self.drain_audio_f32(&mut samples);
+        out.extend(
+            samples
+                .iter()
+                .map(|v| (v.clamp(-1.0, 1.0) * 32767.0) as i16),
+        );
+        count
+    }
+
+    // Fills `out` with as many interleaved i16 samples as fit, for callers that own
+    // a fixed buffer
+    pub fn read_audio_i16(&mut self, out: &mut [i16]) -> usize {
+        let mut samples = vec![0.0; out.len() & !1];
+        let count = self.cpu.memory.sound.read_samples(&mut samples);
+        for (o, v) in out.iter_mut().zip(&samples[..count * 2]) {
+            *o = (v.clamp(-1.0, 1.0) * 32767.0) as i16;
+        }
+        count
+    }
+
+    // Starts (or stops) recording every sound channel into its own buffer
+    pub fn set_audio_stems(&mut self, enabled: bool) {
+        self.cpu.memory.sound.set_stems(enabled);
+    }
+
+    pub fn drain_audio_stem_f32(
+        &mut self,
+        channel: AudioChannel,
+        out: &mut Vec<f32>,
+    ) -> usize {
+        let sound = &mut self.cpu.memory.sound;
+        let start = out.len();
+        out.resize(
+            start + sound.stem_samples_available(channel as usize) * 2,
+            0.0,
+        );
+        let count = sound.read_stem_samples(channel as usize, &mut out[start..]);
+        out.truncate(start + count * 2);
+        count
+    }
+
+    // Snapshot of the whole machine, tied to the loaded ROM
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.rom_hash);
+        self.cpu.save_state(&mut w);
+        w.finish()
+    }
+
+    // On error the machine is left as it was before the call. Not available during a
+    // movie.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.movie.is_some() {
+            return Err(Error::MovieActive);
+        }
+        self.fresh = false;
+        let backup = self.save_state();
+        let result = StateReader::new(data, self.rom_hash).and_then(|mut r| {
+            self.cpu.load_state(&mut r)?;
+            r.finish()
+        });
+        if let Err(e) = result {
+            let mut r = StateReader::new(&backup, self.rom_hash).unwrap();
+            self.cpu.load_state(&mut r).unwrap();
+            return Err(Error::InvalidState(e));
+        }
+        Ok(())
+    }
+
+    // Input comes from the movie while one is playing
     pub fn keydown(&mut self, key: KeypadKey) {
-        self.cpu.memory.keypad.keydown(key);
+        self.set_key(key, true);
     }
 
     pub fn keyup(&mut self, key: KeypadKey) {
-        self.cpu.memory.keypad.keyup(key);
+        self.set_key(key, false);
+    }
+
+    fn set_key(&mut self, key: KeypadKey, pressed: bool) {
+        match self.movie {
+            Some(ref movie) if movie.is_playing() => return,
+            Some(ref mut movie) => movie.record(self.frame_ticks, key, pressed),
+            None => self.fresh = false,
+        }
+        if pressed {
+            self.cpu.memory.keypad.keydown(key);
+        } else {
+            self.cpu.memory.keypad.keyup(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, GameBoy, GameBoyAdvance, GameBoyColor, Gameboy, SuperGameBoy};
+    use crate::header::test_rom;
+    use crate::input::KeypadKey;
+    use crate::mode::GbMode;
+    use crate::movie::{Movie, MovieStart};
+    use crate::state::{StateReader, StateWriter};
+
+    // Counts up in WRAM and HRAM forever, with the APU on
+    fn counter_rom() -> Vec<u8> {
+        let program = [
+            0x3E, 0x80, 0xE0, 0x26, // LD A,0x80; LDH (NR52),A
+            0x21, 0x00, 0xC0, // LD HL,0xC000
+            0x34, // INC (HL)
+            0x3C, // INC A
+            0xE0, 0x80, // LDH (0x80),A
+            0x18, 0xFA, // JR -6
+        ];
+        test_rom(&program)
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut gb = Gameboy::new(counter_rom(), None);
+        for _ in 0..5 {
+            gb.frame();
+        }
+        let saved = gb.save_state();
+        for _ in 0..5 {
+            gb.frame();
+        }
+        let expected = gb.save_state();
+
+        gb.load_state(&saved).unwrap();
+        assert_eq!(gb.save_state(), saved);
+        for _ in 0..5 {
+            gb.frame();
+        }
+        assert_eq!(gb.save_state(), expected);
+    }
+
+    // Adds the joypad buttons to a byte in WRAM forever
+    fn joypad_rom() -> Vec<u8> {
+        let program = [
+            0x3E, 0x10, 0xE0, 0x00, // LD A,0x10; LDH (P1),A: select the buttons
+            0xF0, 0x00, // LDH A,(P1)
+            0x21, 0x00, 0xC0, // LD HL,0xC000
+            0x86, // ADD A,(HL)
+            0x77, // LD (HL),A
+            0x18, 0xF7, // JR -9
+        ];
+        test_rom(&program)
+    }
+
+    #[test]
+    fn movie_plays_back_exactly() {
+        // Nothing outside of the movie may change the machine
+        let refuse = |gb: &mut Gameboy| {
+            let before = gb.save_state();
+            assert_eq!(gb.load_state(&before), Err(Error::MovieActive));
+            gb.poke(0xC000, 0x55);
+            assert_eq!(gb.save_state(), before);
+        };
+        let record = |gb: &mut Gameboy| {
+            gb.start_movie();
+            let mut states = Vec::new();
+            for frame in 0..20 {
+                match frame {
+                    3 => gb.keydown(KeypadKey::A),
+                    5 => gb.keyup(KeypadKey::A),
+                    8 => gb.keydown(KeypadKey::Start),
+                    10 => refuse(gb),
+                    _ => {}
+                }
+                gb.frame();
+                states.push(gb.save_state());
+            }
+            let movie = gb.stop_movie().unwrap();
+            assert_eq!((movie.frames, movie.events.len()), (20, 3));
+            (Movie::decode(&movie.encode()).unwrap(), states)
+        };
+        let replay = |movie: Movie, states: &[Vec<u8>]| {
+            let mut gb = Gameboy::builder(joypad_rom()).movie(movie).build().unwrap();
+            for state in states {
+                assert!(gb.is_movie_playing());
+                gb.keydown(KeypadKey::B);
+                refuse(&mut gb);
+                gb.frame();
+                assert_eq!(&gb.save_state(), state);
+            }
+            assert!(!gb.is_movie_playing());
+        };
+
+        let mut gb = Gameboy::builder(joypad_rom()).seed(7).build().unwrap();
+        let (movie, states) = record(&mut gb);
+        assert_eq!((movie.start.clone(), movie.seed), (MovieStart::PowerOn, 7));
+        replay(movie, &states);
+
+        let (movie, states) = record(&mut gb);
+        assert!(matches!(movie.start, MovieStart::State(_)));
+        replay(movie.clone(), &states);
+
+        let mut rom = joypad_rom();
+        rom[0x7FFF] = 1;
+        assert_eq!(
+            Gameboy::builder(rom).movie(movie).build().err(),
+            Some(Error::BadMovie("recorded with a different ROM"))
+        );
+    }
+
+    #[test]
+    fn state_rejects_other_rom() {
+        let gb = Gameboy::new(counter_rom(), None);
+        let saved = gb.save_state();
+
+        let mut rom = counter_rom();
+        rom[0x7FFF] = 1;
+        let mut other = Gameboy::new(rom, None);
+        let before = other.save_state();
+        assert!(other.load_state(&saved).is_err());
+        assert!(other.load_state(&saved[..saved.len() / 2]).is_err());
+        assert!(other.load_state(b"nope").is_err());
+        assert_eq!(other.save_state(), before);
+    }
+
+    #[test]
+    fn state_rejects_bad_banks() {
+        let mut rom = counter_rom();
+        rom[0x147] = 0x01;
+        let mut gb = Gameboy::new(rom, None);
+        let mbc = &mut gb.cpu.memory.mbc;
+        let mut w = StateWriter::default();
+        mbc.save_state(&mut w);
+        let section = w.finish();
+        assert_eq!(mbc.load_state(&mut StateReader::raw(&section)), Ok(()));
+
+        // MBC1 state ends with the ROM and RAM bank numbers, the ROM has 2 banks
+        let len = section.len();
+        for (at, bank) in [(len - 8, 2u32), (len - 4, 4)] {
+            let mut state = section.clone();
+            state[at..at + 4].copy_from_slice(&bank.to_le_bytes());
+            assert_eq!(
+                mbc.load_state(&mut StateReader::raw(&state)),
+                Err("Invalid cartridge bank")
+            );
+        }
+    }
+
+    #[test]
+    fn rewind_returns_to_earlier_frames() {
+        let mut gb = Gameboy::new(counter_rom(), None);
+        gb.enable_rewind(2, 1024 * 1024);
+        gb.frame();
+        let first = gb.save_state();
+        for _ in 0..8 {
+            gb.frame();
+        }
+        assert_eq!(gb.rewind_usage().0, 5);
+
+        gb.set_rewinding(true);
+        for _ in 0..4 {
+            gb.frame();
+        }
+        assert_eq!(gb.save_state(), first);
+        assert!(!gb.rewind_step());
+
+        gb.set_rewinding(false);
+        gb.frame();
+        assert_ne!(gb.save_state(), first);
+    }
+
+    #[test]
+    fn malformed_roms_are_errors() {
+        let err = |rom: Vec<u8>, path: Option<&str>| {
+            Gameboy::try_new(rom, path.map(Into::into)).err().unwrap()
+        };
+
+        assert_eq!(
+            err(vec![0; 0x100], None),
+            Error::TruncatedRom {
+                expected: 0x150,
+                actual: 0x100
+            }
+        );
+        let mut rom = counter_rom();
+        rom[0x148] = 2;
+        assert_eq!(
+            err(rom, None),
+            Error::TruncatedRom {
+                expected: 0x20000,
+                actual: 0x8000
+            }
+        );
+        let mut rom = counter_rom();
+        rom[0x148] = 0x52;
+        assert_eq!(err(rom, None), Error::BadHeader("invalid ROM size"));
+        let mut rom = counter_rom();
+        rom[0x147] = 0xFC;
+        assert_eq!(
+            err(rom.clone(), Some("camera.gb")),
+            Error::UnsupportedMbc(0xFC)
+        );
+        assert_eq!(err(rom, None), Error::UnsupportedMbc(0xFC));
+    }
+
+    #[test]
+    fn headless_roms_use_their_mbc() {
+        // 64 banks, each starting with its number
+        let mut rom = counter_rom();
+        rom.resize(0x100000, 0);
+        for bank in 0..64 {
+            rom[bank * 0x4000 + 0x1000] = bank as u8;
+        }
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x05;
+        let mut gb = Gameboy::try_new(rom, None).unwrap();
+        // MBC1 only has 5 bits in its low bank register, bank 0x20 maps bank 1
+        gb.poke(0x2000, 0x20);
+        assert_eq!(gb.peek(0x5000), 1);
+    }
+
+    #[test]
+    fn illegal_dma_is_reported() {
+        let program = [
+            0x3E, 0xE0, 0xE0, 0x51, // LD A,0xE0; LDH (HDMA1),A
+            0xAF, 0xE0, 0x55, // XOR A; LDH (HDMA5),A
+            0x18, 0xFE, // JR -2
+        ];
+        let mut rom = test_rom(&program);
+        rom[0x143] = 0x80;
+
+        let mut gb = Gameboy::try_new(rom, None).unwrap();
+        gb.frame();
+        assert_eq!(gb.take_error(), Some(Error::IllegalDma(0xE000)));
+        assert_eq!(gb.take_error(), None);
+    }
+
+    #[test]
+    fn model_sets_boot_state() {
+        let gb = Gameboy::builder(counter_rom()).build().unwrap();
+        assert_eq!(gb.cpu.registers.a, 0x11);
+
+        let mut gb = Gameboy::builder(counter_rom())
+            .model(GameBoy)
+            .build()
+            .unwrap();
+        let r = &gb.cpu.registers;
+        assert_eq!(
+            (r.a, r.f, r.c, r.e, r.h, r.l),
+            (0x01, 0xB0, 0x13, 0xD8, 0x01, 0x4D)
+        );
+        assert_eq!(gb.cpu.memory.rb(0xFF04), 0xAB);
+        // CGB registers are not mapped on a DMG
+        assert_eq!(gb.cpu.memory.rb(0xFF70), 0xFF);
+        gb.cpu.memory.wb(0xFF70, 3);
+        assert_eq!(gb.cpu.memory.rb(0xFF70), 0xFF);
+
+        let mut gb = Gameboy::builder(counter_rom())
+            .model(SuperGameBoy)
+            .build()
+            .unwrap();
+        assert_eq!((gb.cpu.registers.c, gb.cpu.registers.h), (0x14, 0xC0));
+        assert_eq!(gb.cpu.memory.rb(0xFF26), 0xF0);
+        assert_eq!(gb.cpu.memory.rb(0xFF04), 0xD8);
+        let state = gb.save_state();
+        let mut dmg = Gameboy::builder(counter_rom())
+            .model(GameBoy)
+            .build()
+            .unwrap();
+        assert_eq!(
+            dmg.load_state(&state),
+            Err(Error::InvalidState(
+                "Save state belongs to a different model"
+            ))
+        );
+
+        let mut gb = Gameboy::builder(counter_rom())
+            .model(GameBoyAdvance)
+            .build()
+            .unwrap();
+        assert_eq!(gb.cpu.registers.b, 0x01);
+        assert_eq!(gb.peek(0xFF04), 0x1E);
+
+        let mut rom = counter_rom();
+        rom[0x143] = 0xC0;
+        let err = Gameboy::builder(rom).model(GameBoy).build().err().unwrap();
+        assert!(matches!(err, Error::BadHeader(_)));
+    }
+
+    // Turns the LCD on and runs `program`, then unmaps itself with `unmap` right
+    // before 0x0100 like the real boot ROMs do
+    fn boot_rom(size: usize, program: &[u8], unmap: u8) -> Vec<u8> {
+        let mut boot = vec![0; size];
+        // LD A,0x91; LDH (LCDC),A; <program>; JP 0x00FC
+        let program = [&[0x3E, 0x91, 0xE0, 0x40], program, &[0xC3, 0xFC, 0x00]].concat();
+        boot[..program.len()].copy_from_slice(&program);
+        boot[0xFC..0x100].copy_from_slice(&[0x3E, unmap, 0xE0, 0x50]);
+        boot
+    }
+
+    #[test]
+    fn boot_rom_overlay() {
+        let program = [0x3E, 0x5A, 0xEA, 0x00, 0xC1]; // LD A,0x5A; LD (0xC100),A
+        let mut gb = Gameboy::builder(counter_rom())
+            .model(GameBoy)
+            .boot_rom(boot_rom(0x100, &program, 0x01))
+            .build()
+            .unwrap();
+        assert_eq!(gb.cpu.registers.pc, 0);
+        assert_eq!(gb.cpu.memory.rb(0x0000), 0x3E);
+        gb.frame();
+        assert!(!gb.cpu.memory.boot_rom_mapped());
+        assert_eq!(gb.cpu.memory.rb(0x0000), 0x00);
+        assert_eq!(gb.cpu.memory.rb(0xC100), 0x5A);
+
+        // The CGB boot ROM sets up palettes and switches to DMG mode through KEY0
+        let program = [
+            0x3E, 0x04, 0xE0, 0x4C, // LD A,0x04; LDH (KEY0),A
+            0x3E, 0x80, 0xE0, 0x68, // LD A,0x80; LDH (BCPS),A
+            0x3E, 0x1F, 0xE0, 0x69, // LD A,0x1F; LDH (BCPD),A
+        ];
+        let mut gb = Gameboy::builder(counter_rom())
+            .model(GameBoyColor)
+            .boot_rom(boot_rom(0x900, &program, 0x11))
+            .build()
+            .unwrap();
+        assert_eq!(gb.cpu.memory.gbmode, GbMode::Color);
+        gb.frame();
+        assert_eq!(gb.cpu.memory.gbmode, GbMode::ColorAsClassic);
+        assert!(gb.cpu.memory.gpu.compat_palettes);
+        assert_eq!(gb.cpu.memory.rb(0xFF70), 0xFF);
+
+        let err = Gameboy::builder(counter_rom())
+            .boot_rom(vec![0; 0x100])
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            Error::BadBootRom {
+                expected: 0x900,
+                actual: 0x100
+            }
+        );
     }
 }
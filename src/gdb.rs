@@ -0,0 +1,415 @@
+// GDB remote serial protocol stub, for attaching gdb or an IDE over TCP with
+// `target remote localhost:<port>`.
+//
+// Registers are sent as AF, BC, DE, HL, SP and PC, 16 bits each in little-endian
+// order. Breakpoints (Z0/Z1) and watchpoints (Z2-Z4) go to a `Debugger`, which also
+// does the stepping. While running, the emulator checks for an interrupt (Ctrl-C)
+// from the client once per frame worth of cycles.
+
+use crate::debugger::{Access, Breakpoint, Debugger, Register, StopReason};
+use crate::gameboy::{Gameboy, CYCLES};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+const REGISTERS: [Register; 6] = [
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::SP,
+    Register::PC,
+];
+
+// Waits for one client and serves it until it detaches or kills the session
+pub fn serve<A: ToSocketAddrs>(gb: &mut Gameboy, address: A) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    GdbSession::new(stream).run(gb)
+}
+
+pub struct GdbSession {
+    stream: TcpStream,
+    debugger: Debugger,
+    no_ack: bool,
+}
+
+enum Reply {
+    Packet(String),
+    Resume { step: bool },
+    Close(String),
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+    out
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len() / 2)
+        .map(|i| u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+// `addr,length` as used by m, M and Z packets
+fn address_length(s: &str) -> Option<(u16, u16)> {
+    let (address, length) = s.split_once(',')?;
+    Some((number(address)?, number(length)?))
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Watchpoint {
+            address, access, ..
+        } => {
+            let kind = match access {
+                Access::Read => "rwatch",
+                Access::Write => "watch",
+                Access::Execute => return "T05swbreak:;".to_string(),
+            };
+            format!("T05{}:{:x};", kind, address)
+        }
+        StopReason::Breakpoint { .. } => "T05swbreak:;".to_string(),
+        _ => "S05".to_string(),
+    }
+}
+
+impl GdbSession {
+    pub fn new(stream: TcpStream) -> GdbSession {
+        GdbSession {
+            stream,
+            debugger: Debugger::new(),
+            no_ack: false,
+        }
+    }
+
+    pub fn run(&mut self, gb: &mut Gameboy) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                // Interrupt while already stopped
+                None => continue,
+            };
+            match self.handle(gb, &packet) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::Resume { step } => {
+                    let reason = self.resume(gb, step)?;
+                    self.send(&reason)?;
+                }
+                Reply::Close(reply) => {
+                    if !reply.is_empty() {
+                        self.send(&reply)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, gb: &mut Gameboy, packet: &str) -> Reply {
+        let error = || Reply::Packet("E01".to_string());
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => {
+                let values = REGISTERS.map(|r| r.read(gb).to_le_bytes());
+                hex(values.as_flattened())
+            }
+            "G" => {
+                let Some(bytes) = unhex(args).filter(|b| b.len() >= 12) else {
+                    return error();
+                };
+                for (r, v) in REGISTERS.iter().zip(bytes.chunks(2)) {
+                    r.write(gb, u16::from_le_bytes([v[0], v[1]]));
+                }
+                "OK".to_string()
+            }
+            "p" => match number(args).and_then(|n| REGISTERS.get(n as usize)) {
+                Some(r) => hex(&r.read(gb).to_le_bytes()),
+                None => return error(),
+            },
+            "P" => {
+                let Some((n, v)) = args.split_once('=') else {
+                    return error();
+                };
+                let register = number(n).and_then(|n| REGISTERS.get(n as usize));
+                match (register, unhex(v).filter(|v| v.len() == 2)) {
+                    (Some(r), Some(v)) => r.write(gb, u16::from_le_bytes([v[0], v[1]])),
+                    _ => return error(),
+                }
+                "OK".to_string()
+            }
+            "m" => match address_length(args) {
+                Some((address, length)) => {
+                    let bytes: Vec<u8> = (0..length)
+                        .map(|i| gb.peek(address.wrapping_add(i)))
+                        .collect();
+                    hex(&bytes)
+                }
+                None => return error(),
+            },
+            "M" => {
+                let Some((range, data)) = args.split_once(':') else {
+                    return error();
+                };
+                match (address_length(range), unhex(data)) {
+                    (Some((address, _)), Some(bytes)) => {
+                        for (i, b) in bytes.into_iter().enumerate() {
+                            gb.poke(address.wrapping_add(i as u16), b);
+                        }
+                    }
+                    _ => return error(),
+                }
+                "OK".to_string()
+            }
+            "c" | "s" => {
+                if let Some(address) = number(args) {
+                    Register::PC.write(gb, address);
+                }
+                return Reply::Resume {
+                    step: command == "s",
+                };
+            }
+            "Z" | "z" => return self.breakpoint(command == "Z", args),
+            "k" => return Reply::Close(String::new()),
+            "D" => return Reply::Close("OK".to_string()),
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        match packet.split(':').next().unwrap_or_default() {
+            "qSupported" => "PacketSize=4000;QStartNoAckMode+;swbreak+".to_string(),
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Reply {
+        let mut parts = args.splitn(2, ',');
+        let kind = parts.next().unwrap_or_default();
+        let Some((address, length)) = parts.next().and_then(address_length) else {
+            return Reply::Packet("E01".to_string());
+        };
+        let range = address..=address.saturating_add(length.max(1) - 1);
+        let accesses: &[Access] = match kind {
+            "0" | "1" => {
+                match insert {
+                    true => self.debugger.add_breakpoint(Breakpoint::at(address)),
+                    false => {
+                        self.debugger.remove_breakpoint(address, None);
+                    }
+                }
+                return Reply::Packet("OK".to_string());
+            }
+            "2" => &[Access::Write],
+            "3" => &[Access::Read],
+            "4" => &[Access::Read, Access::Write],
+            _ => return Reply::Packet(String::new()),
+        };
+        for access in accesses {
+            match insert {
+                true => self.debugger.add_watchpoint(range.clone(), *access),
+                false => {
+                    self.debugger.remove_watchpoint(range.clone(), *access);
+                }
+            }
+        }
+        Reply::Packet("OK".to_string())
+    }
+
+    fn resume(&mut self, gb: &mut Gameboy, step: bool) -> io::Result<String> {
+        if step {
+            return Ok(stop_reply(&self.debugger.step_into(gb)));
+        }
+        loop {
+            match self.debugger.run_until(gb, CYCLES) {
+                StopReason::CycleLimit => {
+                    if self.interrupted()? {
+                        return Ok("S02".to_string());
+                    }
+                }
+                reason => return Ok(stop_reply(&reason)),
+            }
+        }
+    }
+
+    // Looks for a Ctrl-C from the client without blocking
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = loop {
+            match self.stream.read(&mut byte) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) if byte[0] == 0x03 => break Ok(true),
+                // Stray acks
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Next packet, or None for an interrupt
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                b'$' => break,
+                0x03 => return Ok(None),
+                _ => {}
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                b'#' => break,
+                b => data.push(b),
+            }
+        }
+        let checksum = [self.read_byte()?, self.read_byte()?];
+
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let valid = unhex(&String::from_utf8_lossy(&checksum)) == Some(vec![sum]);
+        if !self.no_ack {
+            self.stream.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        match valid {
+            true => Ok(Some(String::from_utf8_lossy(&data).into_owned())),
+            false => self.read_packet(),
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        loop {
+            write!(self.stream, "${}#{:02x}", data, sum)?;
+            self.stream.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // Resend on a nack, skipping anything else
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::GdbSession;
+    use crate::gameboy::Gameboy;
+    use crate::header::test_rom;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    struct Client(TcpStream);
+
+    impl Client {
+        // Sends a packet and returns the reply
+        fn ask(&mut self, data: &str) -> String {
+            let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(self.0, "${}#{:02x}", data, sum).unwrap();
+            assert_eq!(self.byte(), b'+');
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            while self.byte() != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            self.byte();
+            self.byte();
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.0.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+    }
+
+    #[test]
+    fn loopback_session() {
+        let program = [
+            0x21, 0x00, 0xC0, // LD HL,0xC000
+            0x34, // INC (HL)
+            0x18, 0xFD, // JR -3
+        ];
+        let mut gb = Gameboy::new(test_rom(&program), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut c = Client(TcpStream::connect(address).unwrap());
+            assert!(c.ask("qSupported:swbreak+").contains("swbreak+"));
+            assert_eq!(c.ask("?"), "S05");
+            // AF, BC, DE, HL, SP, PC
+            assert_eq!(c.ask("g"), "8011000008007c00feff0001");
+            assert_eq!(c.ask("m100,3"), "2100c0");
+
+            assert_eq!(c.ask("Z0,103,1"), "OK");
+            assert_eq!(c.ask("c"), "T05swbreak:;");
+            assert_eq!(c.ask("p3"), "00c0");
+            assert_eq!(c.ask("s"), "S05");
+            assert_eq!(c.ask("p5"), "0401");
+            assert_eq!(c.ask("z0,103,1"), "OK");
+
+            assert_eq!(c.ask("Z2,c000,1"), "OK");
+            assert_eq!(c.ask("c"), "T05watch:c000;");
+            assert_eq!(c.ask("z2,c000,1"), "OK");
+            assert_eq!(c.ask("Mc000,1:aa"), "OK");
+            assert_eq!(c.ask("mc000,1"), "aa");
+            assert_eq!(c.ask("P1=3412"), "OK");
+            assert_eq!(c.ask("p1"), "3412");
+
+            // Interrupt a free run
+            write!(c.0, "$c#{:02x}", b'c').unwrap();
+            assert_eq!(c.byte(), b'+');
+            c.0.write_all(&[0x03]).unwrap();
+            assert_eq!(c.reply(), "S02");
+            assert_eq!(c.ask("D"), "OK");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbSession::new(stream).run(&mut gb).unwrap();
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,526 @@
+// Mode 3 as a pixel FIFO, one dot at a time.
+//
+// The fetcher reads a row of 8 background or window pixels in 6 dots and pushes it
+// once the FIFO is empty, while every dot shifts a pixel out to the screen. The
+// first fetch of a line is thrown away, SCX % 8 pixels are dropped at the start,
+// the window restarts the fetcher and sprites stall the output while their row is
+// fetched, so mode 3 takes 172 to 289 dots. Registers are read when they are
+// used, which makes writes in the middle of a line show up on it.
+
+use crate::gpu::{Gpu, WIDTH};
+use crate::mode::GbMode;
+use crate::state::{StateReader, StateWriter, StrResult};
+
+#[derive(Debug, Copy, Clone, Default)]
+struct BgPixel {
+    color: u8,
+    palette: u8,
+    // CGB attribute bit 7, the background goes over sprites
+    priority: bool,
+}
+
+// A color of 0 is transparent
+#[derive(Debug, Copy, Clone, Default)]
+struct ObjPixel {
+    color: u8,
+    palette: u8,
+    behind: bool,
+    index: u8,
+}
+
+// OAM coordinates of a sprite found on the line
+#[derive(Debug, Copy, Clone, Default)]
+struct Sprite {
+    x: u8,
+    y: u8,
+    index: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    // Dots into the current background fetch, it waits at 6 to push
+    step: u8,
+    dummy: bool,
+    // Tile column of the fetch
+    fetch_x: u8,
+    window: bool,
+    tile: u8,
+    attrs: u8,
+    low: u8,
+    high: u8,
+    bg: [BgPixel; 8],
+    // Pixels left in `bg`, taken from the front
+    bg_count: u8,
+    // Lined up with the next pixel out
+    obj: [ObjPixel; 8],
+    // Pixels out on the line so far, and pixels still to drop
+    lx: u8,
+    discard: u8,
+    sprites: [Sprite; 10],
+    sprite_count: u8,
+    next_sprite: u8,
+    // Dots into a sprite fetch, 0 when none is running
+    obj_step: u8,
+}
+
+impl Pipeline {
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for v in [
+            self.step,
+            self.fetch_x,
+            self.tile,
+            self.attrs,
+            self.low,
+            self.high,
+        ] {
+            w.u8(v);
+        }
+        w.bool(self.dummy);
+        w.bool(self.window);
+        for p in &self.bg {
+            w.u8(p.color);
+            w.u8(p.palette);
+            w.bool(p.priority);
+        }
+        w.u8(self.bg_count);
+        for p in &self.obj {
+            w.u8(p.color);
+            w.u8(p.palette);
+            w.bool(p.behind);
+            w.u8(p.index);
+        }
+        w.u8(self.lx);
+        w.u8(self.discard);
+        for s in &self.sprites {
+            w.u8(s.x);
+            w.u8(s.y);
+            w.u8(s.index);
+        }
+        w.u8(self.sprite_count);
+        w.u8(self.next_sprite);
+        w.u8(self.obj_step);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        for v in [
+            &mut self.step,
+            &mut self.fetch_x,
+            &mut self.tile,
+            &mut self.attrs,
+            &mut self.low,
+            &mut self.high,
+        ] {
+            *v = r.u8()?;
+        }
+        self.dummy = r.bool()?;
+        self.window = r.bool()?;
+        for p in &mut self.bg {
+            p.color = r.u8()? & 0x03;
+            p.palette = r.u8()? & 0x07;
+            p.priority = r.bool()?;
+        }
+        self.bg_count = r.u8()?.min(8);
+        for p in &mut self.obj {
+            p.color = r.u8()? & 0x03;
+            p.palette = r.u8()? & 0x07;
+            p.behind = r.bool()?;
+            p.index = r.u8()?;
+        }
+        self.lx = r.u8()?.min(WIDTH as u8);
+        self.discard = r.u8()?;
+        for s in &mut self.sprites {
+            s.x = r.u8()?;
+            s.y = r.u8()?;
+            s.index = r.u8()? % 40;
+        }
+        self.sprite_count = r.u8()?.min(10);
+        self.next_sprite = r.u8()?.min(self.sprite_count);
+        self.obj_step = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Gpu {
+    // Picks the sprites of the line, at most 10 in OAM order, and resets the fetcher
+    pub(super) fn start_pipeline(&mut self) {
+        let mut p = Pipeline {
+            dummy: true,
+            discard: self.scx & 0x07,
+            ..Default::default()
+        };
+        let top = self.line as u16 + 16;
+        let scanned = if self.oam_dma { 0 } else { 40 };
+        for index in 0..scanned {
+            let y = self.voam[index * 4] as u16;
+            if top < y || top >= y + self.sprite_size as u16 {
+                continue;
+            }
+            p.sprites[p.sprite_count as usize] = Sprite {
+                x: self.voam[index * 4 + 1],
+                y: y as u8,
+                index: index as u8,
+            };
+            p.sprite_count += 1;
+            if p.sprite_count == 10 {
+                break;
+            }
+        }
+        // Stable, so sprites at the same x stay in OAM order
+        p.sprites[..p.sprite_count as usize].sort_by_key(|s| s.x);
+        self.pipeline = p;
+    }
+
+    // Runs a dot of mode 3, returns whether the line is done
+    pub(super) fn pipeline_dot(&mut self) -> bool {
+        if self.pipeline.obj_step > 0 {
+            self.pipeline.obj_step += 1;
+            if self.pipeline.obj_step == 6 {
+                self.fetch_sprite();
+                self.pipeline.obj_step = 0;
+            }
+            return false;
+        }
+
+        if self.sprite_due() {
+            // The background fetch in progress finishes first
+            if self.pipeline.bg_count == 0 || self.pipeline.step < 5 {
+                self.fetcher_dot();
+            } else {
+                self.pipeline.obj_step = 1;
+            }
+            return false;
+        }
+
+        if self.window_due() {
+            let p = &mut self.pipeline;
+            p.window = true;
+            p.step = 0;
+            p.fetch_x = 0;
+            p.bg_count = 0;
+            p.discard = 7u8.saturating_sub(self.winx);
+            self.wy_pos += 1;
+        }
+
+        let p = &mut self.pipeline;
+        if p.bg_count > 0 {
+            let bg = p.bg[8 - p.bg_count as usize];
+            p.bg_count -= 1;
+            if p.discard > 0 {
+                p.discard -= 1;
+            } else {
+                let obj = p.obj[0];
+                p.obj.rotate_left(1);
+                p.obj[7] = ObjPixel::default();
+                self.put_pixel(bg, obj);
+                self.pipeline.lx += 1;
+            }
+        }
+        self.fetcher_dot();
+        self.pipeline.lx as usize == WIDTH
+    }
+
+    fn sprite_due(&self) -> bool {
+        let p = &self.pipeline;
+        self.sprite_on
+            && p.discard == 0
+            && p.next_sprite < p.sprite_count
+            && p.sprites[p.next_sprite as usize].x <= p.lx + 8
+    }
+
+    fn window_due(&self) -> bool {
+        let p = &self.pipeline;
+        self.win_on
+            && self.wy_trigger
+            && (self.gbmode == GbMode::Color || self.lcdc0)
+            && !p.window
+            && p.discard == 0
+            && p.lx as u16 + 7 >= self.winx as u16
+    }
+
+    fn fetcher_dot(&mut self) {
+        let color = self.gbmode == GbMode::Color;
+        if self.pipeline.step < 6 {
+            self.pipeline.step += 1;
+            match self.pipeline.step {
+                2 => {
+                    let address = self.tile_map_address();
+                    self.pipeline.tile = self.vram[address];
+                    self.pipeline.attrs = if color {
+                        self.vram[0x2000 + address]
+                    } else {
+                        0
+                    };
+                }
+                4 => self.pipeline.low = self.vram[self.tile_data_address()],
+                6 => self.pipeline.high = self.vram[self.tile_data_address() + 1],
+                _ => {}
+            }
+        }
+        if self.pipeline.step < 6 {
+            return;
+        }
+
+        let p = &mut self.pipeline;
+        if p.dummy {
+            p.dummy = false;
+            p.step = 0;
+            return;
+        }
+        if p.bg_count > 0 {
+            return;
+        }
+        let xflip = p.attrs & 0x20 != 0;
+        for (i, pixel) in p.bg.iter_mut().enumerate() {
+            let bit = if xflip { i } else { 7 - i };
+            *pixel = BgPixel {
+                color: (p.low >> bit) & 1 | ((p.high >> bit) & 1) << 1,
+                palette: p.attrs & 0x07,
+                priority: p.attrs & 0x80 != 0,
+            };
+        }
+        p.bg_count = 8;
+        p.step = 0;
+        p.fetch_x = p.fetch_x.wrapping_add(1);
+    }
+
+    // Offset in VRAM bank 0
+    fn tile_map_address(&self) -> usize {
+        let p = &self.pipeline;
+        let (map, row, column) = match p.window {
+            true => (self.win_tilemap, self.wy_pos as u8, p.fetch_x),
+            false => (
+                self.bg_tilemap,
+                self.scy.wrapping_add(self.line),
+                (self.scx >> 3).wrapping_add(p.fetch_x),
+            ),
+        };
+        let address = map + (row as u16 >> 3) * 32 + (column as u16 & 31);
+        address as usize & 0x1FFF
+    }
+
+    // Offset in VRAM of the low byte of the fetched row
+    fn tile_data_address(&self) -> usize {
+        let p = &self.pipeline;
+        let y = match p.window {
+            true => self.wy_pos as u8,
+            false => self.scy.wrapping_add(self.line),
+        };
+        let row = match p.attrs & 0x40 != 0 {
+            true => 7 - (y & 0x07),
+            false => y & 0x07,
+        } as u16;
+        let tile = match self.tilebase {
+            0x8000 => 0x8000 + p.tile as u16 * 16,
+            _ => (0x9000 + p.tile as i8 as i32 * 16) as u16,
+        };
+        let bank = if p.attrs & 0x08 != 0 { 0x2000 } else { 0 };
+        bank + ((tile + row * 2) as usize & 0x1FFF)
+    }
+
+    fn fetch_sprite(&mut self) {
+        let color = self.gbmode == GbMode::Color;
+        let p = &mut self.pipeline;
+        let sprite = p.sprites[p.next_sprite as usize];
+        p.next_sprite += 1;
+
+        let oam = sprite.index as usize * 4;
+        let size = self.sprite_size as u8;
+        let tile = match size {
+            16 => self.voam[oam + 2] & 0xFE,
+            _ => self.voam[oam + 2],
+        } as u16;
+        let flags = self.voam[oam + 3];
+        // The size may have shrunk since the OAM scan picked the sprite
+        let mut row = self.line.wrapping_add(16).wrapping_sub(sprite.y) & (size - 1);
+        if flags & 0x40 != 0 {
+            row = size - 1 - row;
+        }
+        let bank = if color && flags & 0x08 != 0 {
+            0x2000
+        } else {
+            0
+        };
+        let address = bank + (tile * 16 + row as u16 * 2) as usize;
+        let (low, high) = (self.vram[address], self.vram[address + 1]);
+
+        // Columns already left behind, for sprites partly off the left edge
+        let skip = (p.lx + 8).saturating_sub(sprite.x) as usize;
+        for (i, slot) in p
+            .obj
+            .iter_mut()
+            .enumerate()
+            .take(8usize.saturating_sub(skip))
+        {
+            let column = i + skip;
+            let bit = if flags & 0x20 != 0 {
+                column
+            } else {
+                7 - column
+            };
+            let pixel = ObjPixel {
+                color: (low >> bit) & 1 | ((high >> bit) & 1) << 1,
+                palette: if color {
+                    flags & 0x07
+                } else {
+                    (flags >> 4) & 1
+                },
+                behind: flags & 0x80 != 0,
+                index: sprite.index,
+            };
+            // The first sprite fetched wins, except for OAM order on the CGB
+            if pixel.color != 0
+                && (slot.color == 0 || (color && pixel.index < slot.index))
+            {
+                *slot = pixel;
+            }
+        }
+    }
+
+    fn put_pixel(&mut self, bg: BgPixel, obj: ObjPixel) {
+        if self.skip_frame {
+            return;
+        }
+        let x = self.pipeline.lx as usize;
+        let color = self.gbmode == GbMode::Color;
+        // Without LCDC bit 0 the background and window are blank, except on the CGB
+        // where sprites go on top instead
+        let blank = !color && !self.lcdc0;
+        let bg_color = if blank { 0 } else { bg.color };
+        let obj_wins = obj.color != 0
+            && match color {
+                true => !self.lcdc0 || bg_color == 0 || (!bg.priority && !obj.behind),
+                false => !obj.behind || bg_color == 0,
+            };
+
+        if obj_wins {
+            let palette = obj.palette as usize;
+            if color {
+                let [r, g, b] = self.csprit[palette][obj.color as usize];
+                self.setrgb(x, r, g, b);
+                return;
+            }
+            let shade = match palette {
+                1 => self.pal1[obj.color as usize],
+                _ => self.pal0[obj.color as usize],
+            };
+            if self.compat_palettes {
+                let [r, g, b] = self.csprit[palette][shade as usize];
+                self.setrgb(x, r, g, b);
+            } else {
+                self.setcolor(x, shade);
+            }
+        } else if color {
+            let [r, g, b] = self.cbgpal[bg.palette as usize][bg_color as usize];
+            self.setrgb(x, r, g, b);
+        } else if blank {
+            self.setcolor(x, 0);
+        } else {
+            self.setcolor(x, self.palb[bg_color as usize]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::gpu::{Gpu, WIDTH};
+
+    // LCD, background and sprites on, tiles at 0x8000, with one dark tile row
+    fn gpu() -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.vram[0x10..0x20].fill(0xFF);
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF40, 0x93);
+        gpu
+    }
+
+    fn run_to_line(gpu: &mut Gpu, line: u8) {
+        while gpu.rb(0xFF44) != line {
+            gpu.do_cycle(1);
+        }
+    }
+
+    fn mode3_length(gpu: &mut Gpu) -> u32 {
+        run_to_line(gpu, 1);
+        let mut dots = 0;
+        while gpu.rb(0xFF44) == 1 {
+            gpu.do_cycle(1);
+            if gpu.rb(0xFF41) & 0x03 == 3 {
+                dots += 1;
+            }
+        }
+        dots
+    }
+
+    #[test]
+    fn mode3_length_varies() {
+        assert_eq!(mode3_length(&mut gpu()), 172);
+
+        let mut scrolled = gpu();
+        scrolled.wb(0xFF43, 3);
+        assert_eq!(mode3_length(&mut scrolled), 175);
+
+        let mut window = gpu();
+        window.wb(0xFF40, 0xB3);
+        window.wb(0xFF4A, 0);
+        window.wb(0xFF4B, 87);
+        assert_eq!(mode3_length(&mut window), 178);
+
+        let mut sprite = gpu();
+        sprite.voam[..4].copy_from_slice(&[16, 88, 1, 0]);
+        let length = mode3_length(&mut sprite);
+        assert!((178..=183).contains(&length), "{}", length);
+
+        let mut crowded = gpu();
+        for i in 0..12 {
+            crowded.voam[i * 4..i * 4 + 4].copy_from_slice(&[16, 0, 1, 0]);
+        }
+        let length = mode3_length(&mut crowded);
+        assert!((232..=289).contains(&length), "{}", length);
+    }
+
+    #[test]
+    fn palette_changes_mid_line() {
+        let mut gpu = gpu();
+        gpu.vram[0x1800..0x1820].fill(1);
+        // The first frame after the LCD turns on is not shown
+        run_to_line(&mut gpu, 144);
+        run_to_line(&mut gpu, 1);
+        while gpu.rb(0xFF41) & 0x03 != 3 {
+            gpu.do_cycle(1);
+        }
+        gpu.do_cycle(100);
+        gpu.wb(0xFF47, 0x1B);
+        run_to_line(&mut gpu, 2);
+
+        let row = &gpu.data[WIDTH * 4..WIDTH * 8];
+        assert_eq!(&row[..3], &[0, 0, 0]);
+        assert_eq!(&row[(WIDTH - 1) * 4..(WIDTH - 1) * 4 + 3], &[255, 255, 255]);
+    }
+
+    #[test]
+    fn sprite_size_changes_mid_line() {
+        let mut gpu = gpu();
+        gpu.wb(0xFF40, 0x97);
+        gpu.wb(0xFF48, 0xE4);
+        // Two 8x16 sprites, the first one Y-flipped, line 9 crosses their lower
+        // halves. Their tiles are solid dark except for the rows line 9 shows once
+        // they are 8 lines high: the left half light and the right half clear.
+        gpu.voam[..8].copy_from_slice(&[16, 8, 2, 0x40, 16, 16, 4, 0]);
+        gpu.vram[0x20..0x60].fill(0xFF);
+        gpu.vram[0x2C..0x2E].copy_from_slice(&[0xF0, 0x00]);
+        gpu.vram[0x42..0x44].copy_from_slice(&[0xF0, 0x00]);
+        run_to_line(&mut gpu, 144);
+        run_to_line(&mut gpu, 9);
+        while gpu.rb(0xFF41) & 0x03 != 3 {
+            gpu.do_cycle(1);
+        }
+        gpu.wb(0xFF40, 0x93);
+        run_to_line(&mut gpu, 10);
+
+        let row = &gpu.data[WIDTH * 9 * 4..WIDTH * 10 * 4];
+        for x in 0..16 {
+            let shade = if x % 8 < 4 { 192 } else { 255 };
+            assert_eq!(&row[x * 4..x * 4 + 3], &[shade; 3], "{}", x);
+        }
+    }
+}
@@ -1,17 +1,21 @@
+mod fifo;
+
+use crate::gpu::fifo::Pipeline;
 use crate::mode::GbMode;
-use std::cmp::Ordering;
+use crate::state::{StateReader, StateWriter, StrResult};
 
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
 pub const HEIGHT: usize = 144;
 pub const WIDTH: usize = 160;
 
-#[derive(PartialEq, Debug, Copy, Clone)]
-enum PrioType {
-    Color0,
-    PrioFlag,
-    Normal,
-}
+// RGB of the four monochrome shades, lightest first
+pub const GREY_SHADES: [[u8; 3]; 4] =
+    [[255, 255, 255], [192, 192, 192], [96, 96, 96], [0, 0, 0]];
+pub const DMG_SHADES: [[u8; 3]; 4] =
+    [[155, 188, 15], [139, 172, 15], [48, 98, 48], [15, 56, 15]];
+pub const SGB_SHADES: [[u8; 3]; 4] =
+    [[248, 232, 200], [216, 144, 72], [168, 40, 32], [48, 24, 80]];
 
 #[derive(Debug)]
 pub struct Gpu {
@@ -53,10 +57,23 @@ pub struct Gpu {
     csprit: [[[u8; 3]; 4]; 8],
     vrambank: usize,
     pub data: Box<[u8; WIDTH * HEIGHT * 4]>,
-    bgprio: [PrioType; WIDTH],
+    pipeline: Pipeline,
     pub updated: bool,
     pub interrupt: u8,
+    // The STAT sources OR'd together, the interrupt fires on its rising edge
+    stat_line: bool,
+    // LY = LYC, compared every dot
+    coincidence: bool,
+    // The first line after the LCD turns on has no OAM scan and reads as mode 0
+    lcd_starting: bool,
+    // The first frame after the LCD turns on is not shown
+    skip_frame: bool,
+    // Set by the MMU while OAM DMA owns OAM, the OAM scan then finds no sprites
+    pub oam_dma: bool,
     pub gbmode: GbMode,
+    pub shades: [[u8; 3]; 4],
+    // Set when the CGB boot ROM picked compatibility palettes for a DMG game
+    pub compat_palettes: bool,
     hblanking: bool,
 }
 
@@ -100,10 +117,17 @@ impl Gpu {
             vram: [0; VRAM_SIZE],
             voam: [0; VOAM_SIZE],
             data: Box::new([0; HEIGHT * WIDTH * 4]),
-            bgprio: [PrioType::Normal; WIDTH],
+            pipeline: Pipeline::default(),
             updated: false,
             interrupt: 0,
+            stat_line: false,
+            coincidence: false,
+            lcd_starting: false,
+            skip_frame: false,
+            oam_dma: false,
             gbmode: GbMode::Classic,
+            shades: GREY_SHADES,
+            compat_palettes: false,
             cbgpal_inc: false,
             cbgpal_ind: 0,
             cbgpal: [[[0u8; 3]; 4]; 8],
@@ -115,8 +139,119 @@ impl Gpu {
         }
     }
 
-    pub fn new_cgb() -> Gpu {
-        Gpu::new()
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.mode);
+        w.u32(self.modeclock);
+        w.u8(self.line);
+        w.u8(self.lyc);
+        w.bool(self.lcd_on);
+        w.u16(self.win_tilemap);
+        w.bool(self.win_on);
+        w.u16(self.tilebase);
+        w.u16(self.bg_tilemap);
+        w.u32(self.sprite_size);
+        w.bool(self.sprite_on);
+        w.bool(self.lcdc0);
+        w.bool(self.lyc_inte);
+        w.bool(self.m0_inte);
+        w.bool(self.m1_inte);
+        w.bool(self.m2_inte);
+        w.u8(self.scy);
+        w.u8(self.scx);
+        w.u8(self.winy);
+        w.u8(self.winx);
+        w.bool(self.wy_trigger);
+        w.u32(self.wy_pos as u32);
+        w.u8(self.palbr);
+        w.u8(self.pal0r);
+        w.u8(self.pal1r);
+        w.bytes(&self.palb);
+        w.bytes(&self.pal0);
+        w.bytes(&self.pal1);
+        w.bytes(&self.vram);
+        w.bytes(&self.voam);
+        w.bool(self.cbgpal_inc);
+        w.u8(self.cbgpal_ind);
+        w.bool(self.csprit_inc);
+        w.u8(self.csprit_ind);
+        for color in self.cbgpal.iter().chain(self.csprit.iter()).flatten() {
+            w.bytes(color);
+        }
+        w.u32(self.vrambank as u32);
+        w.bytes(&self.data[..]);
+        w.bool(self.updated);
+        w.u8(self.interrupt);
+        w.bool(self.stat_line);
+        w.bool(self.coincidence);
+        w.bool(self.lcd_starting);
+        w.bool(self.skip_frame);
+        w.u8(self.gbmode as u8);
+        w.bool(self.compat_palettes);
+        w.bool(self.hblanking);
+        self.pipeline.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.mode = r.u8()?;
+        self.modeclock = r.u32()?;
+        self.line = r.u8()?;
+        self.lyc = r.u8()?;
+        self.lcd_on = r.bool()?;
+        self.win_tilemap = r.u16()?;
+        self.win_on = r.bool()?;
+        self.tilebase = r.u16()?;
+        self.bg_tilemap = r.u16()?;
+        self.sprite_size = r.u32()?;
+        self.sprite_on = r.bool()?;
+        self.lcdc0 = r.bool()?;
+        self.lyc_inte = r.bool()?;
+        self.m0_inte = r.bool()?;
+        self.m1_inte = r.bool()?;
+        self.m2_inte = r.bool()?;
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.winy = r.u8()?;
+        self.winx = r.u8()?;
+        self.wy_trigger = r.bool()?;
+        self.wy_pos = r.u32()? as i32;
+        self.palbr = r.u8()?;
+        self.pal0r = r.u8()?;
+        self.pal1r = r.u8()?;
+        r.bytes(&mut self.palb)?;
+        r.bytes(&mut self.pal0)?;
+        r.bytes(&mut self.pal1)?;
+        r.bytes(&mut self.vram)?;
+        r.bytes(&mut self.voam)?;
+        self.cbgpal_inc = r.bool()?;
+        self.cbgpal_ind = r.u8()?;
+        self.csprit_inc = r.bool()?;
+        self.csprit_ind = r.u8()?;
+        for color in self
+            .cbgpal
+            .iter_mut()
+            .chain(self.csprit.iter_mut())
+            .flatten()
+        {
+            r.bytes(color)?;
+        }
+        self.vrambank = match r.u32()? {
+            bank @ 0..=1 => bank as usize,
+            _ => return Err("Invalid VRAM bank"),
+        };
+        if self.cbgpal_ind > 0x3F || self.csprit_ind > 0x3F {
+            return Err("Invalid palette index");
+        }
+        r.bytes(&mut self.data[..])?;
+        self.updated = r.bool()?;
+        self.interrupt = r.u8()?;
+        self.stat_line = r.bool()?;
+        self.coincidence = r.bool()?;
+        self.lcd_starting = r.bool()?;
+        self.skip_frame = r.bool()?;
+        self.gbmode = GbMode::from_u8(r.u8()?).ok_or("Invalid save state")?;
+        self.compat_palettes = r.bool()?;
+        self.hblanking = r.bool()?;
+        self.pipeline.load_state(r)
     }
 
     pub fn do_cycle(&mut self, ticks: u32) {
@@ -125,82 +260,104 @@ impl Gpu {
         }
         self.hblanking = false;
 
-        let mut ticksleft = ticks;
+        for _ in 0..ticks {
+            self.dot();
+        }
+    }
 
-        while ticksleft > 0 {
-            let curticks = if ticksleft >= 80 { 80 } else { ticksleft };
-            self.modeclock += curticks;
-            ticksleft -= curticks;
+    // A line takes 456 dots: 80 in mode 2, mode 3 until the pixel FIFO has put out
+    // the whole line and the rest in mode 0
+    fn dot(&mut self) {
+        if self.mode == 3 && self.pipeline_dot() {
+            self.change_mode(0);
+        }
 
-            // Full line takes 114 ticks
-            if self.modeclock >= 456 {
-                self.modeclock -= 456;
-                self.line = (self.line + 1) % 154;
-                self.check_interrupt_lyc();
+        self.modeclock += 1;
+        if self.modeclock >= 456 {
+            self.modeclock = 0;
+            self.line = (self.line + 1) % 154;
 
+            if self.line >= 144 {
                 // This is a VBlank line
-                if self.line >= 144 && self.mode != 1 {
+                if self.mode != 1 {
                     self.change_mode(1);
                 }
+            } else {
+                self.change_mode(2);
             }
+        } else if (self.mode == 2 || self.lcd_starting) && self.modeclock == 80 {
+            self.change_mode(3);
+        }
 
-            // This is a normal line
-            if self.line < 144 {
-                if self.modeclock <= 80 {
-                    if self.mode != 2 {
-                        self.change_mode(2);
-                    }
-                } else if self.modeclock <= (80 + 172) {
-                    // 252 cycles
-                    if self.mode != 3 {
-                        self.change_mode(3);
-                    }
-                } else {
-                    // the remaining 204
-                    if self.mode != 0 {
-                        self.change_mode(0);
-                    }
-                }
-            }
+        self.coincidence = self.ly() == self.lyc;
+        self.update_stat();
+    }
+
+    // Line 153 reads as 0 after its first cycle, so LYC = 0 matches early
+    pub fn ly(&self) -> u8 {
+        match self.line == 153 && self.modeclock >= 4 {
+            true => 0,
+            false => self.line,
         }
     }
 
-    fn check_interrupt_lyc(&mut self) {
-        if self.lyc_inte && self.line == self.lyc {
+    // While one source holds the line high, the others can't fire
+    fn update_stat(&mut self) {
+        let mode = match self.mode {
+            0 => self.m0_inte && !self.lcd_starting,
+            // The mode 2 source also fires when VBlank starts
+            1 => {
+                self.m1_inte || (self.m2_inte && self.line == 144 && self.modeclock == 0)
+            }
+            2 => self.m2_inte,
+            _ => false,
+        };
+        let line = self.lcd_on && (mode || (self.lyc_inte && self.coincidence));
+        if line && !self.stat_line {
             self.interrupt |= 0x02;
         }
+        self.stat_line = line;
     }
 
     fn change_mode(&mut self, mode: u8) {
         self.mode = mode;
 
-        if match self.mode {
-            0 => {
-                self.renderscan();
-                self.hblanking = true;
-                self.m0_inte
-            }
+        match self.mode {
+            0 => self.hblanking = true,
             1 => {
                 // Vertical blank
                 self.wy_trigger = false;
+                self.skip_frame = false;
                 self.interrupt |= 0x01;
                 self.updated = true;
-                self.m1_inte
             }
-            2 => self.m2_inte,
             3 => {
+                self.lcd_starting = false;
                 if self.win_on && !self.wy_trigger && self.line == self.winy {
                     self.wy_trigger = true;
                     self.wy_pos = -1;
                 }
-                false
+                self.start_pipeline();
             }
-            _ => false,
-        } {
-            self.interrupt |= 0x02;
+            _ => {}
         }
     }
 
+    // Whether the PPU keeps the CPU away from `a`: VRAM while drawing, OAM from the
+    // start of the OAM scan
+    pub fn locked(&self, a: u16) -> bool {
+        self.lcd_on
+            && match a {
+                0x8000..=0x9FFF => self.mode == 3,
+                0xFE00..=0xFE9F => self.mode == 2 || self.mode == 3,
+                _ => false,
+            }
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
     pub fn rb(&self, a: u16) -> u8 {
         match a {
             0x8000..=0x9FFF => {
@@ -222,12 +379,12 @@ impl Gpu {
                     | (if self.m2_inte { 0x20 } else { 0 })
                     | (if self.m1_inte { 0x10 } else { 0 })
                     | (if self.m0_inte { 0x08 } else { 0 })
-                    | (if self.line == self.lyc { 0x04 } else { 0 })
+                    | (if self.coincidence { 0x04 } else { 0 })
                     | self.mode
             }
             0xFF42 => self.scy,
             0xFF43 => self.scx,
-            0xFF44 => self.line,
+            0xFF44 => self.ly(),
             0xFF45 => self.lyc,
             0xFF46 => 0, // Write only
             0xFF47 => self.palbr,
@@ -267,19 +424,6 @@ impl Gpu {
         }
     }
 
-    fn rbvram0(&self, a: u16) -> u8 {
-        if !(0x8000..0xA000).contains(&a) {
-            panic!("Shouldn't have used rbvram0");
-        }
-        self.vram[a as usize & 0x1FFF]
-    }
-    fn rbvram1(&self, a: u16) -> u8 {
-        if !(0x8000..0xA000).contains(&a) {
-            panic!("Shouldn't have used rbvram1");
-        }
-        self.vram[0x2000 + (a as usize & 0x1FFF)]
-    }
-
     pub fn wb(&mut self, a: u16, v: u8) {
         match a {
             0x8000..=0x9FFF => {
@@ -301,11 +445,18 @@ impl Gpu {
                     self.line = 0;
                     self.mode = 0;
                     self.wy_trigger = false;
+                    self.stat_line = false;
                     self.clear_screen();
                 }
                 if !orig_lcd_on && self.lcd_on {
-                    self.change_mode(2);
+                    self.line = 0;
+                    self.mode = 0;
                     self.modeclock = 4;
+                    self.lcd_starting = true;
+                    self.skip_frame = true;
+                    self.fill_blank();
+                    self.coincidence = self.lyc == 0;
+                    self.update_stat();
                 }
             }
             0xFF41 => {
@@ -313,12 +464,18 @@ impl Gpu {
                 self.m2_inte = v & 0x20 == 0x20;
                 self.m1_inte = v & 0x10 == 0x10;
                 self.m0_inte = v & 0x08 == 0x08;
+                self.update_stat();
             }
             0xFF42 => self.scy = v,
             0xFF43 => self.scx = v,
             0xFF44 => {} // Read-only
-            0xFF45 => self.lyc = v,
-            0xFF46 => panic!("0xFF46 should be handled by MMU"),
+            0xFF45 => {
+                self.lyc = v;
+                if self.lcd_on {
+                    self.coincidence = self.ly() == self.lyc;
+                    self.update_stat();
+                }
+            }
             0xFF47 => {
                 self.palbr = v;
                 self.update_pal();
@@ -377,17 +534,29 @@ impl Gpu {
                     self.csprit_ind = (self.csprit_ind + 1) & 0x3F;
                 };
             }
-            _ => panic!("Gpu does not handle write {:04X}", a),
+            _ => {}
         }
     }
 
-    fn clear_screen(&mut self) {
-        for v in self.data.iter_mut() {
-            *v = 255;
+    // What the LCD shows while it isn't clocked, e.g. in STOP mode
+    pub fn blank(&mut self) {
+        if self.lcd_on {
+            self.clear_screen();
         }
+    }
+
+    fn clear_screen(&mut self) {
+        self.fill_blank();
         self.updated = true;
     }
 
+    fn fill_blank(&mut self) {
+        let [r, g, b] = self.shades[0];
+        for pixel in self.data.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+
     fn update_pal(&mut self) {
         for i in 0..4 {
             self.palb[i] = Gpu::get_monochrome_pal_val(self.palbr, i);
@@ -397,27 +566,18 @@ impl Gpu {
     }
 
     fn get_monochrome_pal_val(value: u8, index: usize) -> u8 {
-        match (value >> (2 * index)) & 0x03 {
-            0 => 255,
-            1 => 192,
-            2 => 96,
-            _ => 0,
-        }
+        (value >> (2 * index)) & 0x03
     }
 
-    fn renderscan(&mut self) {
-        for x in 0..WIDTH {
-            self.setcolor(x, 255);
-            self.bgprio[x] = PrioType::Normal;
+    fn setcolor(&mut self, x: usize, shade: u8) {
+        if self.compat_palettes {
+            let [r, g, b] = self.cbgpal[0][shade as usize & 0x03];
+            return self.setrgb(x, r, g, b);
         }
-        self.draw_bg();
-        self.draw_sprites();
-    }
-
-    fn setcolor(&mut self, x: usize, color: u8) {
-        self.data[self.line as usize * WIDTH * 4 + x * 4] = color;
-        self.data[self.line as usize * WIDTH * 4 + x * 4 + 1] = color;
-        self.data[self.line as usize * WIDTH * 4 + x * 4 + 2] = color;
+        let [r, g, b] = self.shades[shade as usize & 0x03];
+        self.data[self.line as usize * WIDTH * 4 + x * 4] = r;
+        self.data[self.line as usize * WIDTH * 4 + x * 4 + 1] = g;
+        self.data[self.line as usize * WIDTH * 4 + x * 4 + 2] = b;
     }
 
     fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
@@ -435,224 +595,82 @@ impl Gpu {
         self.data[baseidx + 2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
     }
 
-    fn draw_bg(&mut self) {
-        let drawbg = self.gbmode == GbMode::Color || self.lcdc0;
-
-        let wx_trigger = self.winx <= 166;
-        let winy = if self.win_on && self.wy_trigger && wx_trigger {
-            self.wy_pos += 1;
-            self.wy_pos
-        } else {
-            -1
-        };
-
-        if winy < 0 && !drawbg {
-            return;
-        }
-
-        let wintiley = (winy as u16 >> 3) & 31;
-
-        let bgy = self.scy.wrapping_add(self.line);
-        let bgtiley = (bgy as u16 >> 3) & 31;
-
-        for x in 0..WIDTH {
-            let winx = -((self.winx as i32) - 7) + (x as i32);
-            let bgx = self.scx as u32 + x as u32;
-
-            let (tilemapbase, tiley, tilex, pixely, pixelx) = if winy >= 0 && winx >= 0 {
-                (
-                    self.win_tilemap,
-                    wintiley,
-                    (winx as u16 >> 3),
-                    winy as u16 & 0x07,
-                    winx as u8 & 0x07,
-                )
-            } else if drawbg {
-                (
-                    self.bg_tilemap,
-                    bgtiley,
-                    (bgx as u16 >> 3) & 31,
-                    bgy as u16 & 0x07,
-                    bgx as u8 & 0x07,
-                )
-            } else {
-                continue;
-            };
-
-            let tilenr: u8 = self.rbvram0(tilemapbase + tiley * 32 + tilex);
-
-            let (palnr, vram1, xflip, yflip, prio) = if self.gbmode == GbMode::Color {
-                let flags = self.rbvram1(tilemapbase + tiley * 32 + tilex) as usize;
-                (
-                    flags & 0x07,
-                    flags & (1 << 3) != 0,
-                    flags & (1 << 5) != 0,
-                    flags & (1 << 6) != 0,
-                    flags & (1 << 7) != 0,
-                )
-            } else {
-                (0, false, false, false, false)
-            };
-
-            let tileaddress = self.tilebase
-                + (if self.tilebase == 0x8000 {
-                    tilenr as u16
-                } else {
-                    (tilenr as i8 as i16 + 128) as u16
-                }) * 16;
-
-            let a0 = match yflip {
-                false => tileaddress + (pixely * 2),
-                true => tileaddress + (14 - (pixely * 2)),
-            };
-
-            let (b1, b2) = match vram1 {
-                false => (self.rbvram0(a0), self.rbvram0(a0 + 1)),
-                true => (self.rbvram1(a0), self.rbvram1(a0 + 1)),
-            };
-
-            let xbit = match xflip {
-                true => pixelx,
-                false => 7 - pixelx,
-            } as u32;
-            let colnr = if b1 & (1 << xbit) != 0 { 1 } else { 0 }
-                | if b2 & (1 << xbit) != 0 { 2 } else { 0 };
-
-            self.bgprio[x] = if colnr == 0 {
-                PrioType::Color0
-            } else if prio {
-                PrioType::PrioFlag
-            } else {
-                PrioType::Normal
-            };
-            if self.gbmode == GbMode::Color {
-                let r = self.cbgpal[palnr][colnr][0];
-                let g = self.cbgpal[palnr][colnr][1];
-                let b = self.cbgpal[palnr][colnr][2];
-                self.setrgb(x, r, g, b);
-            } else {
-                let color = self.palb[colnr];
-                self.setcolor(x, color);
-            }
-        }
+    pub fn may_hdma(&self) -> bool {
+        self.hblanking
     }
+}
 
-    fn draw_sprites(&mut self) {
-        if !self.sprite_on {
-            return;
-        }
-
-        let line = self.line as i32;
-        let sprite_size = self.sprite_size as i32;
+#[cfg(test)]
+mod test {
+    use crate::gpu::Gpu;
+
+    fn gpu(stat: u8, lyc: u8) -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.wb(0xFF45, lyc);
+        gpu.wb(0xFF41, stat);
+        gpu.wb(0xFF40, 0x91);
+        gpu.interrupt = 0;
+        gpu
+    }
 
-        let mut sprites_to_draw = [(0, 0, 0); 10];
-        let mut sidx = 0;
-        for index in 0..40 {
-            let spriteaddr = 0xFE00 + (index as u16) * 4;
-            let spritey = self.rb(spriteaddr) as u16 as i32 - 16;
-            if line < spritey || line >= spritey + sprite_size {
-                continue;
-            }
-            let spritex = self.rb(spriteaddr + 1) as u16 as i32 - 8;
-            sprites_to_draw[sidx] = (spritex, spritey, index);
-            sidx += 1;
-            if sidx >= 10 {
-                break;
+    // Runs a frame, returns the internal line of every STAT interrupt
+    fn stat_interrupts(gpu: &mut Gpu) -> Vec<u8> {
+        let mut lines = Vec::new();
+        for _ in 0..154 * 456 {
+            gpu.do_cycle(1);
+            if gpu.interrupt & 0x02 != 0 {
+                lines.push(gpu.line);
             }
+            gpu.interrupt = 0;
         }
-        if self.gbmode == GbMode::Color {
-            sprites_to_draw[..sidx].sort_unstable_by(cgb_sprite_order);
-        } else {
-            sprites_to_draw[..sidx].sort_unstable_by(dmg_sprite_order);
-        }
-
-        for &(spritex, spritey, i) in &sprites_to_draw[..sidx] {
-            if spritex < -7 || spritex >= (WIDTH as i32) {
-                continue;
-            }
-
-            let spriteaddr = 0xFE00 + (i as u16) * 4;
-            let tilenum = (self.rb(spriteaddr + 2)
-                & (if self.sprite_size == 16 { 0xFE } else { 0xFF }))
-                as u16;
-            let flags = self.rb(spriteaddr + 3) as usize;
-            let usepal1: bool = flags & (1 << 4) != 0;
-            let xflip: bool = flags & (1 << 5) != 0;
-            let yflip: bool = flags & (1 << 6) != 0;
-            let belowbg: bool = flags & (1 << 7) != 0;
-            let c_palnr = flags & 0x07;
-            let c_vram1: bool = flags & (1 << 3) != 0;
-
-            let tiley: u16 = if yflip {
-                (sprite_size - 1 - (line - spritey)) as u16
-            } else {
-                (line - spritey) as u16
-            };
+        lines
+    }
 
-            let tileaddress = 0x8000u16 + tilenum * 16 + tiley * 2;
-            let (b1, b2) = if c_vram1 && self.gbmode == GbMode::Color {
-                (self.rbvram1(tileaddress), self.rbvram1(tileaddress + 1))
-            } else {
-                (self.rbvram0(tileaddress), self.rbvram0(tileaddress + 1))
-            };
+    #[test]
+    fn stat_sources_block_each_other() {
+        assert_eq!(stat_interrupts(&mut gpu(0x08, 0)).len(), 144);
+        assert_eq!(stat_interrupts(&mut gpu(0x10, 0)), vec![144]);
+        // Mode 0 on line 143 holds the line high into VBlank
+        assert_eq!(stat_interrupts(&mut gpu(0x18, 0)).len(), 144);
+        // LY = LYC takes over from the mode 0 of the line before, and hides the
+        // mode 0 of its own line
+        assert_eq!(stat_interrupts(&mut gpu(0x48, 10)).len(), 143);
+        assert_eq!(stat_interrupts(&mut gpu(0x40, 10)), vec![10]);
+    }
 
-            'xloop: for x in 0..8 {
-                if spritex + x < 0 || spritex + x >= (WIDTH as i32) {
-                    continue;
-                }
+    #[test]
+    fn line_153_reads_as_0() {
+        let mut gpu = gpu(0x40, 0);
+        assert_eq!(stat_interrupts(&mut gpu), vec![153]);
 
-                let xbit = 1 << (if xflip { x } else { 7 - x } as u32);
-                let colnr = (if b1 & xbit != 0 { 1 } else { 0 })
-                    | (if b2 & xbit != 0 { 2 } else { 0 });
-                if colnr == 0 {
-                    continue;
-                }
-
-                if self.gbmode == GbMode::Color {
-                    if self.lcdc0
-                        && (self.bgprio[(spritex + x) as usize] == PrioType::PrioFlag
-                            || (belowbg
-                                && self.bgprio[(spritex + x) as usize]
-                                    != PrioType::Color0))
-                    {
-                        continue 'xloop;
-                    }
-                    let r = self.csprit[c_palnr][colnr][0];
-                    let g = self.csprit[c_palnr][colnr][1];
-                    let b = self.csprit[c_palnr][colnr][2];
-                    self.setrgb((spritex + x) as usize, r, g, b);
-                } else {
-                    if belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0
-                    {
-                        continue 'xloop;
-                    }
-                    let color = if usepal1 {
-                        self.pal1[colnr]
-                    } else {
-                        self.pal0[colnr]
-                    };
-                    self.setcolor((spritex + x) as usize, color);
-                }
-            }
+        while gpu.line != 153 {
+            gpu.do_cycle(1);
         }
+        assert_eq!(gpu.rb(0xFF44), 153);
+        gpu.do_cycle(4);
+        assert_eq!(gpu.rb(0xFF44), 0);
+        assert_eq!(gpu.rb(0xFF41) & 0x04, 0x04);
     }
 
-    pub fn may_hdma(&self) -> bool {
-        self.hblanking
+    #[test]
+    fn lcd_on_sequence() {
+        let mut gpu = gpu(0x20, 0);
+        gpu.wb(0xFF47, 0xFF);
+        assert_eq!(gpu.rb(0xFF41) & 0x03, 0);
+        gpu.do_cycle(76);
+        assert_eq!(gpu.rb(0xFF41) & 0x03, 3);
+
+        // No OAM scan on the first line. Mode 2 also fires as VBlank starts, and
+        // for line 0 of the next frame.
+        let lines = stat_interrupts(&mut gpu);
+        assert_eq!((lines[0], lines[143], lines.len()), (1, 144, 145));
+        // The first frame stays blank, the next one is drawn
+        assert_eq!(&gpu.data[..4], &[255, 255, 255, 255]);
+        stat_interrupts(&mut gpu);
+        assert_eq!(&gpu.data[..3], &[0, 0, 0]);
+
+        gpu.wb(0xFF40, 0x11);
+        assert_eq!((gpu.rb(0xFF44), gpu.rb(0xFF41) & 0x03), (0, 0));
+        assert!(stat_interrupts(&mut gpu).is_empty());
     }
 }
-
-// Functions to determine the order of sprites. Input is a tuple x-coord, OAM position
-// These function ensures that sprites with a higher priority are 'larger'
-fn dmg_sprite_order(a: &(i32, i32, u8), b: &(i32, i32, u8)) -> Ordering {
-    // DMG order: prioritize on x-coord, and then by OAM position.
-    if a.0 != b.0 {
-        return b.0.cmp(&a.0);
-    }
-    b.2.cmp(&a.2)
-}
-
-fn cgb_sprite_order(a: &(i32, i32, u8), b: &(i32, i32, u8)) -> Ordering {
-    // CGB order: only prioritize based on OAM position.
-    b.2.cmp(&a.2)
-}
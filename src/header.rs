@@ -0,0 +1,389 @@
+// Cartridge header, found at 0x0100-0x014F of every ROM.
+
+use crate::gameboy::Error;
+
+const HEADER_END: usize = 0x150;
+
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C,
+    0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6,
+    0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC,
+    0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum CartridgeType {
+    RomOnly,
+    Mbc1,
+    Mbc1Ram,
+    Mbc1RamBattery,
+    Mbc2,
+    Mbc2Battery,
+    RomRam,
+    RomRamBattery,
+    Mmm01,
+    Mmm01Ram,
+    Mmm01RamBattery,
+    Mbc3TimerBattery,
+    Mbc3TimerRamBattery,
+    Mbc3,
+    Mbc3Ram,
+    Mbc3RamBattery,
+    Mbc5,
+    Mbc5Ram,
+    Mbc5RamBattery,
+    Mbc5Rumble,
+    Mbc5RumbleRam,
+    Mbc5RumbleRamBattery,
+    Mbc6,
+    Mbc7SensorRumbleRamBattery,
+    PocketCamera,
+    BandaiTama5,
+    HuC3,
+    HuC1RamBattery,
+    Unknown(u8),
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> CartridgeType {
+        use CartridgeType::*;
+        match code {
+            0x00 => RomOnly,
+            0x01 => Mbc1,
+            0x02 => Mbc1Ram,
+            0x03 => Mbc1RamBattery,
+            0x05 => Mbc2,
+            0x06 => Mbc2Battery,
+            0x08 => RomRam,
+            0x09 => RomRamBattery,
+            0x0B => Mmm01,
+            0x0C => Mmm01Ram,
+            0x0D => Mmm01RamBattery,
+            0x0F => Mbc3TimerBattery,
+            0x10 => Mbc3TimerRamBattery,
+            0x11 => Mbc3,
+            0x12 => Mbc3Ram,
+            0x13 => Mbc3RamBattery,
+            0x19 => Mbc5,
+            0x1A => Mbc5Ram,
+            0x1B => Mbc5RamBattery,
+            0x1C => Mbc5Rumble,
+            0x1D => Mbc5RumbleRam,
+            0x1E => Mbc5RumbleRamBattery,
+            0x20 => Mbc6,
+            0x22 => Mbc7SensorRumbleRamBattery,
+            0xFC => PocketCamera,
+            0xFD => BandaiTama5,
+            0xFE => HuC3,
+            0xFF => HuC1RamBattery,
+            n => Unknown(n),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        use CartridgeType::*;
+        match *self {
+            RomOnly => 0x00,
+            Mbc1 => 0x01,
+            Mbc1Ram => 0x02,
+            Mbc1RamBattery => 0x03,
+            Mbc2 => 0x05,
+            Mbc2Battery => 0x06,
+            RomRam => 0x08,
+            RomRamBattery => 0x09,
+            Mmm01 => 0x0B,
+            Mmm01Ram => 0x0C,
+            Mmm01RamBattery => 0x0D,
+            Mbc3TimerBattery => 0x0F,
+            Mbc3TimerRamBattery => 0x10,
+            Mbc3 => 0x11,
+            Mbc3Ram => 0x12,
+            Mbc3RamBattery => 0x13,
+            Mbc5 => 0x19,
+            Mbc5Ram => 0x1A,
+            Mbc5RamBattery => 0x1B,
+            Mbc5Rumble => 0x1C,
+            Mbc5RumbleRam => 0x1D,
+            Mbc5RumbleRamBattery => 0x1E,
+            Mbc6 => 0x20,
+            Mbc7SensorRumbleRamBattery => 0x22,
+            PocketCamera => 0xFC,
+            BandaiTama5 => 0xFD,
+            HuC3 => 0xFE,
+            HuC1RamBattery => 0xFF,
+            Unknown(n) => n,
+        }
+    }
+
+    // External RAM on the cartridge (MBC2 has its RAM built in, so it is not counted)
+    pub fn has_ram(&self) -> bool {
+        use CartridgeType::*;
+        matches!(
+            self,
+            Mbc1Ram
+                | Mbc1RamBattery
+                | RomRam
+                | RomRamBattery
+                | Mmm01Ram
+                | Mmm01RamBattery
+                | Mbc3TimerRamBattery
+                | Mbc3Ram
+                | Mbc3RamBattery
+                | Mbc5Ram
+                | Mbc5RamBattery
+                | Mbc5RumbleRam
+                | Mbc5RumbleRamBattery
+                | Mbc7SensorRumbleRamBattery
+                | HuC1RamBattery
+        )
+    }
+
+    pub fn has_battery(&self) -> bool {
+        use CartridgeType::*;
+        matches!(
+            self,
+            Mbc1RamBattery
+                | Mbc2Battery
+                | RomRamBattery
+                | Mmm01RamBattery
+                | Mbc3TimerBattery
+                | Mbc3TimerRamBattery
+                | Mbc3RamBattery
+                | Mbc5RamBattery
+                | Mbc5RumbleRamBattery
+                | Mbc7SensorRumbleRamBattery
+                | HuC1RamBattery
+        )
+    }
+
+    pub fn has_timer(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc3TimerBattery | CartridgeType::Mbc3TimerRamBattery
+        )
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        use CartridgeType::*;
+        matches!(
+            self,
+            Mbc5Rumble
+                | Mbc5RumbleRam
+                | Mbc5RumbleRamBattery
+                | Mbc7SensorRumbleRamBattery
+        )
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum CgbSupport {
+    None,
+    // Runs on both, with color enhancements on CGB (0x80)
+    Compatible,
+    // Refuses to run on anything but a CGB (0xC0)
+    Only,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Licensee {
+    Old(u8),
+    // Two ASCII characters, used when the old licensee code is 0x33
+    New([u8; 2]),
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer: Option<String>,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub licensee: Licensee,
+    pub cartridge_type: CartridgeType,
+    // In bytes
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub logo_valid: bool,
+    pub header_checksum_valid: bool,
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, Error> {
+        if rom.len() < HEADER_END {
+            return Err(Error::TruncatedRom {
+                expected: HEADER_END,
+                actual: rom.len(),
+            });
+        }
+
+        let rom_size = match rom[0x148] {
+            v @ 0..=8 => 0x8000 << v,
+            _ => return Err(Error::BadHeader("invalid ROM size")),
+        };
+        let ram_size = match rom[0x149] {
+            0 => 0,
+            1 => 0x800,
+            2 => 0x2000,
+            3 => 0x8000,
+            4 => 0x20000,
+            5 => 0x10000,
+            _ => return Err(Error::BadHeader("invalid RAM size")),
+        };
+
+        let cgb = match rom[0x143] {
+            0xC0 => CgbSupport::Only,
+            v if v & 0x80 == 0x80 => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        };
+        // Newer cartridges took the end of the title for the manufacturer code
+        let manufacturer = &rom[0x13F..0x143];
+        let (title_end, manufacturer) = match cgb {
+            CgbSupport::None => (0x144, None),
+            _ if manufacturer.iter().all(|c| c.is_ascii_alphanumeric()) => (
+                0x13F,
+                Some(manufacturer.iter().map(|c| *c as char).collect()),
+            ),
+            _ => (0x143, None),
+        };
+        let title = rom[0x134..title_end]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+
+        let licensee = match rom[0x14B] {
+            0x33 => Licensee::New([rom[0x144], rom[0x145]]),
+            v => Licensee::Old(v),
+        };
+
+        let header_checksum = rom[0x14D];
+        let global_checksum = u16::from_be_bytes([rom[0x14E], rom[0x14F]]);
+        let global_sum = rom
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x14E && *i != 0x14F)
+            .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16));
+
+        Ok(CartridgeHeader {
+            title,
+            manufacturer,
+            cgb,
+            sgb: rom[0x146] == 0x03,
+            licensee,
+            cartridge_type: CartridgeType::from_code(rom[0x147]),
+            rom_size,
+            ram_size,
+            destination: match rom[0x14A] {
+                0 => Destination::Japan,
+                _ => Destination::Overseas,
+            },
+            version: rom[0x14C],
+            header_checksum,
+            global_checksum,
+            logo_valid: rom[0x104..0x134] == NINTENDO_LOGO,
+            header_checksum_valid: compute_header_checksum(rom) == header_checksum,
+            global_checksum_valid: global_sum == global_checksum,
+        })
+    }
+
+    pub fn rom_banks(&self) -> usize {
+        self.rom_size / 0x4000
+    }
+
+    // "Listed in various unofficial docs as 2 KiB. However, a 2 KiB RAM chip was never
+    // used in a cartridge. The source of this value is unknown."
+    // Needed by some test roms. As we only deal in whole banks, just make it 1 8KiB bank.
+    pub fn ram_banks(&self) -> usize {
+        self.ram_size.div_ceil(0x2000)
+    }
+}
+
+// A 32 KiB ROM without a mapper that starts running `program`. Callers fill in
+// whatever header bytes they need.
+#[cfg(test)]
+pub(crate) fn test_rom(program: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    rom
+}
+
+// The boot ROM refuses to start a cartridge when this does not match 0x14D
+fn compute_header_checksum(rom: &[u8]) -> u8 {
+    let mut value: u8 = 0;
+    for item in rom.iter().take(0x14D).skip(0x134) {
+        value = value.wrapping_sub(*item).wrapping_sub(1);
+    }
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksum_zero() {
+        let mut data = vec![0; 0x150];
+        data[0x14D] = -(0x14D_i32 - 0x134_i32) as u8;
+        assert_eq!(super::compute_header_checksum(&data), data[0x14D]);
+    }
+
+    #[test]
+    fn checksum_ones() {
+        let mut data = vec![1; 0x150];
+        data[0x14D] = (-(0x14D_i32 - 0x134_i32) * 2) as u8;
+        assert_eq!(super::compute_header_checksum(&data), data[0x14D]);
+    }
+
+    #[test]
+    fn parse_fields() {
+        let mut rom = test_rom(&[]);
+        rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x134..0x13F].copy_from_slice(b"POKEMON_GLD");
+        rom[0x13F..0x143].copy_from_slice(b"AAUE");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x03;
+        rom[0x14A] = 0x01;
+        rom[0x14B] = 0x33;
+        rom[0x14C] = 0x02;
+        rom[0x14D] = compute_header_checksum(&rom);
+        let sum = rom.iter().fold(0u16, |s, b| s.wrapping_add(*b as u16));
+        rom[0x14E..0x150].copy_from_slice(&sum.to_be_bytes());
+
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON_GLD");
+        assert_eq!(header.manufacturer.as_deref(), Some("AAUE"));
+        assert_eq!(header.cgb, CgbSupport::Compatible);
+        assert!(header.sgb);
+        assert_eq!(header.licensee, Licensee::New(*b"01"));
+        assert_eq!(header.cartridge_type, CartridgeType::Mbc3TimerRamBattery);
+        assert!(header.cartridge_type.has_timer());
+        assert_eq!(header.rom_size, 0x8000);
+        assert_eq!(header.ram_banks(), 4);
+        assert_eq!(header.destination, Destination::Overseas);
+        assert_eq!(header.version, 2);
+        assert!(header.logo_valid);
+        assert!(header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+
+        rom[0x104] = 0;
+        rom[0x143] = 0;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.logo_valid);
+        assert!(!header.header_checksum_valid);
+        assert_eq!(header.manufacturer, None);
+        assert_eq!(header.title, "POKEMON_GLDAAUE");
+    }
+}
@@ -1,3 +1,5 @@
+use crate::state::{StateReader, StateWriter, StrResult};
+
 pub struct Keypad {
     row0: u8,
     row1: u8,
@@ -5,7 +7,7 @@ pub struct Keypad {
     pub interrupt: u8,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum KeypadKey {
     Right,
@@ -18,6 +20,22 @@ pub enum KeypadKey {
     Start,
 }
 
+impl KeypadKey {
+    pub fn from_name(name: &str) -> Option<KeypadKey> {
+        match name.to_ascii_lowercase().as_str() {
+            "right" => Some(KeypadKey::Right),
+            "left" => Some(KeypadKey::Left),
+            "up" => Some(KeypadKey::Up),
+            "down" => Some(KeypadKey::Down),
+            "a" => Some(KeypadKey::A),
+            "b" => Some(KeypadKey::B),
+            "select" => Some(KeypadKey::Select),
+            "start" => Some(KeypadKey::Start),
+            _ => None,
+        }
+    }
+}
+
 impl Default for Keypad {
     fn default() -> Self {
         Keypad {
@@ -57,6 +75,21 @@ impl Keypad {
         self.data = (self.data & 0xF0) | new_values;
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.row0);
+        w.u8(self.row1);
+        w.u8(self.data);
+        w.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.row0 = r.u8()?;
+        self.row1 = r.u8()?;
+        self.data = r.u8()?;
+        self.interrupt = r.u8()?;
+        Ok(())
+    }
+
     pub fn keydown(&mut self, key: KeypadKey) {
         match key {
             KeypadKey::Right => self.row0 &= !(1 << 0),
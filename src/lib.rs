@@ -5,13 +5,26 @@ use std::sync::OnceLock;
 use wasm_bindgen::prelude::*;
 
 pub mod cpu;
+pub mod debugger;
+pub mod export;
 pub mod gameboy;
+#[cfg(feature = "gdb")]
+pub mod gdb;
 mod gpu;
+pub mod header;
 mod input;
 mod mbc;
 mod mmu;
 mod mode;
+pub mod movie;
+mod rewind;
 mod screen;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod screenshot;
+mod sound;
+mod state;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testrom;
 
 pub use crate::input::KeypadKey;
 
@@ -32,19 +45,29 @@ unsafe impl Sync for crate::gameboy::Gameboy {}
 /// # Safety
 ///
 /// This function is not safe due to from_raw_parts.
+/// Returns false when the ROM could not be loaded.
 #[no_mangle]
-pub unsafe extern "C" fn load_rom(bytes: *const std::ffi::c_uchar, bytes_length: usize) {
+pub unsafe extern "C" fn load_rom(
+    bytes: *const std::ffi::c_uchar,
+    bytes_length: usize,
+) -> bool {
     let bytes = std::slice::from_raw_parts(bytes, bytes_length);
     let bytes: Vec<u8> = Vec::from(bytes);
-    GAMEBOY
-        .get_or_init(|| Some(crate::gameboy::Gameboy::new(bytes.to_vec(), None)).into());
+    let gb = GAMEBOY.get_or_init(|| {
+        crate::gameboy::Gameboy::try_new(bytes.to_vec(), None)
+            .ok()
+            .into()
+    });
+    matches!(gb.lock(), Ok(locked_gb) if locked_gb.is_some())
 }
 
 #[no_mangle]
 pub extern "C" fn frame() {
     if let Some(gb) = GAMEBOY.get() {
         if let Ok(mut locked_gb) = gb.lock() {
-            locked_gb.as_mut().unwrap().frame();
+            if let Some(gb) = locked_gb.as_mut() {
+                gb.frame();
+            }
         }
     }
 }
@@ -53,7 +76,9 @@ pub extern "C" fn frame() {
 pub extern "C" fn keydown(key: KeypadKey) {
     if let Some(gb) = GAMEBOY.get() {
         if let Ok(mut locked_gb) = gb.lock() {
-            locked_gb.as_mut().unwrap().keydown(key);
+            if let Some(gb) = locked_gb.as_mut() {
+                gb.keydown(key);
+            }
         }
     }
 }
@@ -62,11 +87,45 @@ pub extern "C" fn keydown(key: KeypadKey) {
 pub extern "C" fn keyup(key: KeypadKey) {
     if let Some(gb) = GAMEBOY.get() {
         if let Ok(mut locked_gb) = gb.lock() {
-            locked_gb.as_mut().unwrap().keyup(key);
+            if let Some(gb) = locked_gb.as_mut() {
+                gb.keyup(key);
+            }
         }
     }
 }
 
+#[no_mangle]
+pub extern "C" fn set_audio_sample_rate(rate: u32) {
+    if let Some(gb) = GAMEBOY.get() {
+        if let Ok(mut locked_gb) = gb.lock() {
+            if let Some(gb) = locked_gb.as_mut() {
+                gb.set_audio_sample_rate(rate);
+            }
+        }
+    }
+}
+
+/// # Safety
+///
+/// `samples` must point to a buffer of at least `samples_length` i16 values.
+/// Returns the number of stereo frames written.
+#[no_mangle]
+pub unsafe extern "C" fn audio(samples: *mut i16, samples_length: usize) -> usize {
+    if samples.is_null() {
+        return 0;
+    }
+    let samples = std::slice::from_raw_parts_mut(samples, samples_length);
+    if let Some(gb) = GAMEBOY.get() {
+        if let Ok(mut locked_gb) = gb.lock() {
+            if let Some(gb) = locked_gb.as_mut() {
+                return gb.read_audio_i16(samples);
+            }
+        }
+    }
+
+    0
+}
+
 #[repr(C)]
 pub struct ImageBuffer {
     len: i32,
@@ -77,13 +136,15 @@ pub struct ImageBuffer {
 pub extern "C" fn image() -> ImageBuffer {
     if let Some(gb) = GAMEBOY.get() {
         if let Ok(mut locked_gb) = gb.lock() {
-            let image: &[u8] = locked_gb.as_mut().unwrap().image();
-            let data = image.as_ptr();
-            let len = image.len() as i32;
-            // std::mem::forget(image);
-            // My guess image will be dropped but let's test
+            if let Some(gb) = locked_gb.as_mut() {
+                let image: &[u8] = gb.image();
+                let data = image.as_ptr();
+                let len = image.len() as i32;
+                // std::mem::forget(image);
+                // My guess image will be dropped but let's test
 
-            return ImageBuffer { len, data };
+                return ImageBuffer { len, data };
+            }
         }
     }
 
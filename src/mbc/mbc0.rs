@@ -1,12 +1,13 @@
-pub type StrResult<T> = Result<T, &'static str>;
+use crate::gameboy::Error;
 use crate::mbc::MemoryBankController;
+use crate::state::{StateReader, StateWriter, StrResult};
 
 pub struct MBC0 {
     rom: Vec<u8>,
 }
 
 impl MBC0 {
-    pub fn new(data: Vec<u8>) -> StrResult<MBC0> {
+    pub fn new(data: Vec<u8>) -> Result<MBC0, Error> {
         Ok(MBC0 { rom: data })
     }
 }
@@ -20,4 +21,9 @@ impl MemoryBankController for MBC0 {
     }
     fn writerom(&mut self, _a: u16, _v: u8) {}
     fn writeram(&mut self, _a: u16, _v: u8) {}
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+    fn load_state(&mut self, _r: &mut StateReader) -> StrResult<()> {
+        Ok(())
+    }
 }
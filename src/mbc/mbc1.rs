@@ -1,8 +1,10 @@
 use std::io::prelude::*;
 use std::{fs, io, path};
 
-use crate::mbc::{ram_banks, rom_banks, MemoryBankController};
-pub type StrResult<T> = Result<T, &'static str>;
+use crate::gameboy::Error;
+use crate::header::CartridgeHeader;
+use crate::mbc::{save_error, MemoryBankController};
+use crate::state::{StateReader, StateWriter, StrResult};
 
 pub struct MBC1 {
     rom: Vec<u8>,
@@ -17,37 +19,29 @@ pub struct MBC1 {
 }
 
 impl MBC1 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC1> {
-        let (svpath, rambanks) = match data[0x147] {
-            0x02 => (None, ram_banks(data[0x149])),
-            0x03 => (Some(file.with_extension("gbsave")), ram_banks(data[0x149])),
-            _ => (None, 0),
-        };
-        let rombanks = rom_banks(data[0x148]);
-        let ramsize = rambanks * 0x2000;
-
-        let mut res = MBC1 {
-            rom: data,
-            ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
-            ram_on: false,
-            banking_mode: 0,
-            rombank: 1,
-            rambank: 0,
-            savepath: svpath,
-            rombanks,
-            rambanks,
-        };
+    pub fn new(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+        file: path::PathBuf,
+    ) -> Result<MBC1, Error> {
+        let mut res = MBC1::new_without_save(data, header)?;
+        if header.cartridge_type.has_battery() {
+            res.savepath = Some(file.with_extension("gbsave"));
+        }
         res.loadram().map(|_| res)
     }
 
-    #[allow(dead_code)]
-    pub fn new_without_save(data: Vec<u8>) -> StrResult<MBC1> {
-        let (svpath, rambanks) = match data[0x147] {
-            0x02 => (None, ram_banks(data[0x149])),
-            0x03 => (None, ram_banks(data[0x149])),
-            _ => (None, 0),
+    pub fn new_without_save(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+    ) -> Result<MBC1, Error> {
+        let kind = header.cartridge_type;
+        let rambanks = if kind.has_ram() {
+            header.ram_banks()
+        } else {
+            0
         };
-        let rombanks = rom_banks(data[0x148]);
+        let rombanks = header.rom_banks();
         let ramsize = rambanks * 0x2000;
 
         let res = MBC1 {
@@ -57,15 +51,14 @@ impl MBC1 {
             banking_mode: 0,
             rombank: 1,
             rambank: 0,
-            savepath: svpath,
+            savepath: None,
             rombanks,
             rambanks,
         };
-        // res.loadram().map(|_| res)
         Ok(res)
     }
 
-    fn loadram(&mut self) -> StrResult<()> {
+    fn loadram(&mut self) -> Result<(), Error> {
         match self.savepath {
             None => Ok(()),
             Some(ref savepath) => {
@@ -73,7 +66,7 @@ impl MBC1 {
                 match fs::File::open(savepath).and_then(|mut f| f.read_to_end(&mut data))
                 {
                     Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
-                    Err(_) => Err("Could not open save file"),
+                    Err(e) => Err(save_error(savepath, e)),
                     Ok(..) => {
                         self.ram = data;
                         Ok(())
@@ -97,18 +90,19 @@ impl Drop for MBC1 {
 
 impl MemoryBankController for MBC1 {
     fn readrom(&self, a: u16) -> u8 {
-        let bank = if a < 0x4000 {
-            if self.banking_mode == 0 {
-                0
-            } else {
-                self.rombank & 0xE0
-            }
-        } else {
-            self.rombank
-        };
+        let bank = self.rombank(a);
         let idx = (bank * 0x4000) | ((a as usize) & 0x3FFF);
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
+    fn rombank(&self, a: u16) -> usize {
+        if a >= 0x4000 {
+            self.rombank
+        } else if self.banking_mode == 0 {
+            0
+        } else {
+            self.rombank & 0xE0
+        }
+    }
     fn readram(&self, a: u16) -> u8 {
         if !self.ram_on {
             return 0xFF;
@@ -118,7 +112,8 @@ impl MemoryBankController for MBC1 {
         } else {
             0
         };
-        self.ram[(rambank * 0x2000) | ((a & 0x1FFF) as usize)]
+        let address = (rambank * 0x2000) | ((a & 0x1FFF) as usize);
+        *self.ram.get(address).unwrap_or(&0xFF)
     }
 
     fn writerom(&mut self, a: u16, v: u8) {
@@ -145,7 +140,7 @@ impl MemoryBankController for MBC1 {
             0x6000..=0x7FFF => {
                 self.banking_mode = v & 0x01;
             }
-            _ => panic!("Could not write to {:04X} (MBC1)", a),
+            _ => {}
         }
     }
 
@@ -163,4 +158,28 @@ impl MemoryBankController for MBC1 {
             self.ram[address] = v;
         }
     }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.bool(self.ram_on);
+        w.u8(self.banking_mode);
+        w.u32(self.rombank as u32);
+        w.u32(self.rambank as u32);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        let ram = r.vec()?;
+        if ram.len() != self.ram.len() {
+            return Err("Cartridge RAM size mismatch");
+        }
+        self.ram = ram;
+        self.ram_on = r.bool()?;
+        self.banking_mode = r.u8()?;
+        self.rombank = r.u32()? as usize;
+        self.rambank = r.u32()? as usize;
+        if self.rombank >= self.rombanks || self.rambank > 3 {
+            return Err("Invalid cartridge bank");
+        }
+        Ok(())
+    }
 }
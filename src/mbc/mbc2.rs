@@ -1,8 +1,10 @@
 use std::io::prelude::*;
 use std::{fs, io, path};
 
-use crate::mbc::{rom_banks, MemoryBankController};
-pub type StrResult<T> = Result<T, &'static str>;
+use crate::gameboy::Error;
+use crate::header::CartridgeHeader;
+use crate::mbc::{save_error, MemoryBankController};
+use crate::state::{StateReader, StateWriter, StrResult};
 
 pub struct MBC2 {
     rom: Vec<u8>,
@@ -14,26 +16,36 @@ pub struct MBC2 {
 }
 
 impl MBC2 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC2> {
-        let svpath = match data[0x147] {
-            0x05 => None,
-            0x06 => Some(file.with_extension("gbsave")),
-            _ => None,
-        };
-        let rombanks = rom_banks(data[0x148]);
+    pub fn new(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+        file: path::PathBuf,
+    ) -> Result<MBC2, Error> {
+        let mut res = MBC2::new_without_save(data, header)?;
+        if header.cartridge_type.has_battery() {
+            res.savepath = Some(file.with_extension("gbsave"));
+        }
+        res.loadram().map(|_| res)
+    }
 
-        let mut res = MBC2 {
+    pub fn new_without_save(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+    ) -> Result<MBC2, Error> {
+        let rombanks = header.rom_banks();
+
+        let res = MBC2 {
             rom: data,
             ram: vec![0; 512],
             ram_on: false,
             rombank: 1,
-            savepath: svpath,
+            savepath: None,
             rombanks,
         };
-        res.loadram().map(|_| res)
+        Ok(res)
     }
 
-    fn loadram(&mut self) -> StrResult<()> {
+    fn loadram(&mut self) -> Result<(), Error> {
         match self.savepath {
             None => Ok(()),
             Some(ref savepath) => {
@@ -41,7 +53,7 @@ impl MBC2 {
                 match fs::File::open(savepath).and_then(|mut f| f.read_to_end(&mut data))
                 {
                     Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
-                    Err(_) => Err("Could not open save file"),
+                    Err(e) => Err(save_error(savepath, e)),
                     Ok(..) => {
                         self.ram = data;
                         Ok(())
@@ -69,11 +81,18 @@ impl MemoryBankController for MBC2 {
         let idx = (bank * 0x4000) | ((a as usize) & 0x3FFF);
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
+    fn rombank(&self, a: u16) -> usize {
+        if a < 0x4000 {
+            0
+        } else {
+            self.rombank
+        }
+    }
     fn readram(&self, a: u16) -> u8 {
         if !self.ram_on {
             return 0xFF;
         }
-        self.ram[(a as usize) & 0x1FF] | 0xF0
+        self.ram.get((a as usize) & 0x1FF).unwrap_or(&0xFF) | 0xF0
     }
 
     fn writerom(&mut self, a: u16, v: u8) {
@@ -93,6 +112,28 @@ impl MemoryBankController for MBC2 {
         if !self.ram_on {
             return;
         }
-        self.ram[(a as usize) & 0x1FF] = v | 0xF0;
+        if let Some(b) = self.ram.get_mut((a as usize) & 0x1FF) {
+            *b = v | 0xF0;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.bool(self.ram_on);
+        w.u32(self.rombank as u32);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        let ram = r.vec()?;
+        if ram.len() != self.ram.len() {
+            return Err("Cartridge RAM size mismatch");
+        }
+        self.ram = ram;
+        self.ram_on = r.bool()?;
+        self.rombank = r.u32()? as usize;
+        if self.rombank >= self.rombanks {
+            return Err("Invalid cartridge bank");
+        }
+        Ok(())
     }
 }
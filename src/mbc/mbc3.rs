@@ -1,5 +1,7 @@
-use crate::mbc::{ram_banks, MemoryBankController};
-pub type StrResult<T> = Result<T, &'static str>;
+use crate::gameboy::Error;
+use crate::header::CartridgeHeader;
+use crate::mbc::{save_error, MemoryBankController};
+use crate::state::{StateReader, StateWriter, StrResult};
 
 use std::io::prelude::*;
 use std::path;
@@ -20,53 +22,32 @@ pub struct MBC3 {
 }
 
 impl MBC3 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC3> {
-        let subtype = data[0x147];
-        let svpath = match subtype {
-            0x0F | 0x10 | 0x13 => Some(file.with_extension("gbsave")),
-            _ => None,
-        };
-        let rambanks = match subtype {
-            0x10 | 0x12 | 0x13 => ram_banks(data[0x149]),
-            _ => 0,
-        };
-        let ramsize = rambanks * 0x2000;
-        let rtc = match subtype {
-            0x0F | 0x10 => Some(0),
-            _ => None,
-        };
-
-        let mut res = MBC3 {
-            rom: data,
-            ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
-            rombank: 1,
-            rambank: 0,
-            rambanks,
-            selectrtc: false,
-            ram_on: false,
-            savepath: svpath,
-            rtc_ram: [0u8; 5],
-            rtc_ram_latch: [0u8; 5],
-            rtc_zero: rtc,
-        };
+    pub fn new(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+        file: path::PathBuf,
+    ) -> Result<MBC3, Error> {
+        let mut res = MBC3::new_without_save(data, header)?;
+        if header.cartridge_type.has_battery() {
+            res.savepath = Some(file.with_extension("gbsave"));
+        }
         res.loadram().map(|_| res)
     }
 
-    pub fn new_without_save(data: Vec<u8>) -> StrResult<MBC3> {
-        let subtype = data[0x147];
-        let svpath = None;
-
-        let rambanks = match subtype {
-            0x10 | 0x12 | 0x13 => ram_banks(data[0x149]),
-            _ => 0,
+    pub fn new_without_save(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+    ) -> Result<MBC3, Error> {
+        let kind = header.cartridge_type;
+        let rambanks = if kind.has_ram() {
+            header.ram_banks()
+        } else {
+            0
         };
         let ramsize = rambanks * 0x2000;
-        let rtc = match subtype {
-            0x0F | 0x10 => Some(0),
-            _ => None,
-        };
+        let rtc = if kind.has_timer() { Some(0) } else { None };
 
-        let mut res = MBC3 {
+        let res = MBC3 {
             rom: data,
             ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
             rombank: 1,
@@ -74,33 +55,33 @@ impl MBC3 {
             rambanks,
             selectrtc: false,
             ram_on: false,
-            savepath: svpath,
+            savepath: None,
             rtc_ram: [0u8; 5],
             rtc_ram_latch: [0u8; 5],
             rtc_zero: rtc,
         };
-        res.loadram().map(|_| res)
+        Ok(res)
     }
 
-    fn loadram(&mut self) -> StrResult<()> {
+    fn loadram(&mut self) -> Result<(), Error> {
         match self.savepath {
             None => Ok(()),
             Some(ref savepath) => {
                 let mut file = match fs::File::open(savepath) {
                     Ok(f) => f,
                     Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
-                    Err(..) => return Err("Could not read existing save file"),
+                    Err(e) => return Err(save_error(savepath, e)),
                 };
                 let mut rtc_bytes = [0; 8];
                 file.read_exact(&mut rtc_bytes)
-                    .map_err(|_| "Could not read RTC")?;
+                    .map_err(|e| save_error(savepath, e))?;
                 let rtc = u64::from_be_bytes(rtc_bytes);
                 if self.rtc_zero.is_some() {
                     self.rtc_zero = Some(rtc);
                 }
                 let mut data = vec![];
                 match file.read_to_end(&mut data) {
-                    Err(..) => Err("Could not read ROM"),
+                    Err(e) => Err(save_error(savepath, e)),
                     Ok(..) => {
                         self.ram = data;
                         Ok(())
@@ -152,15 +133,14 @@ impl MBC3 {
         let mut difftime = match time::SystemTime::now().duration_since(time::UNIX_EPOCH)
         {
             Ok(t) => t.as_secs(),
-            Err(_) => {
-                panic!("System clock is set to a time before the unix epoch (1970-01-01)")
-            }
+            // The system clock is set before 1970
+            Err(_) => 0,
         };
-        difftime -= self.rtc_ram[0] as u64;
-        difftime -= (self.rtc_ram[1] as u64) * 60;
-        difftime -= (self.rtc_ram[2] as u64) * 3600;
+        difftime = difftime.saturating_sub(self.rtc_ram[0] as u64);
+        difftime = difftime.saturating_sub((self.rtc_ram[1] as u64) * 60);
+        difftime = difftime.saturating_sub((self.rtc_ram[2] as u64) * 3600);
         let days = ((self.rtc_ram[4] as u64 & 0x1) << 8) | (self.rtc_ram[3] as u64);
-        difftime -= days * 3600 * 24;
+        difftime = difftime.saturating_sub(days * 3600 * 24);
         Some(difftime)
     }
 
@@ -201,12 +181,20 @@ impl MemoryBankController for MBC3 {
         };
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
+    fn rombank(&self, a: u16) -> usize {
+        if a < 0x4000 {
+            0
+        } else {
+            self.rombank
+        }
+    }
     fn readram(&self, a: u16) -> u8 {
         if !self.ram_on {
             return 0xFF;
         }
         if !self.selectrtc && self.rambank < self.rambanks {
-            self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)]
+            let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+            *self.ram.get(address).unwrap_or(&0xFF)
         } else if self.selectrtc && self.rambank < 5 {
             self.rtc_ram_latch[self.rambank]
         } else {
@@ -227,7 +215,7 @@ impl MemoryBankController for MBC3 {
                 self.rambank = (v & 0x7) as usize;
             }
             0x6000..=0x7FFF => self.latch_rtc_reg(),
-            _ => panic!("Could not write to {:04X} (MBC3)", a),
+            _ => {}
         }
     }
     fn writeram(&mut self, a: u16, v: u8) {
@@ -235,7 +223,10 @@ impl MemoryBankController for MBC3 {
             return;
         }
         if !self.selectrtc && self.rambank < self.rambanks {
-            self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)] = v;
+            let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+            if let Some(b) = self.ram.get_mut(address) {
+                *b = v;
+            }
         } else if self.selectrtc && self.rambank < 5 {
             self.calc_rtc_reg();
             let vmask = match self.rambank {
@@ -248,4 +239,38 @@ impl MemoryBankController for MBC3 {
             self.calc_rtc_zero();
         }
     }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.u32(self.rombank as u32);
+        w.u32(self.rambank as u32);
+        w.bool(self.selectrtc);
+        w.bool(self.ram_on);
+        w.bytes(&self.rtc_ram);
+        w.bytes(&self.rtc_ram_latch);
+        w.bool(self.rtc_zero.is_some());
+        w.u64(self.rtc_zero.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        let ram = r.vec()?;
+        if ram.len() != self.ram.len() {
+            return Err("Cartridge RAM size mismatch");
+        }
+        self.ram = ram;
+        self.rombank = r.u32()? as usize;
+        self.rambank = r.u32()? as usize;
+        // Bank registers are 7 and 3 bits wide
+        if self.rombank > 0x7F || self.rambank > 7 {
+            return Err("Invalid cartridge bank");
+        }
+        self.selectrtc = r.bool()?;
+        self.ram_on = r.bool()?;
+        r.bytes(&mut self.rtc_ram)?;
+        r.bytes(&mut self.rtc_ram_latch)?;
+        let has_rtc = r.bool()?;
+        let rtc_zero = r.u64()?;
+        self.rtc_zero = if has_rtc { Some(rtc_zero) } else { None };
+        Ok(())
+    }
 }
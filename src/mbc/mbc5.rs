@@ -1,5 +1,7 @@
-use crate::mbc::{ram_banks, rom_banks, MemoryBankController};
-pub type StrResult<T> = Result<T, &'static str>;
+use crate::gameboy::Error;
+use crate::header::CartridgeHeader;
+use crate::mbc::{save_error, MemoryBankController};
+use crate::state::{StateReader, StateWriter, StrResult};
 
 use std::fs::File;
 use std::io::prelude::*;
@@ -17,40 +19,52 @@ pub struct MBC5 {
 }
 
 impl MBC5 {
-    pub fn new(data: Vec<u8>, file: path::PathBuf) -> StrResult<MBC5> {
-        let subtype = data[0x147];
-        let svpath = match subtype {
-            0x1B | 0x1E => Some(file.with_extension("gbsave")),
-            _ => None,
-        };
-        let rambanks = match subtype {
-            0x1A | 0x1B | 0x1D | 0x1E => ram_banks(data[0x149]),
-            _ => 0,
+    pub fn new(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+        file: path::PathBuf,
+    ) -> Result<MBC5, Error> {
+        let mut res = MBC5::new_without_save(data, header)?;
+        if header.cartridge_type.has_battery() {
+            res.savepath = Some(file.with_extension("gbsave"));
+        }
+        res.loadram().map(|_| res)
+    }
+
+    pub fn new_without_save(
+        data: Vec<u8>,
+        header: &CartridgeHeader,
+    ) -> Result<MBC5, Error> {
+        let kind = header.cartridge_type;
+        let rambanks = if kind.has_ram() {
+            header.ram_banks()
+        } else {
+            0
         };
         let ramsize = 0x2000 * rambanks;
-        let rombanks = rom_banks(data[0x148]);
+        let rombanks = header.rom_banks();
 
-        let mut res = MBC5 {
+        let res = MBC5 {
             rom: data,
             ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
             rombank: 1,
             rambank: 0,
             ram_on: false,
-            savepath: svpath,
+            savepath: None,
             rombanks,
             rambanks,
         };
-        res.loadram().map(|_| res)
+        Ok(res)
     }
 
-    fn loadram(&mut self) -> StrResult<()> {
+    fn loadram(&mut self) -> Result<(), Error> {
         match self.savepath {
             None => Ok(()),
             Some(ref savepath) => {
                 let mut data = vec![];
                 match File::open(savepath).and_then(|mut f| f.read_to_end(&mut data)) {
                     Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
-                    Err(_) => Err("Could not read RAM"),
+                    Err(e) => Err(save_error(savepath, e)),
                     Ok(..) => {
                         self.ram = data;
                         Ok(())
@@ -81,11 +95,19 @@ impl MemoryBankController for MBC5 {
         };
         *self.rom.get(idx).unwrap_or(&0)
     }
+    fn rombank(&self, a: u16) -> usize {
+        if a < 0x4000 {
+            0
+        } else {
+            self.rombank
+        }
+    }
     fn readram(&self, a: u16) -> u8 {
         if !self.ram_on {
             return 0;
         }
-        self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)]
+        let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+        *self.ram.get(address).unwrap_or(&0)
     }
     fn writerom(&mut self, a: u16, v: u8) {
         match a {
@@ -97,15 +119,44 @@ impl MemoryBankController for MBC5 {
                 self.rombank =
                     ((self.rombank & 0x0FF) | (((v & 0x1) as usize) << 8)) % self.rombanks
             }
-            0x4000..=0x5FFF => self.rambank = ((v & 0x0F) as usize) % self.rambanks,
+            0x4000..=0x5FFF => {
+                if self.rambanks > 0 {
+                    self.rambank = ((v & 0x0F) as usize) % self.rambanks
+                }
+            }
             0x6000..=0x7FFF => { /* ? */ }
-            _ => panic!("Could not write to {:04X} (MBC5)", a),
+            _ => {}
         }
     }
     fn writeram(&mut self, a: u16, v: u8) {
         if !self.ram_on {
             return;
         }
-        self.ram[(self.rambank * 0x2000) | ((a as usize) & 0x1FFF)] = v;
+        let address = (self.rambank * 0x2000) | ((a as usize) & 0x1FFF);
+        if let Some(b) = self.ram.get_mut(address) {
+            *b = v;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.u32(self.rombank as u32);
+        w.u32(self.rambank as u32);
+        w.bool(self.ram_on);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        let ram = r.vec()?;
+        if ram.len() != self.ram.len() {
+            return Err("Cartridge RAM size mismatch");
+        }
+        self.ram = ram;
+        self.rombank = r.u32()? as usize;
+        self.rambank = r.u32()? as usize;
+        if self.rombank >= self.rombanks || self.rambank >= self.rambanks.max(1) {
+            return Err("Invalid cartridge bank");
+        }
+        self.ram_on = r.bool()?;
+        Ok(())
     }
 }
@@ -1,5 +1,6 @@
-pub type StrResult<T> = Result<T, &'static str>;
-
+use crate::gameboy::Error;
+use crate::header::{CartridgeHeader, CartridgeType};
+use crate::state::{StateReader, StateWriter, StrResult};
 use std::path;
 
 mod mbc0;
@@ -14,105 +15,58 @@ pub trait MemoryBankController: Send {
     fn writerom(&mut self, a: u16, v: u8);
     fn writeram(&mut self, a: u16, v: u8);
 
-    fn romname(&self) -> String {
-        const TITLE_START: u16 = 0x134;
-        const CGB_FLAG: u16 = 0x143;
-
-        let title_size = match self.readrom(CGB_FLAG) & 0x80 {
-            0x80 => 11,
-            _ => 16,
-        };
-
-        let mut result = String::with_capacity(title_size as usize);
+    // Bank mapped at `a` (0x0000-0x7FFF)
+    fn rombank(&self, a: u16) -> usize {
+        (a >= 0x4000) as usize
+    }
 
-        for i in 0..title_size {
-            match self.readrom(TITLE_START + i) {
-                0 => break,
-                v => result.push(v as char),
-            }
-        }
+    // Banking registers, cartridge RAM and clock, not the ROM itself
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()>;
+}
 
-        result
-    }
+fn save_error(path: &path::Path, e: std::io::Error) -> Error {
+    Error::SaveIo(format!("{}: {}", path.display(), e))
 }
 
 pub fn get_mbc(
     data: Vec<u8>,
+    header: &CartridgeHeader,
     filepath: Option<path::PathBuf>,
-) -> StrResult<Box<dyn MemoryBankController + 'static>> {
-    if filepath.is_none() {
-        return mbc3::MBC3::new_without_save(data)
-            .map(|v| Box::new(v) as Box<dyn MemoryBankController>);
-    }
-
-    let file = filepath.unwrap();
-
-    match data[0x147] {
-        0x00 => {
-            mbc0::MBC0::new(data).map(|v| Box::new(v) as Box<dyn MemoryBankController>)
-        }
-        0x01..=0x03 => mbc1::MBC1::new(data, file)
-            .map(|v| Box::new(v) as Box<dyn MemoryBankController>),
-        0x05..=0x06 => mbc2::MBC2::new(data, file)
-            .map(|v| Box::new(v) as Box<dyn MemoryBankController>),
-        0x0F..=0x13 => mbc3::MBC3::new(data, file)
-            .map(|v| Box::new(v) as Box<dyn MemoryBankController>),
-        0x19..=0x1E => mbc5::MBC5::new(data, file)
-            .map(|v| Box::new(v) as Box<dyn MemoryBankController>),
-        _ => Err("Unsupported MBC type"),
+) -> Result<Box<dyn MemoryBankController + 'static>, Error> {
+    if data.len() < header.rom_size {
+        return Err(Error::TruncatedRom {
+            expected: header.rom_size,
+            actual: data.len(),
+        });
     }
-}
 
-fn ram_banks(v: u8) -> usize {
-    match v {
-        1 =>
-        // "Listed in various unofficial docs as 2 KiB. However, a 2 KiB RAM chip was never
-        // used in a cartridge. The source of this value is unknown."
-        // Needed by some test roms. As we only deal in whole banks, just make it 1 8KiB bank.
-        {
-            1
+    use CartridgeType::*;
+    match header.cartridge_type {
+        RomOnly => mbc0::MBC0::new(data).map(boxed),
+        Mbc1 | Mbc1Ram | Mbc1RamBattery => match filepath {
+            Some(file) => mbc1::MBC1::new(data, header, file).map(boxed),
+            None => mbc1::MBC1::new_without_save(data, header).map(boxed),
+        },
+        Mbc2 | Mbc2Battery => match filepath {
+            Some(file) => mbc2::MBC2::new(data, header, file).map(boxed),
+            None => mbc2::MBC2::new_without_save(data, header).map(boxed),
+        },
+        Mbc3TimerBattery | Mbc3TimerRamBattery | Mbc3 | Mbc3Ram | Mbc3RamBattery => {
+            match filepath {
+                Some(file) => mbc3::MBC3::new(data, header, file).map(boxed),
+                None => mbc3::MBC3::new_without_save(data, header).map(boxed),
+            }
         }
-        2 => 1,
-        3 => 4,
-        4 => 16,
-        5 => 8,
-        _ => 0,
-    }
-}
-
-fn rom_banks(v: u8) -> usize {
-    if v <= 8 {
-        2 << v
-    } else {
-        0
-    }
-}
-
-#[allow(dead_code)]
-fn check_checksum(data: &[u8]) -> StrResult<()> {
-    let mut value: u8 = 0;
-    for item in data.iter().take(0x14D).skip(0x134) {
-        value = value.wrapping_sub(*item).wrapping_sub(1);
-    }
-    match data[0x14D] == value {
-        true => Ok(()),
-        false => Err("Cartridge checksum is invalid"),
+        Mbc5 | Mbc5Ram | Mbc5RamBattery | Mbc5Rumble | Mbc5RumbleRam
+        | Mbc5RumbleRamBattery => match filepath {
+            Some(file) => mbc5::MBC5::new(data, header, file).map(boxed),
+            None => mbc5::MBC5::new_without_save(data, header).map(boxed),
+        },
+        kind => Err(Error::UnsupportedMbc(kind.code())),
     }
 }
 
-#[cfg(test)]
-mod test {
-    #[test]
-    fn checksum_zero() {
-        let mut data = vec![0; 0x150];
-        data[0x14D] = -(0x14D_i32 - 0x134_i32) as u8;
-        super::check_checksum(&data).unwrap();
-    }
-
-    #[test]
-    fn checksum_ones() {
-        let mut data = vec![1; 0x150];
-        data[0x14D] = (-(0x14D_i32 - 0x134_i32) * 2) as u8;
-        super::check_checksum(&data).unwrap();
-    }
+fn boxed<M: MemoryBankController + 'static>(mbc: M) -> Box<dyn MemoryBankController> {
+    Box::new(mbc)
 }
@@ -1,20 +1,26 @@
 mod serial;
 mod timer;
 
-use crate::gpu::Gpu;
+use crate::debugger::{Access, IllegalAccess, StopReason, Watchpoint};
+use crate::gameboy::{
+    Error, GameBoy, GameBoyAdvance, GameBoyColor, GameBoyPocket, SuperGameBoy, Target,
+};
+use crate::gpu::{self, Gpu};
+use crate::header::{CartridgeHeader, CgbSupport};
 use crate::input::Keypad;
+use crate::mbc;
 use crate::mmu::serial::Serial;
 use crate::mmu::timer::Timer;
-// use crate::sound::Sound;
-use crate::mbc;
 use crate::mode::{GbMode, GbSpeed};
+use crate::sound::Sound;
+use crate::state::{StateReader, StateWriter, StrResult};
 use std::path;
 
-pub type StrResult<T> = Result<T, &'static str>;
-
 const WRAM_SIZE: usize = 0x8000;
 const ZRAM_SIZE: usize = 0x7F;
 
+pub type AccessCallback<'a> = Box<dyn FnMut(IllegalAccess) + Send + 'a>;
+
 #[derive(PartialEq)]
 enum DMAType {
     NoDma,
@@ -32,17 +38,39 @@ pub struct MemoryManagementUnit<'a> {
     pub timer: Timer,
     pub keypad: Keypad,
     pub gpu: Gpu,
-    // pub sound: Option<Sound>,
+    pub sound: Sound,
     hdma_status: DMAType,
     hdma_src: u16,
     hdma_dst: u16,
     hdma_len: u8,
     wrambank: usize,
     pub mbc: Box<dyn mbc::MemoryBankController + 'static>,
+    pub header: CartridgeHeader,
     pub gbmode: GbMode,
     gbspeed: GbSpeed,
     speed_switch_req: bool,
     undocumented_cgb_regs: [u8; 3], // 0xFF72, 0xFF73, 0xFF75
+    model: Target,
+    boot_rom: Vec<u8>,
+    boot_rom_mapped: bool,
+    key0: u8,
+    // OAM DMA copies a byte per machine cycle. A write to 0xFF46 starts a transfer a
+    // cycle later, the one running until then goes on meanwhile.
+    dma_register: u8,
+    dma_pending: Option<u16>,
+    dma_source: u16,
+    dma_index: u8,
+    dma_active: bool,
+    // The byte on the bus, which the CPU reads outside of HRAM during a transfer
+    dma_value: u8,
+    // Drop CPU accesses to VRAM and OAM the PPU has locked, as hardware does
+    pub access_restrictions: bool,
+    pub access_callback: Option<AccessCallback<'a>>,
+    // Start of the instruction being executed, for `IllegalAccess`
+    pub instruction_pc: u16,
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hit: Option<StopReason>,
+    pub error: Option<Error>,
 }
 
 fn fill_random(slice: &mut [u8], start: u32) {
@@ -62,23 +90,35 @@ impl<'a> MemoryManagementUnit<'a> {
     pub fn new(
         data: Vec<u8>,
         file: Option<path::PathBuf>,
-    ) -> StrResult<MemoryManagementUnit<'a>> {
-        let mmu_mbc = mbc::get_mbc(data, file)?;
-
+        model: Target,
+        boot_rom: Option<Vec<u8>>,
+        seed: u32,
+    ) -> Result<MemoryManagementUnit<'a>, Error> {
+        let boot_rom = boot_rom.unwrap_or_default();
+        let boot_rom_size = if model.is_color() { 0x900 } else { 0x100 };
+        if !boot_rom.is_empty() && boot_rom.len() != boot_rom_size {
+            return Err(Error::BadBootRom {
+                expected: boot_rom_size,
+                actual: boot_rom.len(),
+            });
+        }
+        let header = CartridgeHeader::parse(&data)?;
+        let mmu_mbc = mbc::get_mbc(data, &header, file)?;
         let serial = Serial::default();
         let mut res = MemoryManagementUnit {
             wram: [0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
-            hdma: [0; 4],
             wrambank: 1,
+            hdma: [0; 4],
             inte: 0,
             intf: 0,
             serial,
             timer: Timer::default(),
             keypad: Keypad::default(),
             gpu: Gpu::new(),
-            // sound: None,
+            sound: Sound::new(),
             mbc: mmu_mbc,
+            header,
             gbmode: GbMode::Classic,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
@@ -87,57 +127,53 @@ impl<'a> MemoryManagementUnit<'a> {
             hdma_status: DMAType::NoDma,
             hdma_len: 0xFF,
             undocumented_cgb_regs: [0; 3],
+            model,
+            boot_rom_mapped: !boot_rom.is_empty(),
+            boot_rom,
+            key0: 0,
+            dma_register: 0,
+            dma_pending: None,
+            dma_source: 0,
+            dma_index: 0,
+            dma_active: false,
+            dma_value: 0xFF,
+            access_restrictions: true,
+            access_callback: None,
+            instruction_pc: 0,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            error: None,
         };
-        fill_random(&mut res.wram, 42);
-        if res.rb(0x0143) == 0xC0 {
-            return Err("This game does not work in Classic mode");
+        fill_random(&mut res.wram, seed);
+        res.determine_mode(model)?;
+        if res.boot_rom_mapped {
+            // Color models boot in CGB mode, the boot ROM picks the mode of the game
+            // through KEY0 before unmapping itself
+            if model.is_color() {
+                res.set_mode(GbMode::Color);
+            }
+        } else {
+            res.set_initial(model);
         }
-        res.set_initial();
         Ok(res)
     }
 
-    pub fn new_cgb(
-        data: Vec<u8>,
-        file: Option<path::PathBuf>,
-    ) -> StrResult<MemoryManagementUnit<'a>> {
-        let mmu_mbc = mbc::get_mbc(data, file)?;
-        let serial = Serial::default();
-        let mut res = MemoryManagementUnit {
-            wram: [0; WRAM_SIZE],
-            zram: [0; ZRAM_SIZE],
-            wrambank: 1,
-            hdma: [0; 4],
-            inte: 0,
-            intf: 0,
-            serial,
-            timer: Timer::default(),
-            keypad: Keypad::default(),
-            gpu: Gpu::new_cgb(),
-            // sound: None,
-            mbc: mmu_mbc,
-            gbmode: GbMode::Color,
-            gbspeed: GbSpeed::Single,
-            speed_switch_req: false,
-            hdma_src: 0,
-            hdma_dst: 0,
-            hdma_status: DMAType::NoDma,
-            hdma_len: 0xFF,
-            undocumented_cgb_regs: [0; 3],
-        };
-        fill_random(&mut res.wram, 42);
-        res.determine_mode();
-        res.set_initial();
-        Ok(res)
-    }
-
-    fn set_initial(&mut self) {
+    fn set_initial(&mut self, model: Target) {
+        // Where the boot ROM leaves the system counter. The CGB boot ROM takes longer
+        // for monochrome games, which is not modelled.
+        self.timer.set_system_counter(match model {
+            GameBoy | GameBoyPocket => 0xABCC,
+            SuperGameBoy => 0xD858,
+            GameBoyColor | GameBoyAdvance => 0x1EA4,
+        });
         self.wb(0xFF05, 0);
         self.wb(0xFF06, 0);
         self.wb(0xFF07, 0);
         self.wb(0xFF10, 0x80);
         self.wb(0xFF11, 0xBF);
         self.wb(0xFF12, 0xF3);
-        self.wb(0xFF14, 0xBF);
+        // The SGB boot ROM does not play the chime, so channel 1 is left off
+        self.wb(0xFF14, if model == SuperGameBoy { 0x3F } else { 0xBF });
         self.wb(0xFF16, 0x3F);
         self.wb(0xFF16, 0x3F);
         self.wb(0xFF17, 0);
@@ -164,20 +200,148 @@ impl<'a> MemoryManagementUnit<'a> {
         self.wb(0xFF4B, 0);
     }
 
-    fn determine_mode(&mut self) {
-        let mode = match self.rb(0x0143) & 0x80 {
-            0x80 => GbMode::Color,
-            _ => GbMode::ColorAsClassic,
+    fn determine_mode(&mut self, model: Target) -> Result<(), Error> {
+        let mode = match (model.is_color(), self.header.cgb) {
+            (false, CgbSupport::Only) => {
+                return Err(Error::BadHeader("this game does not work in Classic mode"))
+            }
+            (false, _) => GbMode::Classic,
+            (true, CgbSupport::None) => GbMode::ColorAsClassic,
+            (true, _) => GbMode::Color,
         };
+        self.set_mode(mode);
+        self.gpu.shades = match model {
+            GameBoy => gpu::DMG_SHADES,
+            SuperGameBoy => gpu::SGB_SHADES,
+            _ => gpu::GREY_SHADES,
+        };
+        Ok(())
+    }
+
+    fn set_mode(&mut self, mode: GbMode) {
         self.gbmode = mode;
         self.gpu.gbmode = mode;
     }
 
+    // ROM bank mapped at `address`, if it is in ROM
+    pub fn rombank(&self, address: u16) -> Option<usize> {
+        match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF if self.boot_rom_mapped => None,
+            0x0000..=0x7FFF => Some(self.mbc.rombank(address)),
+            _ => None,
+        }
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    fn unmap_boot_rom(&mut self) {
+        self.boot_rom_mapped = false;
+        if self.model.is_color() && self.key0 & 0x04 != 0 {
+            self.set_mode(GbMode::ColorAsClassic);
+            self.gpu.compat_palettes = true;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.model as u8);
+        w.bytes(&self.wram);
+        w.bytes(&self.zram);
+        w.bytes(&self.hdma);
+        w.u8(self.inte);
+        w.u8(self.intf);
+        w.u8(match self.hdma_status {
+            DMAType::NoDma => 0,
+            DMAType::Gdma => 1,
+            DMAType::Hdma => 2,
+        });
+        w.u16(self.hdma_src);
+        w.u16(self.hdma_dst);
+        w.u8(self.hdma_len);
+        w.u32(self.wrambank as u32);
+        w.u8(self.gbmode as u8);
+        w.bool(self.gbspeed == GbSpeed::Double);
+        w.bool(self.speed_switch_req);
+        w.bytes(&self.undocumented_cgb_regs);
+        w.bool(self.boot_rom_mapped);
+        w.u8(self.key0);
+        w.u8(self.dma_register);
+        w.bool(self.dma_pending.is_some());
+        w.u16(self.dma_pending.unwrap_or(0));
+        w.u16(self.dma_source);
+        w.u8(self.dma_index);
+        w.bool(self.dma_active);
+        w.u8(self.dma_value);
+
+        self.serial.save_state(w);
+        self.timer.save_state(w);
+        self.keypad.save_state(w);
+        self.gpu.save_state(w);
+        self.sound.save_state(w);
+        self.mbc.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        if r.u8()? != self.model as u8 {
+            return Err("Save state belongs to a different model");
+        }
+        r.bytes(&mut self.wram)?;
+        r.bytes(&mut self.zram)?;
+        r.bytes(&mut self.hdma)?;
+        self.inte = r.u8()?;
+        self.intf = r.u8()?;
+        self.hdma_status = match r.u8()? {
+            0 => DMAType::NoDma,
+            1 => DMAType::Gdma,
+            2 => DMAType::Hdma,
+            _ => return Err("Invalid save state"),
+        };
+        self.hdma_src = r.u16()?;
+        self.hdma_dst = r.u16()?;
+        self.hdma_len = r.u8()?;
+        self.wrambank = match r.u32()? {
+            bank @ 1..=7 => bank as usize,
+            _ => return Err("Invalid WRAM bank"),
+        };
+        self.gbmode = GbMode::from_u8(r.u8()?).ok_or("Invalid save state")?;
+        self.gbspeed = match r.bool()? {
+            true => GbSpeed::Double,
+            false => GbSpeed::Single,
+        };
+        self.speed_switch_req = r.bool()?;
+        r.bytes(&mut self.undocumented_cgb_regs)?;
+        self.boot_rom_mapped = r.bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_empty() {
+            return Err("Save state was taken while running the boot ROM");
+        }
+        self.key0 = r.u8()?;
+        self.dma_register = r.u8()?;
+        let pending = r.bool()?;
+        let source = r.u16()?;
+        self.dma_pending = if pending { Some(source) } else { None };
+        self.dma_source = r.u16()?;
+        self.dma_index = r.u8()?.min(0x9F);
+        self.dma_active = r.bool()?;
+        self.dma_value = r.u8()?;
+        self.gpu.oam_dma = self.dma_active;
+
+        self.serial.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.keypad.load_state(r)?;
+        self.gpu.load_state(r)?;
+        self.sound.load_state(r)?;
+        self.mbc.load_state(r)
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
         let cpudivider = match self.gbspeed {
             GbSpeed::Single => 1,
             GbSpeed::Double => 2,
         };
+        for _ in 0..ticks / 4 {
+            self.oamdma_cycle();
+        }
         let vramticks = self.perform_vramdma();
         let gputicks = ticks / cpudivider + vramticks;
         let cputicks = ticks + vramticks * cpudivider;
@@ -193,7 +357,7 @@ impl<'a> MemoryManagementUnit<'a> {
         self.intf |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
-        // self.sound.as_mut().map_or((), |s| s.do_cycle(gputicks));
+        self.sound.do_cycle(gputicks);
 
         self.intf |= self.serial.interrupt;
         self.serial.interrupt = 0;
@@ -202,7 +366,77 @@ impl<'a> MemoryManagementUnit<'a> {
     }
 
     pub fn rb(&mut self, address: u16) -> u8 {
+        let value = if self.dma_conflict(address) {
+            match address {
+                0xFE00..=0xFEFF => 0xFF,
+                _ => self.dma_value,
+            }
+        } else if self.gpu.locked(address) {
+            let value = match self.access_restrictions {
+                true => 0xFF,
+                false => self.read_byte(address),
+            };
+            self.illegal_access(address, Access::Read, value);
+            value
+        } else {
+            self.read_byte(address)
+        };
+        if !self.watchpoints.is_empty() {
+            self.watch(address, Access::Read, value);
+        }
+        value
+    }
+
+    pub fn wb(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.watch(address, Access::Write, value);
+        }
+        if self.dma_conflict(address) {
+            return;
+        }
+        if self.gpu.locked(address) {
+            self.illegal_access(address, Access::Write, value);
+            if self.access_restrictions {
+                return;
+            }
+        }
+        self.write_byte(address, value)
+    }
+
+    fn illegal_access(&mut self, address: u16, access: Access, value: u8) {
+        if let Some(callback) = &mut self.access_callback {
+            callback(IllegalAccess {
+                pc: self.instruction_pc,
+                address,
+                access,
+                value,
+                mode: self.gpu.mode(),
+                ly: self.gpu.ly(),
+            });
+        }
+    }
+
+    fn watch(&mut self, address: u16, access: Access, value: u8) {
+        if self.watch_hit.is_none()
+            && self.watchpoints.iter().any(|w| w.matches(address, access))
+        {
+            self.watch_hit = Some(StopReason::Watchpoint {
+                address,
+                access,
+                value,
+            });
+        }
+    }
+
+    // CPU accesses go through `rb`/`wb`, DMA uses these directly so it does not
+    // trip watchpoints
+    pub fn read_byte(&mut self, address: u16) -> u8 {
         match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF
+                if self.boot_rom_mapped && (address as usize) < self.boot_rom.len() =>
+            {
+                self.boot_rom[address as usize]
+            }
             0x0000..=0x7FFF => self.mbc.readrom(address),
             0x8000..=0x9FFF => self.gpu.rb(address),
             0xA000..=0xBFFF => self.mbc.readram(address),
@@ -215,8 +449,7 @@ impl<'a> MemoryManagementUnit<'a> {
             0xFF01..=0xFF02 => self.serial.rb(address),
             0xFF04..=0xFF07 => self.timer.rb(address),
             0xFF0F => self.intf | 0b11100000,
-            0xFF10..=0xFF3F => 0xFF,
-            // self.sound.as_mut().map_or(0xFF, |s| s.rb(address)),
+            0xFF10..=0xFF3F => self.sound.rb(address),
             0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70
                 if self.gbmode != GbMode::Color =>
             {
@@ -232,13 +465,15 @@ impl<'a> MemoryManagementUnit<'a> {
                     })
                     | (if self.speed_switch_req { 1 } else { 0 })
             }
+            0xFF46 => self.dma_register,
             0xFF40..=0xFF4F => self.gpu.rb(address),
             0xFF51..=0xFF55 => self.hdma_read(address),
             0xFF68..=0xFF6B => self.gpu.rb(address),
             0xFF70 => self.wrambank as u8,
             0xFF72..=0xFF73 => self.undocumented_cgb_regs[address as usize - 0xFF72],
             0xFF75 => self.undocumented_cgb_regs[2] | 0b10001111,
-            0xFF76..=0xFF77 => 0x00, // CGB PCM registers. Not yet implemented.
+            0xFF76 => self.sound.pcm12(),
+            0xFF77 => self.sound.pcm34(),
             0xFF80..=0xFFFE => self.zram[address as usize & 0x007F],
             0xFFFF => self.inte,
             _ => 0xFF,
@@ -249,7 +484,7 @@ impl<'a> MemoryManagementUnit<'a> {
         (self.rb(address) as u16) | ((self.rb(address + 1) as u16) << 8)
     }
 
-    pub fn wb(&mut self, address: u16, value: u8) {
+    fn write_byte(&mut self, address: u16, value: u8) {
         match address {
             0x0000..=0x7FFF => self.mbc.writerom(address, value),
             0x8000..=0x9FFF => self.gpu.wb(address, value),
@@ -264,17 +499,19 @@ impl<'a> MemoryManagementUnit<'a> {
             0xFF00 => self.keypad.wb(value),
             0xFF01..=0xFF02 => self.serial.wb(address, value),
             0xFF04..=0xFF07 => self.timer.wb(address, value),
-            0xFF10..=0xFF3F => {}
-            // self.sound.as_mut().map_or((), |s| s.wb(address, value)),
-            0xFF46 => self.oamdma(value),
+            0xFF10..=0xFF3F => self.sound.wb(address, value),
+            0xFF46 => {
+                self.dma_register = value;
+                // Sources past 0xDF00 read the WRAM echo
+                let page = if value >= 0xE0 { value - 0x20 } else { value };
+                self.dma_pending = Some((page as u16) << 8);
+            }
+            0xFF4C if self.boot_rom_mapped => self.key0 = value,
+            0xFF50 if self.boot_rom_mapped && value & 0x01 != 0 => self.unmap_boot_rom(),
             0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF6C | 0xFF70 | 0xFF76..=0xFF77
                 if self.gbmode != GbMode::Color => {}
             0xFF72..=0xFF73 | 0xFF75..=0xFF77 if self.gbmode == GbMode::Classic => {}
-            0xFF4D => {
-                if value & 0x1 == 0x1 {
-                    self.speed_switch_req = true;
-                }
-            }
+            0xFF4D => self.speed_switch_req = value & 0x1 == 0x1,
             0xFF40..=0xFF4F => self.gpu.wb(address, value),
             0xFF51..=0xFF55 => self.hdma_write(address, value),
             0xFF68..=0xFF6B => self.gpu.wb(address, value),
@@ -300,23 +537,40 @@ impl<'a> MemoryManagementUnit<'a> {
         self.wb(address + 1, (value >> 8) as u8);
     }
 
-    pub fn switch_speed(&mut self) {
-        if self.speed_switch_req {
-            if self.gbspeed == GbSpeed::Double {
-                self.gbspeed = GbSpeed::Single;
-            } else {
-                self.gbspeed = GbSpeed::Double;
-            }
+    // Returns whether a switch was requested through KEY1
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_req {
+            return false;
         }
+        self.gbspeed = match self.gbspeed {
+            GbSpeed::Single => GbSpeed::Double,
+            GbSpeed::Double => GbSpeed::Single,
+        };
         self.speed_switch_req = false;
+        true
     }
 
-    fn oamdma(&mut self, value: u8) {
-        let base = (value as u16) << 8;
-        for i in 0..0xA0 {
-            let b = self.rb(base + i);
-            self.wb(0xFE00 + i, b);
+    fn oamdma_cycle(&mut self) {
+        if self.dma_active {
+            let value = self.read_byte(self.dma_source + self.dma_index as u16);
+            self.gpu.voam[self.dma_index as usize] = value;
+            self.dma_value = value;
+            self.dma_index += 1;
+            if self.dma_index == 0xA0 {
+                self.dma_active = false;
+            }
+        }
+        if let Some(source) = self.dma_pending.take() {
+            self.dma_source = source;
+            self.dma_index = 0;
+            self.dma_active = true;
         }
+        self.gpu.oam_dma = self.dma_active;
+    }
+
+    // While a transfer runs, the CPU only reaches the registers and HRAM
+    fn dma_conflict(&self, address: u16) -> bool {
+        self.dma_active && address < 0xFF00
     }
 
     fn hdma_read(&self, a: u16) -> u8 {
@@ -330,7 +584,7 @@ impl<'a> MemoryManagementUnit<'a> {
                         0
                     }
             }
-            _ => panic!("The address {:04X} should not be handled by hdma_read", a),
+            _ => 0xFF,
         }
     }
 
@@ -350,7 +604,8 @@ impl<'a> MemoryManagementUnit<'a> {
                 let src = ((self.hdma[0] as u16) << 8) | (self.hdma[1] as u16);
                 let dst = ((self.hdma[2] as u16) << 8) | (self.hdma[3] as u16) | 0x8000;
                 if !(src <= 0x7FF0 || (0xA000..=0xDFF0).contains(&src)) {
-                    panic!("HDMA transfer with illegal start address {:04X}", src);
+                    self.error = Some(Error::IllegalDma(src));
+                    return;
                 }
 
                 self.hdma_src = src;
@@ -363,7 +618,7 @@ impl<'a> MemoryManagementUnit<'a> {
                     DMAType::Gdma
                 };
             }
-            _ => panic!("The address {:04X} should not be handled by hdma_write", a),
+            _ => {}
         };
     }
 
@@ -401,7 +656,7 @@ impl<'a> MemoryManagementUnit<'a> {
     fn perform_vramdma_row(&mut self) {
         let mmu_src = self.hdma_src;
         for j in 0..0x10 {
-            let b: u8 = self.rb(mmu_src + j);
+            let b: u8 = self.read_byte(mmu_src + j);
             self.gpu.wb(self.hdma_dst + j, b);
         }
         self.hdma_src += 0x10;
@@ -414,3 +669,116 @@ impl<'a> MemoryManagementUnit<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MemoryManagementUnit;
+    use crate::debugger::{Access, IllegalAccess};
+    use crate::gameboy::Target;
+    use crate::header::test_rom;
+    use std::sync::{Arc, Mutex};
+
+    fn mmu<'a>() -> MemoryManagementUnit<'a> {
+        let mut rom = test_rom(&[]);
+        rom[0x143] = 0x80;
+        let mut mmu =
+            MemoryManagementUnit::new(rom, None, Target::GameBoy, None, 0).unwrap();
+        for i in 0..0x200 {
+            mmu.wb(0xC000 + i, (i / 2) as u8);
+        }
+        // Keeps the PPU away from OAM
+        mmu.wb(0xFF40, 0);
+        mmu
+    }
+
+    #[test]
+    fn oam_dma_takes_160_cycles() {
+        let mut mmu = mmu();
+        mmu.wb(0xFF80, 0x12);
+        mmu.wb(0xFF46, 0xC0);
+        assert_eq!(mmu.rb(0xFF46), 0xC0);
+        // The transfer starts a cycle after the write
+        mmu.do_cycle(4);
+        for i in 0..0x9F {
+            mmu.do_cycle(4);
+            assert_eq!(mmu.rb(0xFE00), 0xFF);
+            assert_eq!(mmu.rb(0xD000), i / 2);
+            assert_eq!(mmu.rb(0xFF80), 0x12);
+            mmu.wb(0xC000, 0xAA);
+        }
+        assert_eq!(mmu.read_byte(0xC000), 0);
+        mmu.do_cycle(4);
+        for i in 0..0xA0 {
+            assert_eq!(mmu.rb(0xFE00 + i), (i / 2) as u8);
+        }
+    }
+
+    #[test]
+    fn oam_dma_restart() {
+        let mut mmu = mmu();
+        mmu.wb(0xFF46, 0xC0);
+        for _ in 0..11 {
+            mmu.do_cycle(4);
+        }
+        mmu.wb(0xFF46, 0xC1);
+        // The first transfer runs on while the second one starts
+        mmu.do_cycle(4);
+        assert_eq!(mmu.rb(0xD000), 5);
+        assert_eq!(mmu.gpu.voam[10], 5);
+        for _ in 0..0xA0 {
+            mmu.do_cycle(4);
+        }
+        for i in 0..0xA0 {
+            assert_eq!(mmu.rb(0xFE00 + i), 0x80 + (i / 2) as u8);
+        }
+    }
+
+    fn run_to_mode(mmu: &mut MemoryManagementUnit, mode: u8) {
+        while mmu.gpu.mode() != mode {
+            mmu.do_cycle(4);
+        }
+    }
+
+    #[test]
+    fn ppu_locks_vram_and_oam() {
+        let mut mmu = mmu();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        mmu.access_callback = Some(Box::new(move |a| sink.lock().unwrap().push(a)));
+        mmu.instruction_pc = 0x150;
+        mmu.wb(0xFF40, 0x91);
+
+        run_to_mode(&mut mmu, 2);
+        mmu.wb(0x8000, 0x12);
+        mmu.wb(0xFE00, 0x34);
+        assert_eq!(mmu.rb(0xFE00), 0xFF);
+        run_to_mode(&mut mmu, 3);
+        assert_eq!(mmu.rb(0x8000), 0xFF);
+        mmu.wb(0x8001, 0x56);
+        run_to_mode(&mut mmu, 0);
+        assert_eq!(mmu.rb(0x8000), 0x12);
+        assert_eq!(mmu.rb(0x8001), 0);
+        assert_eq!(mmu.rb(0xFE00), 0);
+
+        let ly = mmu.gpu.ly();
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 4);
+        assert_eq!(
+            log[3],
+            IllegalAccess {
+                pc: 0x150,
+                address: 0x8001,
+                access: Access::Write,
+                value: 0x56,
+                mode: 3,
+                ly,
+            }
+        );
+        drop(log);
+
+        mmu.access_restrictions = false;
+        run_to_mode(&mut mmu, 3);
+        mmu.wb(0x8001, 0x56);
+        assert_eq!(mmu.rb(0x8001), 0x56);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::state::{StateReader, StateWriter, StrResult};
+
 pub type SerialCallback<'a> = Box<dyn FnMut(u8) -> Option<u8> + Send + 'a>;
 
 fn noop(_: u8) -> Option<u8> {
@@ -33,7 +35,7 @@ impl<'a> Serial<'a> {
                     }
                 }
             }
-            _ => panic!("Serial does not handle address {:4X} (write)", a),
+            _ => {}
         };
     }
 
@@ -41,10 +43,23 @@ impl<'a> Serial<'a> {
         match a {
             0xFF01 => self.data,
             0xFF02 => self.control | 0b01111110,
-            _ => panic!("Serial does not handle address {:4X} (read)", a),
+            _ => 0xFF,
         }
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.data = r.u8()?;
+        self.control = r.u8()?;
+        self.interrupt = r.u8()?;
+        Ok(())
+    }
+
     pub fn set_callback(&mut self, cb: SerialCallback<'static>) {
         self.callback = cb;
     }
@@ -1,90 +1,212 @@
+// DIV is the top byte of a 16-bit counter running at the CPU clock. TIMA counts the
+// falling edges of one of its bits, ANDed with the enable bit of TAC, so resetting
+// DIV or changing TAC can increment TIMA too.
+//
+// When TIMA overflows it reads 0 for a machine cycle, then it is reloaded from TMA
+// and the interrupt is requested. Writing TIMA during the first cycle cancels the
+// reload, writes during the reload cycle are lost and TMA writes go through to TIMA.
+
+use crate::state::{StateReader, StateWriter, StrResult};
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum Reload {
+    None,
+    Overflowed,
+    Reloading,
+}
+
 pub struct Timer {
-    divider: u8,
+    system: u16,
     counter: u8,
     modulo: u8,
-    enabled: bool,
-    step: u32,
-    internalcnt: u32,
-    internaldiv: u32,
+    tac: u8,
+    reload: Reload,
     pub interrupt: u8,
 }
 
 impl Default for Timer {
     fn default() -> Self {
         Timer {
-            divider: 0,
+            system: 0,
             counter: 0,
             modulo: 0,
-            enabled: false,
-            step: 256,
-            internalcnt: 0,
-            internaldiv: 0,
+            tac: 0,
+            reload: Reload::None,
             interrupt: 0,
         }
     }
 }
 
 impl Timer {
+    pub fn set_system_counter(&mut self, value: u16) {
+        self.system = value;
+    }
+
     pub fn rb(&self, a: u16) -> u8 {
         match a {
-            0xFF04 => self.divider,
+            0xFF04 => (self.system >> 8) as u8,
             0xFF05 => self.counter,
             0xFF06 => self.modulo,
-            0xFF07 => {
-                0xF8 | (if self.enabled { 0x4 } else { 0 })
-                    | (match self.step {
-                        16 => 1,
-                        64 => 2,
-                        256 => 3,
-                        _ => 0,
-                    })
-            }
-            _ => panic!("Timer does not handler read {:4X}", a),
+            0xFF07 => 0xF8 | self.tac,
+            _ => 0xFF,
         }
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
         match a {
             0xFF04 => {
-                self.divider = 0;
-            }
-            0xFF05 => {
-                self.counter = v;
+                let input = self.input();
+                self.system = 0;
+                self.edge(input);
             }
+            0xFF05 => match self.reload {
+                Reload::None => self.counter = v,
+                Reload::Overflowed => {
+                    self.counter = v;
+                    self.reload = Reload::None;
+                }
+                Reload::Reloading => {}
+            },
             0xFF06 => {
                 self.modulo = v;
+                if self.reload == Reload::Reloading {
+                    self.counter = v;
+                }
             }
             0xFF07 => {
-                self.enabled = v & 0x4 != 0;
-                self.step = match v & 0x3 {
-                    1 => 16,
-                    2 => 64,
-                    3 => 256,
-                    _ => 1024,
-                };
+                let input = self.input();
+                self.tac = v & 0x7;
+                self.edge(input);
             }
-            _ => panic!("Timer does not handler write {:4X}", a),
+            _ => {}
         };
     }
 
-    pub fn do_cycle(&mut self, ticks: u32) {
-        self.internaldiv += ticks;
-        while self.internaldiv >= 256 {
-            self.divider = self.divider.wrapping_add(1);
-            self.internaldiv -= 256;
-        }
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.system);
+        w.u8(self.counter);
+        w.u8(self.modulo);
+        w.u8(self.tac);
+        w.u8(self.reload as u8);
+        w.u8(self.interrupt);
+    }
 
-        if self.enabled {
-            self.internalcnt += ticks;
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.system = r.u16()?;
+        self.counter = r.u8()?;
+        self.modulo = r.u8()?;
+        self.tac = r.u8()? & 0x7;
+        self.reload = match r.u8()? {
+            0 => Reload::None,
+            1 => Reload::Overflowed,
+            2 => Reload::Reloading,
+            _ => return Err("invalid timer reload state"),
+        };
+        self.interrupt = r.u8()?;
+        Ok(())
+    }
 
-            while self.internalcnt >= self.step {
-                self.counter = self.counter.wrapping_add(1);
-                if self.counter == 0 {
+    // `ticks` is a whole number of machine cycles
+    pub fn do_cycle(&mut self, ticks: u32) {
+        for _ in 0..ticks / 4 {
+            self.reload = match self.reload {
+                Reload::Overflowed => {
                     self.counter = self.modulo;
                     self.interrupt |= 0x04;
+                    Reload::Reloading
                 }
-                self.internalcnt -= self.step;
+                _ => Reload::None,
+            };
+
+            let input = self.input();
+            self.system = self.system.wrapping_add(4);
+            self.edge(input);
+        }
+    }
+
+    // The bit of the system counter TIMA follows, gated by the enable bit
+    fn input(&self) -> bool {
+        let bit = match self.tac & 0x3 {
+            1 => 3,
+            2 => 5,
+            3 => 7,
+            _ => 9,
+        };
+        self.tac & 0x4 != 0 && self.system & (1 << bit) != 0
+    }
+
+    fn edge(&mut self, before: bool) {
+        if before && !self.input() {
+            self.counter = self.counter.wrapping_add(1);
+            if self.counter == 0 {
+                self.reload = Reload::Overflowed;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Timer;
+
+    fn timer(tac: u8) -> Timer {
+        let mut timer = Timer::default();
+        timer.wb(0xFF07, tac);
+        timer
+    }
+
+    #[test]
+    fn div_and_tac_writes_increment_tima() {
+        // Bit 3 is set after 8 cycles, resetting DIV makes it fall
+        let mut t = timer(0x05);
+        t.do_cycle(8);
+        t.wb(0xFF04, 0);
+        assert_eq!(t.rb(0xFF05), 1);
+        assert_eq!(t.rb(0xFF04), 0);
+        t.do_cycle(4);
+        t.wb(0xFF04, 0);
+        assert_eq!(t.rb(0xFF05), 1);
+
+        // So does disabling the timer or selecting a bit that is clear
+        t.do_cycle(8);
+        t.wb(0xFF07, 0x01);
+        assert_eq!(t.rb(0xFF05), 2);
+        t.wb(0xFF07, 0x05);
+        t.wb(0xFF07, 0x04);
+        assert_eq!(t.rb(0xFF05), 3);
+
+        // DIV counts every 256 cycles
+        t.do_cycle(1024);
+        assert_eq!(t.rb(0xFF04), 4);
+    }
+
+    #[test]
+    fn tima_reload_is_delayed() {
+        let mut t = timer(0x05);
+        t.wb(0xFF05, 0xFF);
+        t.wb(0xFF06, 0x42);
+        t.do_cycle(16);
+        assert_eq!(t.rb(0xFF05), 0);
+        assert_eq!(t.interrupt, 0);
+        t.do_cycle(4);
+        assert_eq!(t.rb(0xFF05), 0x42);
+        assert_eq!(t.interrupt, 0x04);
+
+        // Writes to TIMA during the reload cycle are lost, TMA writes go through
+        t.wb(0xFF05, 0x10);
+        t.wb(0xFF06, 0x50);
+        assert_eq!(t.rb(0xFF05), 0x50);
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut t = timer(0x05);
+        t.wb(0xFF05, 0xFF);
+        t.wb(0xFF06, 0x42);
+        t.do_cycle(16);
+        t.wb(0xFF05, 0x10);
+        t.do_cycle(4);
+        assert_eq!(t.rb(0xFF05), 0x10);
+        assert_eq!(t.interrupt, 0);
+    }
+}
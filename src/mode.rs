@@ -5,6 +5,17 @@ pub enum GbMode {
     ColorAsClassic,
 }
 
+impl GbMode {
+    pub fn from_u8(v: u8) -> Option<GbMode> {
+        match v {
+            0 => Some(GbMode::Classic),
+            1 => Some(GbMode::Color),
+            2 => Some(GbMode::ColorAsClassic),
+            _ => None,
+        }
+    }
+}
+
 #[derive(PartialEq, Copy, Clone)]
 pub enum GbSpeed {
     Single,
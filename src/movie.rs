@@ -0,0 +1,243 @@
+// Input movies: every key press and release with the time it happened, on top of
+// the machine they started from, so playing one back reproduces the run exactly.
+//
+// Times are counted by `Gameboy::frame`, as the number of frames since the movie
+// started and the clock cycles into that frame. Cartridges with a real time clock
+// read the host clock and do not play back the same.
+
+use crate::gameboy::{Error, Target};
+use crate::input::KeypadKey;
+use crate::state::{StateReader, StateWriter};
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u16 = 1;
+
+const MODELS: [Target; 5] = [
+    Target::GameBoy,
+    Target::GameBoyPocket,
+    Target::SuperGameBoy,
+    Target::GameBoyColor,
+    Target::GameBoyAdvance,
+];
+
+const KEYS: [KeypadKey; 8] = [
+    KeypadKey::Right,
+    KeypadKey::Left,
+    KeypadKey::Up,
+    KeypadKey::Down,
+    KeypadKey::A,
+    KeypadKey::B,
+    KeypadKey::Select,
+    KeypadKey::Start,
+];
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MovieStart {
+    PowerOn,
+    // A save state, which ties the movie to the state format of this version
+    State(Vec<u8>),
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct MovieEvent {
+    pub frame: u32,
+    // Clock cycles into the frame
+    pub ticks: u32,
+    pub key: KeypadKey,
+    pub pressed: bool,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub model: Target,
+    // Seed of the random values WRAM powers on with
+    pub seed: u32,
+    pub start: MovieStart,
+    pub events: Vec<MovieEvent>,
+    // Length in frames
+    pub frames: u32,
+}
+
+impl Movie {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w.u64(self.rom_hash);
+        w.u8(MODELS.iter().position(|m| *m == self.model).unwrap() as u8);
+        w.u32(self.seed);
+        w.u32(self.frames);
+        match &self.start {
+            MovieStart::PowerOn => w.u8(0),
+            MovieStart::State(state) => {
+                w.u8(1);
+                w.vec(state);
+            }
+        }
+        w.u32(self.events.len() as u32);
+        for event in &self.events {
+            w.u32(event.frame);
+            w.u32(event.ticks);
+            w.u8(event.key as u8);
+            w.bool(event.pressed);
+        }
+        w.finish()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Movie, Error> {
+        let mut r = StateReader::raw(data);
+        let truncated = |_| Error::BadMovie("truncated");
+
+        let mut magic = [0; 4];
+        r.bytes(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            return Err(Error::BadMovie("not a movie"));
+        }
+        if r.u16().map_err(truncated)? != VERSION {
+            return Err(Error::BadMovie("unsupported version"));
+        }
+        let rom_hash = r.u64().map_err(truncated)?;
+        let model = *MODELS
+            .get(r.u8().map_err(truncated)? as usize)
+            .ok_or(Error::BadMovie("invalid model"))?;
+        let seed = r.u32().map_err(truncated)?;
+        let frames = r.u32().map_err(truncated)?;
+        let start = match r.u8().map_err(truncated)? {
+            0 => MovieStart::PowerOn,
+            1 => MovieStart::State(r.vec().map_err(truncated)?),
+            _ => return Err(Error::BadMovie("invalid start")),
+        };
+
+        let count = r.u32().map_err(truncated)?;
+        let mut events = Vec::new();
+        for _ in 0..count {
+            let frame = r.u32().map_err(truncated)?;
+            let ticks = r.u32().map_err(truncated)?;
+            let key = *KEYS
+                .get(r.u8().map_err(truncated)? as usize)
+                .ok_or(Error::BadMovie("invalid key"))?;
+            let pressed = r.bool().map_err(truncated)?;
+            events.push(MovieEvent {
+                frame,
+                ticks,
+                key,
+                pressed,
+            });
+        }
+        r.finish().map_err(|_| Error::BadMovie("trailing data"))?;
+
+        Ok(Movie {
+            rom_hash,
+            model,
+            seed,
+            start,
+            events,
+            frames,
+        })
+    }
+}
+
+// A movie being recorded or played back by a `Gameboy`
+pub(crate) struct Session {
+    movie: Movie,
+    playing: bool,
+    frame: u32,
+    // Next event to play
+    next: usize,
+}
+
+impl Session {
+    pub fn recording(movie: Movie) -> Session {
+        Session {
+            movie,
+            playing: false,
+            frame: 0,
+            next: 0,
+        }
+    }
+
+    pub fn playing(movie: Movie) -> Session {
+        Session {
+            movie,
+            playing: true,
+            frame: 0,
+            next: 0,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn record(&mut self, ticks: u32, key: KeypadKey, pressed: bool) {
+        if !self.playing {
+            self.movie.events.push(MovieEvent {
+                frame: self.frame,
+                ticks,
+                key,
+                pressed,
+            });
+        }
+    }
+
+    // Events due once `ticks` cycles of the current frame have run
+    pub fn due(&mut self, ticks: u32) -> &[MovieEvent] {
+        let start = self.next;
+        if self.playing {
+            let frame = self.frame;
+            self.next += self.movie.events[start..]
+                .iter()
+                .take_while(|e| e.frame < frame || (e.frame == frame && e.ticks <= ticks))
+                .count();
+        }
+        &self.movie.events[start..self.next]
+    }
+
+    // Counts a finished frame, returns whether playback reached the end
+    pub fn frame_done(&mut self) -> bool {
+        self.frame += 1;
+        self.playing && self.frame >= self.movie.frames
+    }
+
+    pub fn finish(mut self) -> Movie {
+        if !self.playing {
+            self.movie.frames = self.frame;
+        }
+        self.movie
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Movie, MovieEvent, MovieStart};
+    use crate::gameboy::{Error, Target};
+    use crate::input::KeypadKey;
+
+    #[test]
+    fn encode_round_trip() {
+        let mut movie = Movie {
+            rom_hash: 0x1234,
+            model: Target::SuperGameBoy,
+            seed: 7,
+            start: MovieStart::State(vec![1, 2, 3]),
+            events: vec![MovieEvent {
+                frame: 3,
+                ticks: 1200,
+                key: KeypadKey::Start,
+                pressed: true,
+            }],
+            frames: 10,
+        };
+        let data = movie.encode();
+        assert_eq!(Movie::decode(&data).unwrap(), movie);
+        assert_eq!(
+            Movie::decode(&data[..data.len() - 1]),
+            Err(Error::BadMovie("truncated"))
+        );
+        assert_eq!(Movie::decode(b"GBST"), Err(Error::BadMovie("not a movie")));
+
+        movie.start = MovieStart::PowerOn;
+        assert_eq!(Movie::decode(&movie.encode()).unwrap(), movie);
+    }
+}
@@ -0,0 +1,205 @@
+// Ring buffer of save states for stepping back in time.
+//
+// Only the newest snapshot is kept whole. Every older one is stored as the XOR with
+// the snapshot taken after it, with runs of zeroes (the bytes that did not change)
+// squeezed out, so stepping back walks the chain from the newest end and the oldest
+// entries can be dropped from the other end when over budget.
+
+use std::collections::VecDeque;
+
+pub const DEFAULT_INTERVAL: u32 = 4;
+pub const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    frames: u32,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // Counts a finished frame, returns whether a snapshot is due
+    pub fn frame_done(&mut self) -> bool {
+        self.frames += 1;
+        self.newest.is_none() || self.frames >= self.interval
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        self.frames = 0;
+        if let Some(previous) = self.newest.take() {
+            let delta = encode_delta(&previous, &state);
+            self.used += delta.len();
+            self.deltas.push_back(delta);
+            self.used -= previous.len();
+        }
+        self.used += state.len();
+        self.newest = Some(state);
+
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // The snapshot to go back to. Right after a snapshot this is the one before it,
+    // otherwise it is the snapshot the current frames started from.
+    pub fn step_back(&mut self) -> Option<Vec<u8>> {
+        if self.frames == 0 {
+            let delta = self.deltas.pop_back()?;
+            let newest = self.newest.take()?;
+            self.used -= delta.len() + newest.len();
+            let older = decode_delta(&newest, &delta);
+            self.used += older.len();
+            self.newest = Some(older);
+        }
+        self.frames = 0;
+        self.newest.clone()
+    }
+
+    pub fn snapshots(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        v |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            return v;
+        }
+        shift += 7;
+    }
+}
+
+// `older ^ newer` as (zero run, literal length, literal bytes) triples, prefixed with
+// the length of `older`
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| older[i] ^ newer.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    push_varint(&mut out, older.len());
+
+    let mut i = 0;
+    while i < older.len() {
+        let start = i;
+        while i < older.len() && xor(i) == 0 {
+            i += 1;
+        }
+        let zeroes = i - start;
+        if i == older.len() {
+            break;
+        }
+
+        let start = i;
+        // Short zero runs inside a literal cost more to split than to keep
+        while i < older.len() && (xor(i) != 0 || (i + 1 < older.len() && xor(i + 1) != 0))
+        {
+            i += 1;
+        }
+        push_varint(&mut out, zeroes);
+        push_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+fn decode_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut older = newer.to_vec();
+    older.resize(len, 0);
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literal = read_varint(delta, &mut pos);
+        for b in &mut older[i..i + literal] {
+            *b ^= delta[pos];
+            pos += 1;
+        }
+        i += literal;
+    }
+    older
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_delta, encode_delta, Rewind};
+
+    #[test]
+    fn delta_round_trip() {
+        let older: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let mut newer = older.clone();
+        newer[3] = 0xFF;
+        newer[500..510].fill(7);
+        newer.push(1);
+
+        let delta = encode_delta(&older, &newer);
+        assert!(delta.len() < 40);
+        assert_eq!(decode_delta(&newer, &delta), older);
+    }
+
+    #[test]
+    fn steps_back_and_drops_oldest() {
+        let state = |n: u8| {
+            let mut state = vec![0; 64];
+            state[0] = n;
+            state
+        };
+        let mut rewind = Rewind::new(2, 64 + 20);
+
+        for n in 0..4 {
+            assert!(rewind.frame_done());
+            rewind.push(state(n));
+            assert!(!rewind.frame_done());
+        }
+        // Each delta takes 4 bytes, the full state 64
+        assert_eq!(rewind.snapshots(), 4);
+        assert_eq!(rewind.memory_used(), 64 + 3 * 4);
+
+        // Mid-interval, the first step goes back to the newest snapshot
+        assert_eq!(rewind.step_back(), Some(state(3)));
+        assert_eq!(rewind.step_back(), Some(state(2)));
+        assert_eq!(rewind.step_back(), Some(state(1)));
+        assert_eq!(rewind.step_back(), Some(state(0)));
+        assert_eq!(rewind.step_back(), None);
+
+        let mut rewind = Rewind::new(1, 64 + 8);
+        for n in 0..5 {
+            rewind.frame_done();
+            rewind.push(state(n));
+        }
+        assert_eq!(rewind.snapshots(), 3);
+        assert_eq!(rewind.memory_used(), 64 + 2 * 4);
+    }
+}
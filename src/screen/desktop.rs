@@ -2,6 +2,7 @@ extern crate gl;
 extern crate glutin;
 extern crate libc;
 
+use crate::export;
 use crate::gameboy::Gameboy;
 use crate::input::KeypadKey;
 
@@ -10,6 +11,7 @@ use std::iter::repeat;
 use std::mem;
 use std::ptr;
 use std::str;
+use std::time::{Duration, Instant};
 
 use gl::types::*;
 use glutin::event::{ElementState, VirtualKeyCode};
@@ -78,6 +80,237 @@ pub struct Glcx {
 //     })
 // }
 
+// One frame of the LCD takes 70224 ticks of the 4 MiHz clock, about 59.73 Hz
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+
+// Frames run at once before the pacer gives up catching up
+const MAX_FRAMES_PER_WAKEUP: u32 = 4;
+
+// How much audio is kept queued ahead of the device, in frames of emulation
+const AUDIO_LATENCY_FRAMES: u32 = 3;
+
+pub trait AudioSink {
+    // `None` when there is no device to pace against
+    fn sample_rate(&self) -> Option<u32>;
+    // Stereo frames that were pushed but not played yet
+    fn queued(&self) -> usize;
+    fn push(&mut self, samples: &[f32]);
+}
+
+#[derive(Default)]
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    fn queued(&self) -> usize {
+        0
+    }
+
+    fn push(&mut self, _samples: &[f32]) {}
+}
+
+#[cfg(feature = "audio")]
+pub struct CpalSink {
+    sample_rate: u32,
+    queue: SampleQueue,
+    _stream: cpal::Stream,
+}
+
+#[cfg(feature = "audio")]
+type SampleQueue = std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<f32>>>;
+
+#[cfg(feature = "audio")]
+impl CpalSink {
+    // Logs why when there is no usable device, the caller falls back to a timer
+    pub fn new() -> Option<CpalSink> {
+        match CpalSink::open() {
+            Ok(sink) => Some(sink),
+            Err(reason) => {
+                eprintln!("Audio disabled: {}", reason);
+                None
+            }
+        }
+    }
+
+    fn open() -> Result<CpalSink, String> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use cpal::SampleFormat;
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no output device")?;
+        let config = device.default_output_config().map_err(|e| e.to_string())?;
+        let format = config.sample_format();
+        let sample_rate = config.sample_rate().0;
+        let config = config.into();
+
+        let queue = SampleQueue::default();
+        let stream = match format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+            SampleFormat::F64 => build_stream::<f64>(&device, &config, queue.clone()),
+            SampleFormat::I8 => build_stream::<i8>(&device, &config, queue.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+            SampleFormat::I32 => build_stream::<i32>(&device, &config, queue.clone()),
+            SampleFormat::U8 => build_stream::<u8>(&device, &config, queue.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+            SampleFormat::U32 => build_stream::<u32>(&device, &config, queue.clone()),
+            format => return Err(format!("unsupported sample format {}", format)),
+        }
+        .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok(CpalSink {
+            sample_rate,
+            queue,
+            _stream: stream,
+        })
+    }
+}
+
+// The queue holds f32 stereo frames, converted to whatever the device takes
+#[cfg(feature = "audio")]
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queue: SampleQueue,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::SizedSample + cpal::FromSample<f32>,
+{
+    use cpal::traits::DeviceTrait;
+
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut queue = match queue.lock() {
+                Ok(queue) => queue,
+                Err(_) => return,
+            };
+            for frame in data.chunks_mut(channels) {
+                // Play silence when emulation falls behind
+                let (left, right) = if queue.len() >= 2 {
+                    (queue.pop_front().unwrap(), queue.pop_front().unwrap())
+                } else {
+                    (0.0, 0.0)
+                };
+                if channels == 1 {
+                    frame[0] = T::from_sample((left + right) / 2.0);
+                    continue;
+                }
+                for (i, sample) in frame.iter_mut().enumerate() {
+                    *sample = match i {
+                        0 => T::from_sample(left),
+                        1 => T::from_sample(right),
+                        _ => T::EQUILIBRIUM,
+                    };
+                }
+            }
+        },
+        |err| eprintln!("Audio stream error: {}", err),
+        None,
+    )
+}
+
+#[cfg(feature = "audio")]
+impl AudioSink for CpalSink {
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.sample_rate)
+    }
+
+    fn queued(&self) -> usize {
+        self.queue.lock().map_or(0, |queue| queue.len() / 2)
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.extend(samples);
+        }
+    }
+}
+
+// Opens the default audio device, or a silent sink when there is none
+pub fn open_audio_sink() -> Box<dyn AudioSink> {
+    #[cfg(feature = "audio")]
+    if let Some(sink) = CpalSink::new() {
+        return Box::new(sink);
+    }
+
+    Box::new(NullSink)
+}
+
+// Decides when the next emulated frame is due. With an audio device, frames run
+// whenever the device is about to run out of samples, so the device clock drives the
+// emulation. Without one, frames follow a timer at the hardware refresh rate.
+pub struct FramePacer {
+    next_frame: Instant,
+}
+
+impl FramePacer {
+    pub fn new(now: Instant) -> FramePacer {
+        FramePacer { next_frame: now }
+    }
+
+    pub fn should_run(&mut self, now: Instant, sink: &dyn AudioSink) -> bool {
+        if let Some(rate) = sink.sample_rate() {
+            let target = rate as u64
+                * AUDIO_LATENCY_FRAMES as u64
+                * FRAME_DURATION.as_nanos() as u64
+                / 1_000_000_000;
+            return (sink.queued() as u64) < target;
+        }
+
+        if now < self.next_frame {
+            return false;
+        }
+        self.next_frame += FRAME_DURATION;
+        true
+    }
+
+    // Moment the pacer wants to be polled again
+    pub fn next_deadline(&self, now: Instant, sink: &dyn AudioSink) -> Instant {
+        match sink.sample_rate() {
+            Some(_) => now + FRAME_DURATION / 4,
+            None => self.next_frame.max(now),
+        }
+    }
+
+    // Runs every frame that is due and feeds the generated audio to `sink`
+    pub fn run_frames(
+        &mut self,
+        now: Instant,
+        gameboy: &mut Gameboy,
+        sink: &mut dyn AudioSink,
+    ) -> u32 {
+        let mut frames = 0;
+        let mut samples = Vec::new();
+        while frames < MAX_FRAMES_PER_WAKEUP && self.should_run(now, sink) {
+            gameboy.frame();
+            samples.clear();
+            gameboy.drain_audio_f32(&mut samples);
+            // Rewinding makes no sound, keep the device fed so it keeps the pace
+            if gameboy.is_rewinding() {
+                if let Some(rate) = sink.sample_rate() {
+                    let frame_samples = rate as f64 * FRAME_DURATION.as_secs_f64();
+                    samples.resize(frame_samples as usize * 2, 0.0);
+                }
+            }
+            sink.push(&samples);
+            frames += 1;
+        }
+
+        // When too far behind (the window was dragged or the host was suspended),
+        // skip the lost time instead of fast-forwarding through it
+        if frames == MAX_FRAMES_PER_WAKEUP && self.next_frame < now {
+            self.next_frame = now + FRAME_DURATION;
+        }
+        frames
+    }
+}
+
 pub fn process_window(
     _window: &glutin::window::Window,
     wevent: &glutin::event::WindowEvent,
@@ -92,6 +325,23 @@ pub fn process_window(
         glutin::event::WindowEvent::KeyboardInput { input, .. } => {
             if let Some(virt_keycode) = input.virtual_keycode {
                 let button = match virt_keycode {
+                    // Held down to go back in time
+                    VirtualKeyCode::R => {
+                        gameboy.set_rewinding(input.state == ElementState::Pressed);
+                        return glutin::event_loop::ControlFlow::Poll;
+                    }
+                    VirtualKeyCode::V => {
+                        if input.state == ElementState::Pressed {
+                            match export::toggle_recording(gameboy) {
+                                Ok(Some(path)) => {
+                                    println!("Recording to {}", path.display())
+                                }
+                                Ok(None) => println!("Recording stopped"),
+                                Err(e) => eprintln!("Recording failed: {}", e),
+                            }
+                        }
+                        return glutin::event_loop::ControlFlow::Poll;
+                    }
                     VirtualKeyCode::A => KeypadKey::A,
                     VirtualKeyCode::B => KeypadKey::B,
                     VirtualKeyCode::Z => KeypadKey::Select,
@@ -320,3 +570,78 @@ impl Glcx {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{AudioSink, FramePacer, NullSink, FRAME_DURATION};
+    use crate::gameboy::Gameboy;
+    use crate::header::test_rom;
+    use std::time::Instant;
+
+    struct FakeSink {
+        queued: usize,
+    }
+
+    impl AudioSink for FakeSink {
+        fn sample_rate(&self) -> Option<u32> {
+            Some(48000)
+        }
+
+        fn queued(&self) -> usize {
+            self.queued
+        }
+
+        fn push(&mut self, samples: &[f32]) {
+            self.queued += samples.len() / 2;
+        }
+    }
+
+    fn gameboy() -> Gameboy {
+        Gameboy::new(test_rom(&[]), None)
+    }
+
+    #[test]
+    fn timer_pacing_without_audio() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(start);
+        let mut gameboy = gameboy();
+        let mut sink = NullSink;
+
+        assert_eq!(pacer.run_frames(start, &mut gameboy, &mut sink), 1);
+        assert_eq!(pacer.run_frames(start, &mut gameboy, &mut sink), 0);
+        assert_eq!(pacer.next_deadline(start, &sink), start + FRAME_DURATION);
+
+        let later = start + FRAME_DURATION * 3;
+        assert_eq!(pacer.run_frames(later, &mut gameboy, &mut sink), 3);
+        assert_eq!(pacer.run_frames(later, &mut gameboy, &mut sink), 0);
+    }
+
+    #[test]
+    fn timer_pacing_skips_lost_time() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(start);
+        let mut gameboy = gameboy();
+        let mut sink = NullSink;
+
+        let later = start + FRAME_DURATION * 100;
+        assert_eq!(pacer.run_frames(later, &mut gameboy, &mut sink), 4);
+        assert_eq!(pacer.run_frames(later, &mut gameboy, &mut sink), 0);
+        assert_eq!(pacer.next_deadline(later, &sink), later + FRAME_DURATION);
+    }
+
+    #[test]
+    fn audio_pacing_follows_fill_level() {
+        let now = Instant::now();
+        let mut pacer = FramePacer::new(now);
+        let mut gameboy = gameboy();
+        gameboy.set_audio_sample_rate(48000);
+
+        let mut sink = FakeSink { queued: 0 };
+        let frames = pacer.run_frames(now, &mut gameboy, &mut sink);
+        assert!(frames >= 3);
+        assert!(sink.queued >= 2400);
+
+        let mut full = FakeSink { queued: 4800 };
+        assert_eq!(pacer.run_frames(now, &mut gameboy, &mut full), 0);
+    }
+}
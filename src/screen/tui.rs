@@ -1,3 +1,4 @@
+use crate::export;
 use crate::gameboy::Gameboy;
 use crate::input::KeypadKey;
 use std::env;
@@ -140,6 +141,13 @@ fn run_app<B: Backend>(
                                 //         self.split_percent += 10;
                                 //     }
                                 // }
+                                // The screen is taken over, errors can't be shown
+                                ('v', true) | ('V', true) => {
+                                    let _ = export::toggle_recording(&mut gameboy);
+                                }
+                                ('r', pressed) | ('R', pressed) => {
+                                    gameboy.set_rewinding(pressed);
+                                }
                                 ('a', true) | ('A', true) => {
                                     gameboy.keydown(KeypadKey::A);
                                 }
@@ -360,6 +368,7 @@ fn ui(f: &mut Frame<'_>, app: &mut App) {
             Line::from("Key x/X: start"),
             // Line::from("H/L: resize splits"),
             Line::from("o: scale image"),
+            Line::from("v: start/stop recording"),
             Line::from(format!(
                 "i: cycle image protocols (current: {:?})",
                 app.picker.protocol_type()
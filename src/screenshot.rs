@@ -0,0 +1,91 @@
+// PNG screenshots and comparisons against reference images. Images are RGBA at the
+// size of the screen, like `Gameboy::image`.
+
+use crate::gameboy::Error;
+use crate::gpu::{HEIGHT, WIDTH};
+use image::{ImageFormat, RgbaImage};
+use std::io::Cursor;
+
+const MISMATCH: [u8; 4] = [0xFF, 0x00, 0x00, 0xFF];
+
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    // Pixels whose color differs, alpha is ignored
+    pub mismatches: usize,
+    // Mismatches in red over a faded copy of the image
+    pub diff: Vec<u8>,
+}
+
+impl Comparison {
+    pub fn matches(&self) -> bool {
+        self.mismatches == 0
+    }
+
+    pub fn diff_png(&self) -> Vec<u8> {
+        encode_png(&self.diff)
+    }
+}
+
+pub fn compare(image: &[u8], reference: &[u8]) -> Comparison {
+    let mut diff = Vec::with_capacity(image.len());
+    let mut mismatches = 0;
+    for (a, b) in image.chunks_exact(4).zip(reference.chunks_exact(4)) {
+        if a[..3] == b[..3] {
+            let luma = (a[0] as u32 * 3 + a[1] as u32 * 6 + a[2] as u32) / 10;
+            let faded = (0x80 + luma / 2) as u8;
+            diff.extend_from_slice(&[faded, faded, faded, 0xFF]);
+        } else {
+            mismatches += 1;
+            diff.extend_from_slice(&MISMATCH);
+        }
+    }
+    Comparison { mismatches, diff }
+}
+
+pub fn encode_png(rgba: &[u8]) -> Vec<u8> {
+    let image = RgbaImage::from_raw(WIDTH as u32, HEIGHT as u32, rgba.to_vec())
+        .expect("image is not the size of the screen");
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageFormat::Png)
+        .expect("encoding a PNG in memory");
+    png.into_inner()
+}
+
+// Decodes to RGBA, the image has to be the size of the screen
+pub fn decode_png(png: &[u8]) -> Result<Vec<u8>, Error> {
+    let image = image::load_from_memory_with_format(png, ImageFormat::Png)
+        .map_err(|e| Error::BadImage(e.to_string()))?
+        .into_rgba8();
+    if image.dimensions() != (WIDTH as u32, HEIGHT as u32) {
+        return Err(Error::BadImage(format!(
+            "{}x{} is not the size of the screen",
+            image.width(),
+            image.height()
+        )));
+    }
+    Ok(image.into_raw())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, decode_png, encode_png};
+    use crate::gpu::{HEIGHT, WIDTH};
+
+    #[test]
+    fn round_trip_and_diff() {
+        let mut image = vec![0xFF; WIDTH * HEIGHT * 4];
+        image[0..4].copy_from_slice(&[0x0F, 0x38, 0x0F, 0xFF]);
+        assert_eq!(decode_png(&encode_png(&image)).unwrap(), image);
+
+        let mut other = image.clone();
+        other[4..8].copy_from_slice(&[0x9B, 0xBC, 0x0F, 0x00]);
+        other[8 + 3] = 0;
+        let comparison = compare(&other, &image);
+        assert_eq!(comparison.mismatches, 1);
+        assert_eq!(&comparison.diff[4..8], &[0xFF, 0x00, 0x00, 0xFF]);
+        assert_eq!(&comparison.diff[8..12], &[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        assert!(decode_png(b"not a png").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+// Band-limited synthesis of amplitude steps, in the spirit of blip_buf.
+//
+// Channels only report when their output changes (a delta at a given clock). Every
+// delta is spread over a few output samples with a windowed-sinc step response, which
+// resamples the 4 MiHz signal to the output rate without aliasing.
+
+const PHASE_BITS: u32 = 5;
+const PHASES: usize = 1 << PHASE_BITS;
+const HALF_WIDTH: usize = 8;
+const WIDTH: usize = HALF_WIDTH * 2;
+const FRAC_BITS: u32 = 32;
+
+pub struct BlipBuffer {
+    clock_rate: u64,
+    sample_rate: u32,
+    factor: u64,
+    offset: u64,
+    max_samples: usize,
+    buffer: Vec<f32>,
+    integrator: f32,
+    kernel: Box<[[f32; WIDTH]; PHASES]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> BlipBuffer {
+        let mut blip = BlipBuffer {
+            clock_rate: clock_rate as u64,
+            sample_rate,
+            factor: 0,
+            offset: 0,
+            max_samples: 0,
+            buffer: Vec::new(),
+            integrator: 0.0,
+            kernel: Box::new([[0.0; WIDTH]; PHASES]),
+        };
+        blip.build_kernel();
+        blip.set_sample_rate(sample_rate);
+        blip
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+        self.factor = ((self.sample_rate as u64) << FRAC_BITS) / self.clock_rate;
+        // Keep at most one second of samples around when nobody reads them
+        self.max_samples = self.sample_rate as usize;
+        self.clear();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn clear(&mut self) {
+        self.offset = 0;
+        self.integrator = 0.0;
+        self.buffer.clear();
+    }
+
+    // Windowed sinc with a cutoff slightly below the output Nyquist frequency. Every
+    // phase is normalized so a step of 1.0 settles exactly at 1.0.
+    fn build_kernel(&mut self) {
+        const CUTOFF: f64 = 0.45;
+        for phase in 0..PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            let mut taps = [0.0f64; WIDTH];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = k as f64 - (HALF_WIDTH as f64 - 1.0) - frac;
+                let sinc = if x == 0.0 {
+                    2.0 * CUTOFF
+                } else {
+                    (2.0 * std::f64::consts::PI * CUTOFF * x).sin()
+                        / (std::f64::consts::PI * x)
+                };
+                let w = (x + HALF_WIDTH as f64) / WIDTH as f64;
+                let window = 0.42 - 0.5 * (2.0 * std::f64::consts::PI * w).cos()
+                    + 0.08 * (4.0 * std::f64::consts::PI * w).cos();
+                *tap = sinc * window;
+                sum += *tap;
+            }
+            for (k, tap) in self.kernel[phase].iter_mut().zip(taps) {
+                *k = (tap / sum) as f32;
+            }
+        }
+    }
+
+    // Adds an amplitude change at `time` clocks after the end of the last frame
+    pub fn add_delta(&mut self, time: u32, delta: f32) {
+        let position = self.offset + time as u64 * self.factor;
+        let index = (position >> FRAC_BITS) as usize;
+        let phase = ((position >> (FRAC_BITS - PHASE_BITS)) as usize) & (PHASES - 1);
+
+        if self.buffer.len() < index + WIDTH {
+            self.buffer.resize(index + WIDTH, 0.0);
+        }
+        for (k, tap) in self.kernel[phase].iter().enumerate() {
+            self.buffer[index + k] += delta * tap;
+        }
+    }
+
+    // Marks `clocks` clocks as elapsed, making the samples they cover readable
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.offset += clocks as u64 * self.factor;
+        let available = self.samples_available();
+        if self.buffer.len() < available + WIDTH {
+            self.buffer.resize(available + WIDTH, 0.0);
+        }
+        if available > self.max_samples {
+            self.skip(available - self.max_samples);
+        }
+    }
+
+    pub fn samples_available(&self) -> usize {
+        (self.offset >> FRAC_BITS) as usize
+    }
+
+    fn skip(&mut self, count: usize) {
+        for v in &self.buffer[..count] {
+            self.integrator += v;
+        }
+        self.remove(count);
+    }
+
+    fn remove(&mut self, count: usize) {
+        self.buffer.drain(..count);
+        self.buffer.resize(self.buffer.len() + count, 0.0);
+        self.offset -= (count as u64) << FRAC_BITS;
+    }
+
+    // Reads up to `out.len()` samples, writing them at every `stride`th position
+    pub fn read_samples(&mut self, out: &mut [f32], stride: usize) -> usize {
+        let count = self.samples_available().min(out.len().div_ceil(stride));
+        for i in 0..count {
+            self.integrator += self.buffer[i];
+            out[i * stride] = self.integrator;
+        }
+        self.remove(count);
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BlipBuffer;
+
+    #[test]
+    fn step_settles_at_amplitude() {
+        let mut blip = BlipBuffer::new(4194304, 48000);
+        blip.add_delta(1000, 1.0);
+        blip.end_frame(4194304 / 64);
+
+        let mut out = vec![0.0; 1000];
+        let count = blip.read_samples(&mut out, 1);
+        assert_eq!(count, 750);
+        assert_eq!(out[0], 0.0);
+        assert!((out[count - 1] - 1.0).abs() < 1e-4);
+        assert!(out.iter().take(count).all(|v| *v < 1.2));
+    }
+
+    #[test]
+    fn keeps_at_most_one_second() {
+        let mut blip = BlipBuffer::new(4194304, 48000);
+        for _ in 0..3 {
+            blip.end_frame(4194304);
+        }
+        assert_eq!(blip.samples_available(), 48000);
+    }
+}
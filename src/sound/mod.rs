@@ -0,0 +1,1211 @@
+mod blip;
+
+use crate::sound::blip::BlipBuffer;
+use crate::state::{StateReader, StateWriter, StrResult};
+
+// The APU is clocked in 4 MiHz ticks, also when the CPU runs in double speed
+pub const CLOCK_RATE: u32 = 4194304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+const WAVE_PATTERN: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// The frame sequencer runs at 512 Hz, which is every 8192 ticks of the 4 MiHz clock
+const FRAME_SEQUENCER_TICKS: u32 = 8192;
+
+// Bits that always read back as 1, indexed by address - 0xFF10
+const READ_MASK: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+struct LengthCounter {
+    enabled: bool,
+    value: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.enabled = r.bool()?;
+        self.value = r.u16()?;
+        Ok(())
+    }
+
+    fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            value: 0,
+            max,
+        }
+    }
+
+    fn load(&mut self, v: u16) {
+        self.value = self.max - v;
+    }
+
+    // Returns true when the counter expired and the channel has to be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.value > 0 {
+            self.value -= 1;
+            return self.value == 0;
+        }
+        false
+    }
+
+    // Handles NRx4 writes. `extra_clock` is set when the next frame sequencer step
+    // does not clock the length counters, in which case enabling the counter clocks
+    // it once right away. Returns true when the channel has to be disabled.
+    fn write_control(&mut self, v: u8, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = v & 0x40 == 0x40;
+        let mut disable = false;
+        if !was_enabled && self.enabled && extra_clock && self.value > 0 {
+            self.value -= 1;
+            disable = self.value == 0 && v & 0x80 == 0;
+        }
+        if v & 0x80 == 0x80 && self.value == 0 {
+            self.value = self.max;
+            if self.enabled && extra_clock {
+                self.value -= 1;
+            }
+        }
+        disable
+    }
+}
+
+struct VolumeEnvelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl VolumeEnvelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.register = r.u8()?;
+        self.volume = r.u8()?;
+        self.timer = r.u8()?;
+        Ok(())
+    }
+
+    fn new() -> VolumeEnvelope {
+        VolumeEnvelope {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = match self.period() {
+            0 => 8,
+            n => n,
+        };
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        if self.register & 0x08 == 0x08 {
+            if self.volume < 15 {
+                self.volume += 1;
+            }
+        } else if self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+struct Sweep {
+    register: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+    negate_used: bool,
+}
+
+impl Sweep {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.bool(self.enabled);
+        w.u16(self.shadow);
+        w.u8(self.timer);
+        w.bool(self.negate_used);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.register = r.u8()?;
+        self.enabled = r.bool()?;
+        self.shadow = r.u16()?;
+        self.timer = r.u8()?;
+        self.negate_used = r.bool()?;
+        Ok(())
+    }
+
+    fn new() -> Sweep {
+        Sweep {
+            register: 0,
+            enabled: false,
+            shadow: 0,
+            timer: 0,
+            negate_used: false,
+        }
+    }
+
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn negate(&self) -> bool {
+        self.register & 0x08 == 0x08
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = match self.period() {
+            0 => 8,
+            n => n,
+        };
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.negate() {
+            self.negate_used = true;
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    // Returns false if the frequency overflowed and the channel has to be disabled
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow = frequency;
+        self.negate_used = false;
+        self.reload_timer();
+        self.enabled = self.period() != 0 || self.shift() != 0;
+        !(self.shift() != 0 && self.calculate() > 2047)
+    }
+
+    // Clearing the negate bit after a negated calculation disables the channel
+    fn write(&mut self, v: u8) -> bool {
+        let was_negate = self.negate();
+        self.register = v & 0x7F;
+        !(was_negate && !self.negate() && self.negate_used)
+    }
+
+    // Returns the new frequency (if any) and whether the channel is still enabled
+    fn clock(&mut self) -> (Option<u16>, bool) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return (None, true);
+        }
+        self.reload_timer();
+        if !self.enabled || self.period() == 0 {
+            return (None, true);
+        }
+
+        let frequency = self.calculate();
+        if frequency > 2047 {
+            return (None, false);
+        }
+        if self.shift() == 0 {
+            return (None, true);
+        }
+        self.shadow = frequency;
+        (Some(frequency), self.calculate() <= 2047)
+    }
+}
+
+struct SquareChannel {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: VolumeEnvelope,
+    sweep: Option<Sweep>,
+}
+
+impl SquareChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.duty);
+        w.u8(self.duty_step);
+        w.u16(self.frequency);
+        w.u32(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        if let Some(ref sweep) = self.sweep {
+            sweep.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.enabled = r.bool()?;
+        self.duty = r.u8()?;
+        self.duty_step = r.u8()?;
+        self.frequency = r.u16()?;
+        self.timer = r.u32()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        if let Some(ref mut sweep) = self.sweep {
+            sweep.load_state(r)?;
+        }
+        Ok(())
+    }
+
+    fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 8192,
+            length: LengthCounter::new(64),
+            envelope: VolumeEnvelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn rb(&self, a: u16) -> u8 {
+        match a {
+            0 => self.sweep.as_ref().map_or(0, |s| s.register),
+            1 => self.duty << 6,
+            2 => self.envelope.register,
+            3 => 0,
+            _ => {
+                if self.length.enabled {
+                    0x40
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    fn wb(&mut self, a: u16, v: u8, extra_clock: bool) {
+        match a {
+            0 => {
+                if let Some(ref mut sweep) = self.sweep {
+                    if !sweep.write(v) {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = v >> 6;
+                self.length.load((v & 0x3F) as u16);
+            }
+            2 => {
+                self.envelope.register = v;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | v as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | (((v & 0x07) as u16) << 8);
+                if self.length.write_control(v, extra_clock) {
+                    self.enabled = false;
+                }
+                if v & 0x80 == 0x80 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(ref mut sweep) = self.sweep {
+            if !sweep.trigger(self.frequency) {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if let Some(ref mut sweep) = self.sweep {
+            let (frequency, enabled) = sweep.clock();
+            if let Some(f) = frequency {
+                self.frequency = f;
+            }
+            if !enabled {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        WAVE_PATTERN[self.duty as usize][self.duty_step as usize] * self.envelope.volume
+    }
+}
+
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,
+    length: LengthCounter,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.u8(self.volume_code);
+        w.u16(self.frequency);
+        w.u32(self.timer);
+        w.u8(self.position);
+        w.u8(self.sample);
+        self.length.save_state(w);
+        w.bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.volume_code = r.u8()?;
+        self.frequency = r.u16()?;
+        self.timer = r.u32()?;
+        self.position = r.u8()?;
+        self.sample = r.u8()?;
+        self.length.load_state(r)?;
+        r.bytes(&mut self.wave_ram)
+    }
+
+    fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 4096,
+            position: 0,
+            sample: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [0; 16],
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn rb(&self, a: u16) -> u8 {
+        match a {
+            0 => {
+                if self.dac_enabled {
+                    0x80
+                } else {
+                    0
+                }
+            }
+            1 => 0,
+            2 => self.volume_code << 5,
+            3 => 0,
+            _ => {
+                if self.length.enabled {
+                    0x40
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    fn wb(&mut self, a: u16, v: u8, extra_clock: bool) {
+        match a {
+            0 => {
+                self.dac_enabled = v & 0x80 == 0x80;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(v as u16),
+            2 => self.volume_code = (v >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | v as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | (((v & 0x07) as u16) << 8);
+                if self.length.write_control(v, extra_clock) {
+                    self.enabled = false;
+                }
+                if v & 0x80 == 0x80 {
+                    self.enabled = self.dac_enabled;
+                    // The first sample is fetched with a small delay after a trigger
+                    self.timer = self.period() + 6;
+                    self.position = 0;
+                }
+            }
+        }
+    }
+
+    // While the channel is playing, wave RAM accesses go to the byte being played
+    fn wave_index(&self, a: u16) -> usize {
+        if self.enabled {
+            (self.position >> 1) as usize
+        } else {
+            (a & 0x0F) as usize
+        }
+    }
+
+    fn read_wave(&self, a: u16) -> u8 {
+        self.wave_ram[self.wave_index(a)]
+    }
+
+    fn write_wave(&mut self, a: u16, v: u8) {
+        let index = self.wave_index(a);
+        self.wave_ram[index] = v;
+    }
+
+    fn tick(&mut self) {
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+            let byte = self.wave_ram[(self.position >> 1) as usize];
+            self.sample = if self.position & 0x01 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            n => self.sample >> (n - 1),
+        }
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+    register: u8,
+    lfsr: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: VolumeEnvelope,
+}
+
+impl NoiseChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.register);
+        w.u16(self.lfsr);
+        w.u32(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.enabled = r.bool()?;
+        self.register = r.u8()?;
+        self.lfsr = r.u16()?;
+        self.timer = r.u32()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)
+    }
+
+    fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            register: 0,
+            lfsr: 0x7FFF,
+            timer: 8,
+            length: LengthCounter::new(64),
+            envelope: VolumeEnvelope::new(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[(self.register & 0x07) as usize] << (self.register >> 4)
+    }
+
+    fn rb(&self, a: u16) -> u8 {
+        match a {
+            0 | 1 => 0,
+            2 => self.envelope.register,
+            3 => self.register,
+            _ => {
+                if self.length.enabled {
+                    0x40
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    fn wb(&mut self, a: u16, v: u8, extra_clock: bool) {
+        match a {
+            0 => {}
+            1 => self.length.load((v & 0x3F) as u16),
+            2 => {
+                self.envelope.register = v;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.register = v,
+            _ => {
+                if self.length.write_control(v, extra_clock) {
+                    self.enabled = false;
+                }
+                if v & 0x80 == 0x80 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.lfsr = 0x7FFF;
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        // Clock shifts 14 and 15 leave the LFSR without clocks
+        if self.register >> 4 >= 14 {
+            return;
+        }
+        let bit = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if self.register & 0x08 == 0x08 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 == 0x01 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+}
+
+pub struct Sound {
+    on: bool,
+    frame_step: u8,
+    frame_timer: u32,
+    volume: u8,
+    panning: u8,
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+    time: u32,
+    mix_dirty: bool,
+    last_digital: [u8; 4],
+    output: StereoBuffer,
+    stems: Option<Box<[StereoBuffer; 4]>>,
+    capture: Option<Box<StereoBuffer>>,
+}
+
+impl Default for Sound {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sound {
+    pub fn new() -> Sound {
+        Sound {
+            on: true,
+            frame_step: 0,
+            frame_timer: FRAME_SEQUENCER_TICKS,
+            volume: 0,
+            panning: 0,
+            channel1: SquareChannel::new(true),
+            channel2: SquareChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            time: 0,
+            mix_dirty: true,
+            last_digital: [0; 4],
+            output: StereoBuffer::new(DEFAULT_SAMPLE_RATE),
+            stems: None,
+            capture: None,
+        }
+    }
+
+    // Only the emulated hardware is saved. The output buffers keep playing from where
+    // they are, so loading a state does not click.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.on);
+        w.u8(self.frame_step);
+        w.u32(self.frame_timer);
+        w.u8(self.volume);
+        w.u8(self.panning);
+        self.channel1.save_state(w);
+        self.channel2.save_state(w);
+        self.channel3.save_state(w);
+        self.channel4.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StrResult<()> {
+        self.on = r.bool()?;
+        self.frame_step = r.u8()?;
+        self.frame_timer = r.u32()?;
+        self.volume = r.u8()?;
+        self.panning = r.u8()?;
+        self.channel1.load_state(r)?;
+        self.channel2.load_state(r)?;
+        self.channel3.load_state(r)?;
+        self.channel4.load_state(r)?;
+        self.mix_dirty = true;
+        Ok(())
+    }
+
+    pub fn rb(&mut self, a: u16) -> u8 {
+        let value = match a {
+            0xFF10..=0xFF14 => self.channel1.rb(a - 0xFF10),
+            0xFF16..=0xFF19 => self.channel2.rb(a - 0xFF15),
+            0xFF1A..=0xFF1E => self.channel3.rb(a - 0xFF1A),
+            0xFF20..=0xFF23 => self.channel4.rb(a - 0xFF1F),
+            0xFF24 => self.volume,
+            0xFF25 => self.panning,
+            0xFF26 => {
+                (if self.on { 0x80 } else { 0 })
+                    | (if self.channel4.enabled { 0x08 } else { 0 })
+                    | (if self.channel3.enabled { 0x04 } else { 0 })
+                    | (if self.channel2.enabled { 0x02 } else { 0 })
+                    | (if self.channel1.enabled { 0x01 } else { 0 })
+            }
+            0xFF30..=0xFF3F => return self.channel3.read_wave(a),
+            _ => 0,
+        };
+        match a {
+            0xFF10..=0xFF26 => value | READ_MASK[(a - 0xFF10) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn wb(&mut self, a: u16, v: u8) {
+        self.mix_dirty = true;
+        if a == 0xFF26 {
+            self.write_power(v);
+            return;
+        }
+        if let 0xFF30..=0xFF3F = a {
+            self.channel3.write_wave(a, v);
+            return;
+        }
+        if !self.on {
+            return;
+        }
+
+        let extra_clock = self.frame_step & 0x01 == 0x01;
+        match a {
+            0xFF10..=0xFF14 => self.channel1.wb(a - 0xFF10, v, extra_clock),
+            0xFF16..=0xFF19 => self.channel2.wb(a - 0xFF15, v, extra_clock),
+            0xFF1A..=0xFF1E => self.channel3.wb(a - 0xFF1A, v, extra_clock),
+            0xFF20..=0xFF23 => self.channel4.wb(a - 0xFF1F, v, extra_clock),
+            0xFF24 => self.volume = v,
+            0xFF25 => self.panning = v,
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, v: u8) {
+        let on = v & 0x80 == 0x80;
+        if self.on && !on {
+            // Powering off clears every register except wave RAM
+            let wave_ram = self.channel3.wave_ram;
+            self.channel1 = SquareChannel::new(true);
+            self.channel2 = SquareChannel::new(false);
+            self.channel3 = WaveChannel::new();
+            self.channel3.wave_ram = wave_ram;
+            self.channel4 = NoiseChannel::new();
+            self.volume = 0;
+            self.panning = 0;
+        }
+        if !self.on && on {
+            self.frame_step = 0;
+            self.frame_timer = FRAME_SEQUENCER_TICKS;
+        }
+        self.on = on;
+    }
+
+    pub fn do_cycle(&mut self, ticks: u32) {
+        if !self.on {
+            self.update_output();
+            self.time += ticks;
+        } else {
+            for _ in 0..ticks {
+                self.frame_timer -= 1;
+                if self.frame_timer == 0 {
+                    self.frame_timer = FRAME_SEQUENCER_TICKS;
+                    self.step_frame_sequencer();
+                }
+
+                self.channel1.tick();
+                self.channel2.tick();
+                self.channel3.tick();
+                self.channel4.tick();
+
+                self.update_output();
+                self.time += 1;
+            }
+        }
+
+        if self.time >= CLOCK_RATE {
+            self.end_frame();
+        }
+    }
+
+    fn update_output(&mut self) {
+        let digital = self.digital_outputs();
+        if !self.mix_dirty && digital == self.last_digital {
+            return;
+        }
+        self.mix_dirty = false;
+        self.last_digital = digital;
+
+        let channels = self.channel_mix();
+        let mut mix = (0.0, 0.0);
+        for (left, right) in channels {
+            mix.0 += left;
+            mix.1 += right;
+        }
+        self.output.set_level(self.time, mix);
+        if let Some(ref mut capture) = self.capture {
+            capture.set_level(self.time, mix);
+        }
+        if let Some(ref mut stems) = self.stems {
+            for (stem, level) in stems.iter_mut().zip(channels) {
+                stem.set_level(self.time, level);
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.output.end_frame(self.time);
+        if let Some(ref mut capture) = self.capture {
+            capture.end_frame(self.time);
+        }
+        if let Some(ref mut stems) = self.stems {
+            for stem in stems.iter_mut() {
+                stem.end_frame(self.time);
+            }
+        }
+        self.time = 0;
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.end_frame();
+        self.output.set_sample_rate(rate);
+        if let Some(ref mut stems) = self.stems {
+            for stem in stems.iter_mut() {
+                stem.set_sample_rate(rate);
+            }
+        }
+        self.mix_dirty = true;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.output.left.sample_rate()
+    }
+
+    // Number of stereo frames that can be read right now
+    pub fn samples_available(&mut self) -> usize {
+        self.end_frame();
+        self.output.left.samples_available()
+    }
+
+    // Reads interleaved stereo samples into `out` and returns the number of frames
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.end_frame();
+        self.output.read_samples(out)
+    }
+
+    // Stems record every channel on its own, after panning and master volume, so
+    // they add up to the regular output
+    pub fn set_stems(&mut self, enabled: bool) {
+        if !enabled {
+            self.stems = None;
+            return;
+        }
+        if self.stems.is_none() {
+            self.end_frame();
+            let rate = self.sample_rate();
+            self.stems = Some(Box::new([
+                StereoBuffer::new(rate),
+                StereoBuffer::new(rate),
+                StereoBuffer::new(rate),
+                StereoBuffer::new(rate),
+            ]));
+            self.mix_dirty = true;
+        }
+    }
+
+    pub fn stem_samples_available(&mut self, channel: usize) -> usize {
+        self.end_frame();
+        match self.stems {
+            Some(ref stems) => stems[channel].left.samples_available(),
+            None => 0,
+        }
+    }
+
+    pub fn read_stem_samples(&mut self, channel: usize, out: &mut [f32]) -> usize {
+        self.end_frame();
+        match self.stems {
+            Some(ref mut stems) => stems[channel].read_samples(out),
+            None => 0,
+        }
+    }
+
+    // A copy of the output at its own sample rate, read independently of it, so a
+    // recording doesn't take the sound away from the frontend
+    pub fn set_capture(&mut self, sample_rate: Option<u32>) {
+        self.end_frame();
+        self.capture = sample_rate.map(|rate| Box::new(StereoBuffer::new(rate)));
+        self.mix_dirty = true;
+    }
+
+    pub fn read_capture_samples(&mut self, out: &mut Vec<f32>) -> usize {
+        self.end_frame();
+        match self.capture {
+            Some(ref mut capture) => {
+                let start = out.len();
+                out.resize(start + capture.left.samples_available() * 2, 0.0);
+                capture.read_samples(&mut out[start..])
+            }
+            None => 0,
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 0x01 == 0 {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    // Digital output (0-15) of every channel, as seen by the CGB PCM registers
+    fn digital_outputs(&self) -> [u8; 4] {
+        [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(),
+            self.channel4.output(),
+        ]
+    }
+
+    fn dac_enabled(&self) -> [bool; 4] {
+        [
+            self.channel1.envelope.dac_enabled(),
+            self.channel2.envelope.dac_enabled(),
+            self.channel3.dac_enabled,
+            self.channel4.envelope.dac_enabled(),
+        ]
+    }
+
+    pub fn pcm12(&self) -> u8 {
+        let outputs = self.digital_outputs();
+        outputs[0] | (outputs[1] << 4)
+    }
+
+    pub fn pcm34(&self) -> u8 {
+        let outputs = self.digital_outputs();
+        outputs[2] | (outputs[3] << 4)
+    }
+
+    // Analog output of every channel after its DAC, between -1.0 and 1.0
+    fn analog_outputs(&self) -> [f32; 4] {
+        let digital = self.digital_outputs();
+        let dac = self.dac_enabled();
+        let mut outputs = [0.0; 4];
+        for i in 0..4 {
+            if self.on && dac[i] {
+                outputs[i] = 1.0 - digital[i] as f32 / 7.5;
+            }
+        }
+        outputs
+    }
+
+    // Stereo contribution of every channel to the mixer. Their sum is between -1.0
+    // and 1.0 on each side.
+    fn channel_mix(&self) -> [(f32, f32); 4] {
+        let outputs = self.analog_outputs();
+        let left_volume = ((self.volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.volume & 0x07) as f32 + 1.0;
+        let mut mix = [(0.0, 0.0); 4];
+        for (i, output) in outputs.iter().enumerate() {
+            if self.panning & (0x10 << i) != 0 {
+                mix[i].0 = output * left_volume / 32.0;
+            }
+            if self.panning & (0x01 << i) != 0 {
+                mix[i].1 = output * right_volume / 32.0;
+            }
+        }
+        mix
+    }
+}
+
+// A stereo pair of band-limited buffers, fed with the output level over time
+struct StereoBuffer {
+    left: BlipBuffer,
+    right: BlipBuffer,
+    level: (f32, f32),
+    capacitor: (f32, f32),
+    charge_factor: f32,
+}
+
+impl StereoBuffer {
+    fn new(sample_rate: u32) -> StereoBuffer {
+        StereoBuffer {
+            left: BlipBuffer::new(CLOCK_RATE, sample_rate),
+            right: BlipBuffer::new(CLOCK_RATE, sample_rate),
+            level: (0.0, 0.0),
+            capacitor: (0.0, 0.0),
+            charge_factor: charge_factor(sample_rate),
+        }
+    }
+
+    fn set_level(&mut self, time: u32, level: (f32, f32)) {
+        if level.0 != self.level.0 {
+            self.left.add_delta(time, level.0 - self.level.0);
+        }
+        if level.1 != self.level.1 {
+            self.right.add_delta(time, level.1 - self.level.1);
+        }
+        self.level = level;
+    }
+
+    fn end_frame(&mut self, time: u32) {
+        self.left.end_frame(time);
+        self.right.end_frame(time);
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) {
+        self.left.set_sample_rate(rate);
+        self.right.set_sample_rate(rate);
+        self.charge_factor = charge_factor(rate);
+        // The cleared buffers start from silence again
+        self.level = (0.0, 0.0);
+    }
+
+    fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        // Whole frames only, so both channels read the same count
+        let frames = out.len() / 2;
+        if frames == 0 {
+            return 0;
+        }
+        let out = &mut out[..frames * 2];
+        let count = self.left.read_samples(out, 2);
+        self.right.read_samples(&mut out[1..], 2);
+
+        // Every output goes through a capacitor on hardware, which removes the DC
+        // offset of the DACs
+        for frame in out[..count * 2].chunks_mut(2) {
+            let left = frame[0] - self.capacitor.0;
+            self.capacitor.0 = frame[0] - left * self.charge_factor;
+            let right = frame[1] - self.capacitor.1;
+            self.capacitor.1 = frame[1] - right * self.charge_factor;
+            frame[0] = left;
+            frame[1] = right;
+        }
+        count
+    }
+}
+
+fn charge_factor(sample_rate: u32) -> f32 {
+    0.999958f32.powf(CLOCK_RATE as f32 / sample_rate.max(1) as f32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sound;
+
+    #[test]
+    fn register_read_masks() {
+        let mut sound = Sound::new();
+        for a in 0xFF10..=0xFF25 {
+            sound.wb(a, 0x00);
+        }
+        let expected = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F,
+            0xFF, 0xBF, 0xFF, 0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00,
+        ];
+        for (i, value) in expected.iter().enumerate() {
+            assert_eq!(sound.rb(0xFF10 + i as u16), *value);
+        }
+        for a in 0xFF27..=0xFF2F {
+            assert_eq!(sound.rb(a), 0xFF);
+        }
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut sound = Sound::new();
+        sound.wb(0xFF30, 0x12);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF14, 0x80);
+        assert_eq!(sound.rb(0xFF26), 0xF1);
+
+        sound.wb(0xFF26, 0x00);
+        assert_eq!(sound.rb(0xFF26), 0x70);
+        assert_eq!(sound.rb(0xFF24), 0x00);
+        assert_eq!(sound.rb(0xFF12), 0x00);
+
+        // Registers can't be written while powered off, wave RAM can
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF31, 0x34);
+        assert_eq!(sound.rb(0xFF24), 0x00);
+        assert_eq!(sound.rb(0xFF30), 0x12);
+        assert_eq!(sound.rb(0xFF31), 0x34);
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut sound = Sound::new();
+        sound.wb(0xFF17, 0xF0);
+        sound.wb(0xFF16, 0x3E); // length of 2
+        sound.wb(0xFF19, 0xC0);
+        assert_eq!(sound.rb(0xFF26) & 0x02, 0x02);
+
+        // Two length clocks happen within 4 frame sequencer steps
+        sound.do_cycle(super::FRAME_SEQUENCER_TICKS * 4);
+        assert_eq!(sound.rb(0xFF26) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn produces_samples_at_output_rate() {
+        let mut sound = Sound::new();
+        sound.set_sample_rate(44100);
+        sound.wb(0xFF25, 0xFF);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF17, 0xF0);
+        sound.wb(0xFF18, 0x00);
+        sound.wb(0xFF19, 0x87);
+        sound.do_cycle(super::CLOCK_RATE / 16);
+
+        let mut out = vec![0.0; 10000];
+        let count = sound.read_samples(&mut out);
+        assert!((2755..=2757).contains(&count));
+        assert!(out[..count * 2].iter().any(|v| v.abs() > 0.1));
+        assert!(out[..count * 2].iter().all(|v| v.abs() <= 1.0));
+        assert_eq!(sound.samples_available(), 0);
+    }
+
+    #[test]
+    fn reads_whole_frames() {
+        let mut sound = Sound::new();
+        sound.set_sample_rate(44100);
+        sound.do_cycle(super::CLOCK_RATE / 16);
+        let available = sound.samples_available();
+
+        assert_eq!(sound.read_samples(&mut [0.0; 1]), 0);
+        assert_eq!(sound.read_samples(&mut [0.0; 3]), 1);
+        assert_eq!(sound.samples_available(), available - 1);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        let mut sound = Sound::new();
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF10, 0x11);
+        sound.wb(0xFF13, 0xFF);
+        sound.wb(0xFF14, 0x87);
+        assert_eq!(sound.rb(0xFF26) & 0x01, 0x00);
+    }
+}
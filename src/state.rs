@@ -0,0 +1,167 @@
+// Save state encoding. Every component writes its fields in a fixed order with the
+// helpers below and reads them back in the same order.
+//
+// A state starts with a header: the magic bytes, the format version and a hash of
+// the ROM the state was taken from.
+
+pub type StrResult<T> = Result<T, &'static str>;
+
+const MAGIC: &[u8; 4] = b"GBST";
+pub const VERSION: u16 = 9;
+
+// FNV-1a, only used to tell ROMs apart
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF29CE484222325;
+    for b in rom {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001B3);
+    }
+    hash
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u64) -> StateWriter {
+        let mut w = StateWriter::default();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w.u64(rom_hash);
+        w
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+
+    // For buffers whose size depends on the cartridge
+    pub fn vec(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.bytes(v);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], rom_hash: u64) -> StrResult<StateReader<'a>> {
+        let mut r = StateReader::raw(data);
+        let mut magic = [0; 4];
+        r.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("Not a save state");
+        }
+        if r.u16()? != VERSION {
+            return Err("Unsupported save state version");
+        }
+        if r.u64()? != rom_hash {
+            return Err("Save state belongs to a different ROM");
+        }
+        Ok(r)
+    }
+
+    // Without the header, for other formats built from the same helpers
+    pub fn raw(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> StrResult<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err("Save state is truncated");
+        }
+        let v = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(v)
+    }
+
+    pub fn u8(&mut self) -> StrResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> StrResult<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> StrResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> StrResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> StrResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> StrResult<()> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn vec(&mut self) -> StrResult<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn finish(self) -> StrResult<()> {
+        match self.pos == self.data.len() {
+            true => Ok(()),
+            false => Err("Save state has trailing data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StateReader, StateWriter};
+
+    #[test]
+    fn header_checks() {
+        let mut w = StateWriter::new(7);
+        w.u32(0xDEADBEEF);
+        w.vec(&[1, 2, 3]);
+        let data = w.finish();
+
+        let mut r = StateReader::new(&data, 7).unwrap();
+        assert_eq!(r.u32().unwrap(), 0xDEADBEEF);
+        assert_eq!(r.vec().unwrap(), vec![1, 2, 3]);
+        r.finish().unwrap();
+
+        assert!(StateReader::new(&data, 8).is_err());
+        assert!(StateReader::new(&data[..5], 7).is_err());
+        let mut r = StateReader::new(&data[..data.len() - 1], 7).unwrap();
+        r.u32().unwrap();
+        assert!(r.vec().is_err());
+    }
+}
@@ -0,0 +1,222 @@
+// Headless runs of test ROMs with pass/fail detection. The result comes from
+// whichever shows up first: blargg-style serial output ("Passed"/"Failed"), the
+// mooneye register signature at `LD B,B`, or a match with a reference screenshot.
+
+use crate::cpu::core::CpuState;
+use crate::gameboy::{Error, Gameboy, Target, CYCLES};
+use crate::screenshot;
+use std::sync::{Arc, Mutex};
+
+// B, C, D, E, H, L
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Verdict {
+    Passed,
+    Failed(String),
+    // The budget ran out without a result
+    Timeout,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Detector {
+    Serial,
+    Mooneye,
+    Screenshot,
+    Lockup,
+}
+
+impl Detector {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Detector::Serial => "serial",
+            Detector::Mooneye => "mooneye",
+            Detector::Screenshot => "screenshot",
+            Detector::Lockup => "lockup",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub verdict: Verdict,
+    pub detector: Option<Detector>,
+    pub frames: u32,
+    // Everything the ROM sent over the link cable
+    pub serial: String,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.verdict == Verdict::Passed
+    }
+}
+
+pub struct TestRun {
+    rom: Vec<u8>,
+    model: Target,
+    frames: u32,
+    reference: Option<Vec<u8>>,
+}
+
+impl TestRun {
+    pub fn new(rom: Vec<u8>) -> TestRun {
+        TestRun {
+            rom,
+            model: Target::default(),
+            frames: 60 * 60,
+            reference: None,
+        }
+    }
+
+    pub fn model(mut self, model: Target) -> TestRun {
+        self.model = model;
+        self
+    }
+
+    // Budget in frames worth of cycles, so it also runs out with the LCD off
+    pub fn frames(mut self, frames: u32) -> TestRun {
+        self.frames = frames;
+        self
+    }
+
+    // Expected screen as a PNG
+    pub fn reference(mut self, png: &[u8]) -> Result<TestRun, Error> {
+        self.reference = Some(screenshot::decode_png(png)?);
+        Ok(self)
+    }
+
+    pub fn run(self) -> Result<Outcome, Error> {
+        let mut gb = Gameboy::builder(self.rom).model(self.model).build()?;
+        let serial = Arc::new(Mutex::new(Vec::new()));
+        let output = serial.clone();
+        gb.set_serial_callback(move |b| {
+            output.lock().unwrap().push(b);
+            None
+        });
+
+        let mut outcome = Outcome {
+            verdict: Verdict::Timeout,
+            detector: None,
+            frames: 0,
+            serial: String::new(),
+        };
+        let mut ticks = 0;
+        while outcome.frames < self.frames {
+            let cpu = gb.cpu_mut();
+            let pc = cpu.registers.pc;
+            match cpu.state {
+                CpuState::Locked => {
+                    outcome.verdict = Verdict::Failed(format!("locked up at {:04X}", pc));
+                    outcome.detector = Some(Detector::Lockup);
+                    break;
+                }
+                CpuState::Running if cpu.memory.read_byte(pc) == 0x40 => {
+                    let r = &cpu.registers;
+                    let registers = [r.b, r.c, r.d, r.e, r.h, r.l];
+                    outcome.verdict = match registers == FIBONACCI {
+                        true => Verdict::Passed,
+                        false => Verdict::Failed(format!(
+                            "B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}",
+                            r.b, r.c, r.d, r.e, r.h, r.l
+                        )),
+                    };
+                    outcome.detector = Some(Detector::Mooneye);
+                    break;
+                }
+                _ => {}
+            }
+
+            ticks += cpu.do_cycle();
+            if ticks < CYCLES {
+                continue;
+            }
+            ticks -= CYCLES;
+            outcome.frames += 1;
+
+            let text = String::from_utf8_lossy(&serial.lock().unwrap()).into_owned();
+            if text.contains("Passed") {
+                outcome.verdict = Verdict::Passed;
+            } else if text.contains("Failed") {
+                outcome.verdict = Verdict::Failed(
+                    text.split_whitespace().collect::<Vec<_>>().join(" "),
+                );
+            }
+            if outcome.verdict != Verdict::Timeout {
+                outcome.detector = Some(Detector::Serial);
+                break;
+            }
+            if let Some(reference) = &self.reference {
+                if screenshot::compare(gb.image(), reference).matches() {
+                    outcome.verdict = Verdict::Passed;
+                    outcome.detector = Some(Detector::Screenshot);
+                    break;
+                }
+            }
+        }
+
+        if outcome.verdict == Verdict::Timeout {
+            if let Some(reference) = &self.reference {
+                let count = screenshot::compare(gb.image(), reference).mismatches;
+                outcome.verdict = Verdict::Failed(format!(
+                    "{} pixels differ from the reference",
+                    count
+                ));
+                outcome.detector = Some(Detector::Screenshot);
+            }
+        }
+        outcome.serial = String::from_utf8_lossy(&serial.lock().unwrap()).into_owned();
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Detector, TestRun, Verdict};
+    use crate::gameboy::GameBoy;
+    use crate::header::test_rom;
+
+    #[test]
+    fn detects_results() {
+        let mooneye = test_rom(&[
+            0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34,   // LD r,n
+            0x40, // LD B,B
+            0x18, 0xFE, // JR -2
+        ]);
+        let outcome = TestRun::new(mooneye).model(GameBoy).run().unwrap();
+        assert_eq!(outcome.verdict, Verdict::Passed);
+        assert_eq!(outcome.detector, Some(Detector::Mooneye));
+
+        let mut blargg = test_rom(&[
+            0x21, 0x00, 0x02, // LD HL,0x0200
+            0x2A, // LD A,(HL+)
+            0xB7, // OR A
+            0x28, 0xFE, // JR Z,-2
+            0xE0, 0x01, // LDH (SB),A
+            0x3E, 0x81, // LD A,0x81
+            0xE0, 0x02, // LDH (SC),A
+            0x18, 0xF4, // JR -12
+        ]);
+        let text = b"cpu_instrs\nFailed 2\n";
+        blargg[0x200..0x200 + text.len()].copy_from_slice(text);
+        let outcome = TestRun::new(blargg).model(GameBoy).run().unwrap();
+        assert_eq!(
+            outcome.verdict,
+            Verdict::Failed("cpu_instrs Failed 2".into())
+        );
+        assert_eq!(outcome.serial, "cpu_instrs\nFailed 2\n");
+
+        let outcome = TestRun::new(test_rom(&[0x18, 0xFE]))
+            .model(GameBoy)
+            .frames(10)
+            .run()
+            .unwrap();
+        assert_eq!((outcome.verdict, outcome.frames), (Verdict::Timeout, 10));
+
+        let outcome = TestRun::new(test_rom(&[0xD3]))
+            .model(GameBoy)
+            .run()
+            .unwrap();
+        assert_eq!(outcome.detector, Some(Detector::Lockup));
+    }
+}
@@ -0,0 +1,80 @@
+extern crate gameboy;
+
+use gameboy::gameboy::{GameBoy, Gameboy};
+use std::path::Path;
+
+// Fills tile 0, which the whole background map points at, with 16..1
+fn tiles_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    let program = [
+        0xAF, // XOR A
+        0xE0, 0x40, // LDH (LCDC),A
+        0x21, 0x00, 0x80, // LD HL,0x8000
+        0x0E, 0x10, // LD C,16
+        0x79, // LD A,C
+        0x22, // LD (HL+),A
+        0x0D, // DEC C
+        0x20, 0xFB, // JR NZ,-5
+        0x3E, 0x91, // LD A,0x91
+        0xE0, 0x40, // LDH (LCDC),A
+        0x18, 0xFE, // JR -2
+    ];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+// Set UPDATE_GOLDEN=1 to write the current screen instead of comparing
+fn assert_golden(gb: &Gameboy, name: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, gb.screenshot_png()).unwrap();
+        return;
+    }
+
+    let reference = std::fs::read(&path).unwrap();
+    let comparison = gb.compare_screenshot(&reference).unwrap();
+    if !comparison.matches() {
+        let diff = std::env::temp_dir().join(name.replace(".png", ".diff.png"));
+        std::fs::write(&diff, comparison.diff_png()).unwrap();
+        panic!(
+            "{} pixels differ from {}, see {}",
+            comparison.mismatches,
+            path.display(),
+            diff.display()
+        );
+    }
+}
+
+#[test]
+fn tiles_after_300_frames() {
+    let mut gb = Gameboy::builder(tiles_rom())
+        .model(GameBoy)
+        .build()
+        .unwrap();
+    for _ in 0..300 {
+        gb.frame();
+    }
+    assert_golden(&gb, "tiles.png");
+}
+
+#[test]
+fn screenshot_differs_after_palette_change() {
+    let mut gb = Gameboy::builder(tiles_rom())
+        .model(GameBoy)
+        .build()
+        .unwrap();
+    gb.frame();
+    gb.frame();
+    let before = gb.screenshot_png();
+    assert!(gb.compare_screenshot(&before).unwrap().matches());
+
+    // Swaps the darkest and lightest shades
+    gb.poke(0xFF47, 0x1B);
+    gb.frame();
+    gb.frame();
+    let comparison = gb.compare_screenshot(&before).unwrap();
+    assert!(comparison.mismatches > 0);
+    assert_eq!(comparison.diff.len(), gb.image().len());
+}